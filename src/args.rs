@@ -0,0 +1,1028 @@
+// Command line arguments, which may also come from environment variables or a config file.
+
+use std::ffi::{OsStr, OsString};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use clap::builder::RangedU64ValueParser;
+use clap::error::ErrorKind;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
+use regex::Regex;
+
+use crate::config::{Config, ConfigError};
+
+/// The time between syncs when `--watch` is given without `--watch-interval-seconds`
+pub const DEFAULT_WATCH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// What is created in Kubernetes for each AWS secret
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum OutputMode {
+    /// A Kubernetes secret holding the value of the AWS secret
+    Secret,
+    /// An External Secrets Operator `ExternalSecret` referencing the AWS secret
+    ExternalSecret,
+    /// A Bitnami `SealedSecret` encrypted by `kubeseal` for the Sealed Secrets controller
+    SealedSecret,
+}
+
+/// What is done with the built manifests
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum Output {
+    /// Apply the manifests to Kubernetes
+    Apply,
+    /// Print the manifests to stdout as YAML documents, e.g. for ArgoCD or Flux to apply
+    Manifest,
+}
+
+/// The AWS partition of the regions the secrets are read from
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum AwsPartition {
+    /// The standard AWS regions
+    Aws,
+    /// The China regions, e.g. `cn-north-1`
+    AwsCn,
+    /// The AWS GovCloud (US) regions, e.g. `us-gov-east-1`
+    AwsUsGov,
+}
+
+/// How the secret string of an AWS secret is parsed into keys and values
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum SecretFormat {
+    /// A JSON object or YAML mapping, falling back to plain text for anything else
+    #[value(name = "application/json")]
+    Json,
+    /// URL encoded `key=value` pairs joined by `&`
+    #[value(name = "application/x-www-form-urlencoded")]
+    Form,
+    /// The whole string as the value of `--plain-text-key`
+    #[value(name = "text/plain")]
+    Plain,
+}
+
+/// How the values of an AWS secret are stored in the Kubernetes secret
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum DataFormat {
+    /// One key per value
+    Keys,
+    /// A single env file of all the values, under `--env-file-name`
+    EnvFile,
+}
+
+/// Where the secrets are read from
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum Source {
+    /// AWS Secrets Manager
+    Aws,
+    /// The KV v2 secrets engine of HashiCorp Vault, at `VAULT_ADDR`
+    Vault,
+}
+
+/// How to authenticate to HashiCorp Vault
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum VaultAuthMethod {
+    /// The token in `VAULT_TOKEN`
+    Token,
+    /// The service account token of the pod, exchanged for a Vault token as `--vault-role`
+    Kubernetes,
+}
+
+/// How the Kubernetes API server treats unknown or duplicate fields in the applied objects
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum FieldValidation {
+    /// Fail the request
+    Strict,
+    /// Apply the object, returning a warning
+    Warn,
+    /// Apply the object, silently dropping the invalid fields
+    Ignore,
+}
+
+/// What to do when syncing a secret fails
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum ErrorPolicy {
+    /// Log the error and carry on with the other secrets, without retrying
+    Skip,
+    /// Stop the run straight away, exiting with 1
+    Abort,
+    /// Retry transient errors with `--max-retries` and `--retry-base-ms`, then carry on with
+    /// the other secrets
+    Retry,
+}
+
+/// What to do instead of syncing the secrets
+#[derive(Subcommand, Clone, Debug, PartialEq)]
+pub enum Command {
+    /// Print Helm values for a CronJob chart running the sync with the arguments given before
+    /// this subcommand
+    GenerateHelmValues {
+        /// The cron schedule of the CronJob
+        #[arg(long, default_value = "*/15 * * * *")]
+        schedule: String,
+    },
+    /// Keep running and sync the AWS secrets selected by each `AwsSecretSync` resource of the
+    /// cluster whenever it changes; the arguments given before this subcommand are used for
+    /// the fields missing from the specs
+    Controller,
+    /// Print the CustomResourceDefinition of `AwsSecretSync` for the controller, e.g. to pipe
+    /// to `kubectl apply -f -`
+    GenerateCrd,
+    /// Print a JSON Schema of the keys of an AWS secret parsed with `--parser`, with every
+    /// value typed as a string
+    GenerateSchema {
+        /// The name or ARN of the AWS secret
+        secret_name: String,
+        /// Write the schema to this file instead of stdout
+        #[arg(long, value_name = "PATH")]
+        output_file: Option<PathBuf>,
+    },
+}
+
+/// Output format of the log lines
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum LogFormat {
+    /// Human readable text
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+/// CLAP parser for command line arguments
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+#[command(
+    after_help = "Every option can also be set with the environment variable shown \
+next to it. Options given on the command line take precedence over environment variables, \
+which take precedence over the --config file."
+)]
+pub struct Args {
+    /// A TOML file providing defaults for any of the other flags
+    #[arg(long, env, value_name = "PATH")]
+    pub config: Option<PathBuf>,
+
+    /// Where to read the secrets from; Vault secrets use their custom metadata as tags
+    #[arg(long, env, value_enum, default_value_t = Source::Aws)]
+    pub source: Source,
+
+    /// The mount path of the Vault KV v2 secrets engine
+    #[arg(long, env, default_value = "secret")]
+    pub vault_mount: String,
+
+    /// Only sync the Vault secrets below this path, e.g. `prod/myapp`
+    #[arg(long, env, default_value = "")]
+    pub vault_path_prefix: String,
+
+    /// How to authenticate to Vault; `kubernetes` needs no long-lived `VAULT_TOKEN`
+    #[arg(long, env, value_enum, default_value_t = VaultAuthMethod::Token)]
+    pub vault_auth_method: VaultAuthMethod,
+
+    /// The role of the Vault Kubernetes auth method to log in as
+    #[arg(long, env, required_if_eq("vault_auth_method", "kubernetes"))]
+    pub vault_role: Option<String>,
+
+    /// The mount path of the Vault Kubernetes auth method
+    #[arg(long, env, default_value = "kubernetes")]
+    pub vault_auth_mount: String,
+
+    /// The key of the tag for the namespace in AWS Secrets Manager; may be repeated or comma
+    /// separated to sync to the namespaces of every one of the tags
+    #[arg(short, long, env, required = true, value_delimiter = ',')]
+    pub namespace_tag: Vec<String>,
+
+    /// The key of the tag for the secret name in AWS Secrets Manager, or a template such as
+    /// `{{prefix}}-{{env}}-creds` filled in from the values of the tags named in it
+    #[arg(short, long, env)]
+    pub secret_name_tag: String,
+
+    /// A leading path removed from the secret name tag, whose remaining slashes are replaced
+    /// with hyphens, e.g. `prod/myapp` turns `prod/myapp/db/creds` into `db-creds`
+    #[arg(long, env, value_name = "PREFIX")]
+    pub strip_prefix: Option<String>,
+
+    /// Prepended to the secret name tag to form the Kubernetes secret name
+    #[arg(long, env)]
+    pub secret_name_prefix: Option<String>,
+
+    /// Appended to the secret name tag to form the Kubernetes secret name
+    #[arg(long, env, allow_hyphen_values = true)]
+    pub secret_name_suffix: Option<String>,
+
+    /// The filename key in the AWS secret
+    #[arg(short, long, env)]
+    pub filename_tag: String,
+
+    /// Gzip the files of the secrets with a filename tag before base64 encoding them, for
+    /// secrets with many keys; the Kubernetes secrets are annotated with
+    /// `k8s-aws-secrets-sync/encoding: gzip+base64`
+    #[arg(long, env)]
+    pub compress_file_secret: bool,
+
+    /// Another tag key naming a file to store the secret values in, in addition to the
+    /// filename tag; may be repeated
+    #[arg(long, env, value_delimiter = ',', value_name = "KEY")]
+    pub extra_filename_tag: Vec<String>,
+
+    /// How the values of the secrets without a filename tag are stored; the filename tag
+    /// always stores them in an env file
+    #[arg(long, env, value_enum, default_value_t = DataFormat::Keys)]
+    pub format: DataFormat,
+
+    /// The key of the env file with `--format env-file`
+    #[arg(long, env, default_value = ".env")]
+    pub env_file_name: String,
+
+    /// Only sync to the tagged namespaces whose whole name matches this regular expression,
+    /// e.g. `prod-.*`
+    #[arg(long, env, value_name = "REGEX", value_parser = parse_namespace_filter)]
+    pub namespace_filter: Option<Regex>,
+
+    /// Print the patches that would be applied instead of applying them to Kubernetes
+    #[arg(long, env)]
+    pub dry_run: bool,
+
+    /// Print what a sync would change as a JSON array on stdout, reading the Kubernetes
+    /// secrets but changing nothing
+    #[arg(long, env, conflicts_with_all = ["dry_run", "output", "output_mode"])]
+    pub plan: bool,
+
+    /// Write the decoded data of each Kubernetes secret to a JSON file in this directory, for
+    /// debugging only
+    #[cfg(feature = "debug-dump")]
+    #[arg(long, env, value_name = "DIR")]
+    pub debug_dump_dir: Option<PathBuf>,
+
+    /// The staging label of the AWS secret version to sync, e.g. `AWSPENDING`
+    #[arg(long, env, default_value = "AWSCURRENT")]
+    pub version_stage: String,
+
+    /// The UUID of the AWS secret version to sync, in place of `--version-stage`; AWS only
+    /// accepts one of the two, so giving both is an error
+    #[arg(long, env, value_name = "UUID", conflicts_with = "version_stage")]
+    pub version_id: Option<String>,
+
+    /// The Kubernetes secret key used for AWS secrets that are plain text rather than JSON
+    #[arg(long, env, default_value = "value")]
+    pub plain_text_key: String,
+
+    /// The MIME type of the secret strings of the AWS secrets
+    #[arg(long, env, value_enum, default_value_t = SecretFormat::Json)]
+    pub parser: SecretFormat,
+
+    /// Store each document of a multi-document YAML secret string under its own key, `doc0`,
+    /// `doc1`, ...
+    #[arg(long, env)]
+    pub split_yaml_documents: bool,
+
+    /// The values of the AWS secrets are already base64 encoded, so they are stored in the
+    /// Kubernetes secrets as they are rather than encoded again; doesn't apply to env files
+    #[arg(long, env)]
+    pub values_already_base64: bool,
+
+    /// The Kubernetes secret key used for AWS secrets stored as binary rather than a string
+    #[arg(long, env, default_value = "data")]
+    pub binary_key: String,
+
+    /// Convert the PKCS#12 / PFX bundle of each AWS secret, binary or base64 encoded under
+    /// `--binary-key`, into the `tls.crt` and `tls.key` of a `kubernetes.io/tls` secret
+    #[arg(long, env)]
+    pub parse_pkcs12: bool,
+
+    /// The key of the AWS secret holding the passphrase of its PKCS#12 bundle; bundles are
+    /// read without a passphrase otherwise
+    #[arg(long, env, value_name = "KEY", requires = "parse_pkcs12")]
+    pub pkcs12_password_key: Option<String>,
+
+    /// The `openssl` binary decrypting the PKCS#12 bundles with `--parse-pkcs12`
+    #[arg(long, env, default_value = "openssl")]
+    pub openssl_path: String,
+
+    /// Skip secrets whose base64 encoded data is larger than this, counting them as errors;
+    /// etcd limits objects to 1 MiB
+    #[arg(long, env, value_name = "BYTES")]
+    pub max_secret_size_bytes: Option<usize>,
+
+    /// Warn about AWS secrets that haven't changed in more than this many days
+    #[arg(long, env, value_name = "DAYS")]
+    pub rotation_warn_days: Option<i64>,
+
+    /// Skip AWS secrets with rotation enabled that were rotated in the last
+    /// `--rotation-grace-minutes`, as the rotation may not have finished
+    #[arg(long, env)]
+    pub skip_rotating_secrets: bool,
+
+    /// How long after a rotation `--skip-rotating-secrets` skips a secret
+    #[arg(long, env, value_name = "MINUTES", default_value_t = 5)]
+    pub rotation_grace_minutes: u32,
+
+    /// The maximum number of AWS secrets synced at the same time
+    #[arg(long, env, default_value_t = 10, value_parser = at_least_one())]
+    pub concurrency: usize,
+
+    /// The key of a tag whose integer value is the number of `--concurrency` slots taken by
+    /// the AWS secret, e.g. to sync a very large secret on its own
+    #[arg(long, env, value_name = "KEY")]
+    pub parallelism_tag: Option<String>,
+
+    /// The maximum number of patches in flight to the same Kubernetes namespace at once
+    #[arg(long, env, default_value_t = 5, value_parser = at_least_one())]
+    pub k8s_concurrency: usize,
+
+    /// The format of the log output; filtering is controlled by `RUST_LOG` in both formats
+    #[arg(long, env, value_enum, default_value_t = LogFormat::Text)]
+    pub log_format: LogFormat,
+
+    /// Only sync AWS secrets whose name starts with this prefix, e.g. `prod/myapp/`;
+    /// an empty prefix is equivalent to omitting the flag
+    #[arg(long, env)]
+    pub secret_prefix: Option<String>,
+
+    /// A `key=value` tag the AWS secrets must have to be synced; may be repeated
+    #[arg(long = "include-tag", env, value_name = "KEY=VALUE", value_parser = parse_key_val)]
+    pub include_tags: Vec<(String, String)>,
+
+    /// A `key=value` tag the AWS secrets must have to be synced, checked after listing them,
+    /// e.g. `sync-enabled=true` for staged rollouts; may be repeated
+    #[arg(long = "required-label", env, value_name = "KEY=VALUE", value_parser = parse_key_val)]
+    pub required_labels: Vec<(String, String)>,
+
+    /// Only sync the AWS secrets whose name matches this regular expression, e.g.
+    /// `prod/myapp/.*` to refresh a subset of the secrets
+    #[arg(long, env, value_name = "REGEX", value_parser = Regex::new)]
+    pub secret_filter_name: Option<Regex>,
+
+    /// Skip AWS secrets whose name matches this regular expression
+    #[arg(long, env, value_name = "REGEX", value_parser = Regex::new)]
+    pub exclude_pattern: Option<Regex>,
+
+    /// Only sync the AWS secrets whose description contains this text, e.g. the name of the
+    /// application
+    #[arg(long, env, value_name = "TEXT")]
+    pub description_contains: Option<String>,
+
+    /// The AWS region to read secrets from, overriding the default region resolution
+    #[arg(long, env)]
+    pub aws_region: Option<String>,
+
+    /// The partition of the AWS regions, used to build the Secrets Manager endpoint; by
+    /// default the SDK resolves the endpoint from the region
+    #[arg(long, env, value_enum)]
+    pub aws_partition: Option<AwsPartition>,
+
+    /// Also sync the SSM Parameter Store parameters below this path, e.g. `/myapp/prod`, into
+    /// a ConfigMap keyed by the parameter names relative to the path; `SecureString`
+    /// parameters are skipped, as a ConfigMap is not meant for secrets
+    #[arg(long, env, requires_all = ["ssm_namespace", "ssm_configmap_name"])]
+    pub ssm_parameter_prefix: Option<String>,
+
+    /// The namespace of the ConfigMap holding the `--ssm-parameter-prefix` parameters
+    #[arg(long, env)]
+    pub ssm_namespace: Option<String>,
+
+    /// The name of the ConfigMap holding the `--ssm-parameter-prefix` parameters
+    #[arg(long, env)]
+    pub ssm_configmap_name: Option<String>,
+
+    /// Comma separated list of additional AWS regions to read secrets from;
+    /// replicated secrets use the value from the primary region
+    #[arg(long, env, value_delimiter = ',')]
+    pub extra_regions: Vec<String>,
+
+    /// The ARN of an IAM role to assume before reading secrets, e.g. for cross-account syncing
+    #[arg(long, env)]
+    pub assume_role_arn: Option<String>,
+
+    /// The external ID to pass when assuming `--assume-role-arn`
+    #[arg(long, env, requires = "assume_role_arn")]
+    pub assume_role_external_id: Option<String>,
+
+    /// A Kubernetes secret holding the `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY` and
+    /// optional `AWS_SESSION_TOKEN` to use, for clusters without IAM roles for service accounts
+    #[arg(long, env, value_name = "NAMESPACE/NAME", value_parser = parse_namespaced_name)]
+    pub aws_credentials_secret: Option<(String, String)>,
+
+    /// Read the value of the first AWS secret found before syncing, and stop with a clear
+    /// error if the IAM principal isn't allowed to
+    #[arg(long, env)]
+    pub preflight_check: bool,
+
+    /// Only check the tags and values of the AWS secrets and print a table of the results,
+    /// without connecting to Kubernetes
+    #[arg(long, env, visible_alias = "validate")]
+    pub check: bool,
+
+    /// The number of times a transient AWS or Kubernetes API error is retried
+    #[arg(long, env, default_value_t = 3)]
+    pub max_retries: u32,
+
+    /// The delay before the first retry in milliseconds, doubled for every further retry
+    #[arg(long, env, default_value_t = 200)]
+    pub retry_base_ms: u64,
+
+    /// What to do when an AWS secret can't be read or converted into Kubernetes secret data
+    #[arg(long, env, value_enum, default_value_t = ErrorPolicy::Retry)]
+    pub on_aws_error: ErrorPolicy,
+
+    /// What to do when a Kubernetes secret can't be applied
+    #[arg(long, env, value_enum, default_value_t = ErrorPolicy::Retry)]
+    pub on_k8s_error: ErrorPolicy,
+
+    /// Abort the sync once this many secrets in a row have failed, e.g. because of a
+    /// misconfiguration breaking every one of them; 0 never aborts
+    #[arg(long, env, default_value_t = 0)]
+    pub max_errors_before_abort: usize,
+
+    /// The field manager name used for server-side apply; deployments using different
+    /// tag schemas should use different names so they don't fight over field ownership
+    #[arg(long, env, default_value = "k8s-aws-secrets-sync")]
+    pub field_manager: String,
+
+    /// The server-side field validation of the applied objects; `warn` or `ignore` may be
+    /// needed with older API servers
+    #[arg(long, env, value_enum, default_value_t = FieldValidation::Strict)]
+    pub field_validation: FieldValidation,
+
+    /// A `key=value` label to add to every synced Kubernetes secret; may be repeated
+    #[arg(long = "label", env, value_name = "KEY=VALUE", value_parser = parse_key_val)]
+    pub labels: Vec<(String, String)>,
+
+    /// A `key=value` annotation to add to every synced Kubernetes secret; may be repeated
+    #[arg(long = "annotation", env, value_name = "KEY=VALUE", value_parser = parse_key_val)]
+    pub annotations: Vec<(String, String)>,
+
+    /// Comma separated list of keys of the AWS secret to leave out of the Kubernetes secret,
+    /// e.g. `_comment,_created_by`
+    #[arg(long, env, value_delimiter = ',')]
+    pub exclude_keys: Vec<String>,
+
+    /// Comma separated list of the only keys of the AWS secret to put in the Kubernetes
+    /// secret; a listed key missing from the AWS secret is logged as a warning
+    #[arg(long, env, value_delimiter = ',')]
+    pub include_keys: Vec<String>,
+
+    /// An `aws_key=k8s_key` pair renaming a key of the AWS secret in the Kubernetes secret;
+    /// may be repeated, and keys without a mapping keep their name
+    #[arg(long, env, value_name = "AWS_KEY=K8S_KEY", value_parser = parse_key_val)]
+    pub key_map: Vec<(String, String)>,
+
+    /// A Rhai script transforming the values of each secret; it sees them as the map `secret`
+    /// and must evaluate to the map of transformed values, e.g. to build a JDBC URL
+    #[arg(long, env, value_name = "PATH")]
+    pub transform_script: Option<PathBuf>,
+
+    /// A shell command transforming the values of each secret, reading them as a JSON object
+    /// of strings on stdin and writing the transformed object on stdout, e.g. a `jq` filter;
+    /// a non-zero exit fails the secret
+    #[arg(long, env, value_name = "CMD")]
+    pub transform_command: Option<String>,
+
+    /// How long `--transform-script` and `--transform-command` may each run for each secret,
+    /// in milliseconds
+    #[arg(long, env, default_value_t = 5000)]
+    pub transform_timeout_ms: u64,
+
+    /// Whether to create Kubernetes secrets or `ExternalSecret` resources; the operator
+    /// extracts every key of a JSON AWS secret, so `--key-map` and the filename tag don't apply
+    #[arg(long, env, value_enum, default_value_t = OutputMode::Secret)]
+    pub output_mode: OutputMode,
+
+    /// Whether to apply the manifests to Kubernetes or print them to stdout as YAML; no
+    /// Kubernetes client is created with `manifest`
+    #[arg(long, env, value_enum, default_value_t = Output::Apply, conflicts_with = "dry_run")]
+    pub output: Output,
+
+    /// The name of the External Secrets Operator store that reads AWS Secrets Manager;
+    /// required with `--output-mode external-secret`
+    #[arg(long, env, required_if_eq("output_mode", "external-secret"))]
+    pub secret_store_name: Option<String>,
+
+    /// The kind of `--secret-store-name`, either `SecretStore` or `ClusterSecretStore`
+    #[arg(long, env, default_value = "SecretStore")]
+    pub secret_store_kind: String,
+
+    /// The Sealed Secrets controller whose certificate seals the secrets with
+    /// `--output-mode sealed-secret`, in every cluster
+    #[arg(long, env, value_name = "NAMESPACE/NAME", value_parser = parse_namespaced_name,
+        default_value = "kube-system/sealed-secrets")]
+    pub sealed_secrets_controller: (String, String),
+
+    /// The `kubeseal` binary sealing the secrets with `--output-mode sealed-secret`
+    #[arg(long, env, default_value = "kubeseal")]
+    pub kubeseal_path: String,
+
+    /// The type of the Kubernetes secrets, e.g. `kubernetes.io/tls`; changing the type of an
+    /// existing secret requires recreating it with `--force-recreate`
+    #[arg(long, env, default_value = "Opaque")]
+    pub secret_type: String,
+
+    /// The key of a tag overriding `--secret-type` for the AWS secrets that carry it, e.g.
+    /// `k8s/secret-type` with the value `kubernetes.io/tls`
+    #[arg(long, env, value_name = "KEY")]
+    pub secret_type_tag: Option<String>,
+
+    /// Add the ARN, creation date and last changed date of the AWS secret to the `stringData`
+    /// of the Kubernetes secret, for audit tooling
+    #[arg(long, env)]
+    pub inject_metadata: bool,
+
+    /// The prefix of the keys added by `--inject-metadata`
+    #[arg(long, env, default_value = "_aws_")]
+    pub metadata_key_prefix: String,
+
+    /// The kubeconfig file to read the Kubernetes contexts from, instead of the default
+    /// kubeconfig or the in-cluster configuration
+    #[arg(long, env, value_name = "PATH")]
+    pub kubeconfig: Option<PathBuf>,
+
+    /// The kubeconfig context of the cluster to sync to; may be repeated to sync every
+    /// secret to several clusters, and defaults to the current context
+    #[arg(long, env, value_name = "CONTEXT")]
+    pub kube_context: Vec<String>,
+
+    /// Delete and recreate Kubernetes secrets whose patch is rejected because it changes an
+    /// immutable field, such as the type
+    #[arg(long, env)]
+    pub force_recreate: bool,
+
+    /// Create the Kubernetes secrets as immutable, so only this tool can change them by
+    /// recreating them
+    #[arg(long, env)]
+    pub immutable: bool,
+
+    /// Delete and recreate immutable Kubernetes secrets whose data changed, instead of
+    /// skipping them with a warning
+    #[arg(long, env, requires = "immutable")]
+    pub immutable_recreate: bool,
+
+    /// Create the tagged namespaces that don't exist yet, labelled as managed by this tool
+    #[arg(long, env)]
+    pub namespace_create: bool,
+
+    /// The key of a tag holding comma separated `key=value` labels to add to each namespace
+    /// the AWS secret is synced to
+    #[arg(long, env, value_name = "KEY")]
+    pub namespace_label_tag: Option<String>,
+
+    /// Read every patched Kubernetes secret back and warn when its data differs from what was
+    /// applied, e.g. because of a mutating admission webhook; doubles the API calls
+    #[arg(long, env)]
+    pub verify: bool,
+
+    /// Tag each AWS secret with the time it was last synced to Kubernetes, under
+    /// `k8s-aws-secrets-sync/last-sync`; requires the `secretsmanager:TagResource` permission
+    #[arg(long, env)]
+    pub tag_on_success: bool,
+
+    /// Delete the Kubernetes secrets managed with this `--field-manager` in the synced
+    /// namespaces that no longer have a matching AWS secret; can't be combined with
+    /// `--include-tag`, `--required-label` or `--description-contains`
+    #[arg(long, env)]
+    pub delete_orphans: bool,
+
+    /// Only log the orphaned secrets `--delete-orphans` would delete
+    #[arg(long, env, requires = "delete_orphans")]
+    pub orphan_dry_run: bool,
+
+    /// Hold a lock in the `aws-secrets-sync-lock` ConfigMap of the pod's own namespace while
+    /// syncing; a run that finds the lock held by another pod exits without syncing
+    #[arg(long, env)]
+    pub leader_lock: bool,
+
+    /// Exit with a failure rather than success when `--leader-lock` is held by another pod
+    #[arg(long, env, requires = "leader_lock")]
+    pub lock_fail: bool,
+
+    /// How long the `--leader-lock` is held before another pod may take it over; should be
+    /// longer than a sync, and with `--watch` longer than the interval, as the lock is renewed
+    /// before every sync
+    #[arg(long, env, value_name = "SECONDS", default_value_t = 600)]
+    pub lock_ttl_seconds: u32,
+
+    /// Write Prometheus metrics for the run to this file, for node_exporter's textfile collector
+    #[arg(long, env)]
+    pub metrics_file: Option<PathBuf>,
+
+    /// Write a CSV of the result of every Kubernetes secret of the run to this file, for
+    /// spreadsheets and dashboards
+    #[arg(long, env, value_name = "PATH")]
+    pub report_file: Option<PathBuf>,
+
+    /// Append a JSON line for every list, fetch, patch and skip to this file
+    #[arg(long, env, value_name = "PATH")]
+    pub audit_log: Option<PathBuf>,
+
+    /// Send the audit events to the local syslog daemon as well
+    #[arg(long, env)]
+    pub audit_syslog: bool,
+
+    /// Keep running and sync again every 60 seconds instead of exiting after one sync
+    #[arg(long, env)]
+    pub watch: bool,
+
+    /// Keep running and sync again every this many seconds; implies `--watch`
+    #[arg(long, env, value_name = "SECONDS")]
+    pub watch_interval_seconds: Option<u64>,
+
+    /// Serve `/healthz` and `/readyz` on this port for Kubernetes probes while running
+    #[arg(long, env, value_name = "PORT")]
+    pub health_check_port: Option<u16>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+impl Args {
+    /// Checks the combinations of arguments that clap can't express
+    pub fn validate(&self) -> Result<(), String> {
+        let both: Vec<_> = self
+            .include_keys
+            .iter()
+            .filter(|key| self.exclude_keys.contains(key))
+            .map(String::as_str)
+            .collect();
+        if !both.is_empty() {
+            return Err(format!(
+                "keys given to both --include-keys and --exclude-keys: {}",
+                both.join(", ")
+            ));
+        }
+        // the tags and description of an AWS secret aren't kept on the Kubernetes secret, so
+        // the orphans can't be told apart from the secrets these filters leave out
+        let tag_filters = [
+            (!self.include_tags.is_empty(), "--include-tag"),
+            (!self.required_labels.is_empty(), "--required-label"),
+            (
+                self.description_contains.is_some(),
+                "--description-contains",
+            ),
+        ];
+        if let Some((_, flag)) = tag_filters
+            .iter()
+            .find(|(set, _)| self.delete_orphans && *set)
+        {
+            return Err(format!("--delete-orphans can't be combined with {}", flag));
+        }
+        Ok(())
+    }
+
+    /// The time between syncs in watch mode, or `None` to sync only once
+    pub fn watch_interval(&self) -> Option<Duration> {
+        match self.watch_interval_seconds {
+            Some(seconds) => Some(Duration::from_secs(seconds)),
+            None if self.watch => Some(DEFAULT_WATCH_INTERVAL),
+            None => None,
+        }
+    }
+}
+
+// parses the command line, using the values of the `--config` file as defaults
+pub fn parse_args() -> Result<Args, ConfigError> {
+    let matches = arg_matches()?;
+    let args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    if let Err(message) = args.validate() {
+        Args::command()
+            .error(ErrorKind::ArgumentConflict, message)
+            .exit();
+    }
+    Ok(args)
+}
+
+// matches the command line against the arguments, using the values of the `--config` file as
+// defaults
+pub fn arg_matches() -> Result<ArgMatches, ConfigError> {
+    let argv: Vec<OsString> = std::env::args_os().collect();
+    let mut command = Args::command();
+    if let Some(path) = find_config_path(&argv) {
+        command = apply_config(command, &Config::load(&path)?);
+    }
+    Ok(command.get_matches_from(argv))
+}
+
+// parses the arguments of a resource whose spec is `config`, with the values matched in
+// `fallback` as the defaults of the fields missing from it; the environment is only read
+// through `fallback`
+pub fn spec_args(fallback: &ArgMatches, config: &Config) -> Result<Args, clap::Error> {
+    let command = Args::command().mut_args(|arg| {
+        let values = fallback
+            .try_get_raw(arg.get_id().as_str())
+            .ok()
+            .flatten()
+            .map(|values| values.map(OsStr::to_os_string).collect::<Vec<_>>());
+        let arg = arg.env(None);
+        match values {
+            Some(values) => arg.default_values(values).required(false),
+            None => arg,
+        }
+    });
+    let matches = apply_config(command, config).try_get_matches_from([env!("CARGO_PKG_NAME")])?;
+    let args = Args::from_arg_matches(&matches)?;
+    args.validate()
+        .map_err(|message| Args::command().error(ErrorKind::ArgumentConflict, message))?;
+    Ok(args)
+}
+
+// finds the value of `--config` or `CONFIG` without fully parsing the command line
+pub fn find_config_path(argv: &[OsString]) -> Option<PathBuf> {
+    let mut argv = argv.iter();
+    while let Some(arg) = argv.next() {
+        if arg == "--config" {
+            return argv.next().map(PathBuf::from);
+        }
+        if let Some(path) = arg.to_str().and_then(|arg| arg.strip_prefix("--config=")) {
+            return Some(PathBuf::from(path));
+        }
+    }
+    std::env::var_os("CONFIG").map(PathBuf::from)
+}
+
+// uses the values set in `config` as the default values of the matching arguments
+pub fn apply_config(command: clap::Command, config: &Config) -> clap::Command {
+    config
+        .arg_defaults()
+        .into_iter()
+        .fold(command, |command, (id, values)| {
+            // a default is enough to satisfy an otherwise required argument
+            command.mut_arg(id, |arg| arg.default_values(values).required(false))
+        })
+}
+
+// parses a `key=value` command line argument
+pub fn parse_key_val(s: &str) -> Result<(String, String), String> {
+    let (key, value) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected KEY=VALUE, got `{}`", s))?;
+    Ok((String::from(key), String::from(value)))
+}
+
+// the parser of the limits that would stop every sync at 0, such as `--concurrency`
+fn at_least_one() -> RangedU64ValueParser<usize> {
+    RangedU64ValueParser::new().range(1..)
+}
+
+// parses a `namespace/name` command line argument
+pub fn parse_namespaced_name(s: &str) -> Result<(String, String), String> {
+    match s.split_once('/') {
+        Some((namespace, name)) if !namespace.is_empty() && !name.is_empty() => {
+            Ok((String::from(namespace), String::from(name)))
+        }
+        _ => Err(format!("expected NAMESPACE/NAME, got `{}`", s)),
+    }
+}
+
+// compiles the `--namespace-filter` regular expression so that it must match a whole name
+pub fn parse_namespace_filter(s: &str) -> Result<Regex, regex::Error> {
+    Regex::new(&format!("^(?:{})$", s))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::sync::{PoisonError, RwLock, RwLockReadGuard};
+
+    use super::*;
+
+    // arguments are parsed while holding this for reading, and the environment is only changed
+    // while holding it for writing, so no test sees the variables set by another
+    static ENV: RwLock<()> = RwLock::new(());
+
+    // guards against the environment changing while the guard is held
+    pub(crate) fn env_guard() -> RwLockReadGuard<'static, ()> {
+        ENV.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // parses the required arguments followed by `extra`
+    pub(crate) fn args(extra: &[&str]) -> Args {
+        try_args(extra).unwrap()
+    }
+
+    // parses the required arguments followed by `extra`, which may be invalid
+    pub(crate) fn try_args(extra: &[&str]) -> Result<Args, clap::Error> {
+        let _env = env_guard();
+        parse_required(extra)
+    }
+
+    // parses the required arguments followed by `extra`, in whatever environment is set
+    fn parse_required(extra: &[&str]) -> Result<Args, clap::Error> {
+        let required = [
+            "k8s_aws_secrets_sync",
+            "-n",
+            "ns",
+            "-s",
+            "name",
+            "-f",
+            "file",
+        ];
+        Args::try_parse_from(required.iter().chain(extra))
+    }
+
+    // parses `argv` with the values of `config` as defaults
+    fn args_with_config(config: &Config, argv: &[&str]) -> Args {
+        let _env = env_guard();
+        let command = apply_config(Args::command(), config);
+        let matches = command
+            .try_get_matches_from(["k8s_aws_secrets_sync"].iter().chain(argv))
+            .unwrap();
+        Args::from_arg_matches(&matches).unwrap()
+    }
+
+    #[test]
+    fn config_round_trips_through_toml() {
+        let config: Config = toml::from_str(include_str!("../config.toml")).unwrap();
+        let round_tripped: Config = toml::from_str(&toml::to_string(&config).unwrap()).unwrap();
+        assert_eq!(round_tripped, config);
+    }
+
+    #[test]
+    fn config_provides_defaults_for_args() {
+        let config: Config = toml::from_str(include_str!("../config.toml")).unwrap();
+        let args = args_with_config(&config, &[]);
+        assert_eq!(args.namespace_tag, ["/fhm/k8s/namespace"]);
+        assert_eq!(args.aws_region.as_deref(), Some("eu-west-1"));
+        assert!(matches!(args.log_format, LogFormat::Json));
+        assert!(!args.delete_orphans);
+        assert_eq!(
+            args.labels,
+            [(String::from("managed-by"), String::from("aws-sync"))]
+        );
+    }
+
+    #[test]
+    fn command_line_overrides_config() {
+        let config: Config = toml::from_str(include_str!("../config.toml")).unwrap();
+        let args = args_with_config(&config, &["--aws-region", "us-east-1", "--delete-orphans"]);
+        assert_eq!(args.aws_region.as_deref(), Some("us-east-1"));
+        assert!(args.delete_orphans);
+    }
+
+    #[test]
+    fn find_config_path_in_either_form() {
+        let argv = |args: &[&str]| args.iter().map(OsString::from).collect::<Vec<_>>();
+        assert_eq!(
+            find_config_path(&argv(&["bin", "--config", "a.toml"])),
+            Some(PathBuf::from("a.toml"))
+        );
+        assert_eq!(
+            find_config_path(&argv(&["bin", "--config=b.toml"])),
+            Some(PathBuf::from("b.toml"))
+        );
+        assert_eq!(find_config_path(&argv(&["bin", "-n", "ns"])), None);
+    }
+
+    #[test]
+    fn env_vars_override_defaults_but_not_flags() {
+        let env = ENV.write().unwrap_or_else(PoisonError::into_inner);
+        std::env::set_var("BINARY_KEY", "from-env");
+        let from_env = parse_required(&[]);
+        let from_flag = parse_required(&["--binary-key", "from-flag"]);
+        std::env::remove_var("BINARY_KEY");
+        drop(env);
+        let (from_env, from_flag) = (from_env.unwrap(), from_flag.unwrap());
+
+        assert_eq!(from_env.binary_key, "from-env");
+        assert_eq!(from_flag.binary_key, "from-flag");
+    }
+
+    #[test]
+    fn parse_key_val_requires_equals() {
+        assert_eq!(
+            parse_key_val("env=prod=eu").unwrap(),
+            (String::from("env"), String::from("prod=eu"))
+        );
+        assert!(parse_key_val("env").is_err());
+    }
+
+    #[test]
+    fn parse_namespaced_name_requires_both_parts() {
+        assert_eq!(
+            parse_namespaced_name("kube-system/aws-creds").unwrap(),
+            (String::from("kube-system"), String::from("aws-creds"))
+        );
+        assert!(parse_namespaced_name("aws-creds").is_err());
+        assert!(parse_namespaced_name("/aws-creds").is_err());
+    }
+
+    #[test]
+    fn generate_helm_values_follows_the_arguments() {
+        assert_eq!(args(&[]).command, None);
+        assert_eq!(
+            args(&["--dry-run", "generate-helm-values"]).command,
+            Some(Command::GenerateHelmValues {
+                schedule: String::from("*/15 * * * *")
+            })
+        );
+        assert_eq!(args(&["generate-crd"]).command, Some(Command::GenerateCrd));
+    }
+
+    #[test]
+    fn version_id_conflicts_with_version_stage() {
+        assert_eq!(
+            args(&["--version-id", "v1"]).version_id.as_deref(),
+            Some("v1")
+        );
+
+        let both = try_args(&["--version-id", "v1", "--version-stage", "AWSPENDING"]);
+        assert!(both.is_err());
+    }
+
+    #[test]
+    fn watch_interval_defaults_to_a_minute() {
+        assert_eq!(args(&[]).watch_interval(), None);
+        assert_eq!(
+            args(&["--watch"]).watch_interval(),
+            Some(DEFAULT_WATCH_INTERVAL)
+        );
+        assert_eq!(
+            args(&["--watch-interval-seconds", "15"]).watch_interval(),
+            Some(Duration::from_secs(15))
+        );
+    }
+
+    #[test]
+    fn namespace_filter_must_be_a_valid_regex() {
+        let filter = args(&["--namespace-filter", "prod-.*"]).namespace_filter;
+        assert!(filter.unwrap().is_match("prod-eu"));
+
+        let invalid = try_args(&["--namespace-filter", "prod-("]);
+        assert!(invalid.is_err());
+    }
+
+    #[test]
+    fn output_manifest_conflicts_with_dry_run() {
+        assert_eq!(args(&[]).output, Output::Apply);
+        assert_eq!(args(&["--output", "manifest"]).output, Output::Manifest);
+
+        let both = try_args(&["--output", "manifest", "--dry-run"]);
+        assert!(both.is_err());
+    }
+
+    #[test]
+    fn vault_kubernetes_auth_requires_a_role() {
+        assert_eq!(args(&[]).vault_auth_method, VaultAuthMethod::Token);
+        let kubernetes = args(&[
+            "--vault-auth-method",
+            "kubernetes",
+            "--vault-role",
+            "secrets-sync",
+        ]);
+        assert_eq!(kubernetes.vault_auth_method, VaultAuthMethod::Kubernetes);
+        assert_eq!(kubernetes.vault_role.as_deref(), Some("secrets-sync"));
+        assert_eq!(kubernetes.vault_auth_mount, "kubernetes");
+
+        let without_role = try_args(&["--vault-auth-method", "kubernetes"]);
+        assert!(without_role.is_err());
+    }
+
+    #[test]
+    fn validate_rejects_keys_both_included_and_excluded() {
+        let included = args(&["--include-keys", "username,password"]);
+        assert_eq!(included.include_keys, ["username", "password"]);
+        assert!(included.validate().is_ok());
+
+        let conflicting = args(&[
+            "--include-keys",
+            "username,password",
+            "--exclude-keys",
+            "_comment,password",
+        ]);
+        assert_eq!(
+            conflicting.validate().unwrap_err(),
+            "keys given to both --include-keys and --exclude-keys: password"
+        );
+    }
+
+    #[test]
+    fn validate_rejects_delete_orphans_with_tag_filters() {
+        let name_filtered = args(&["--delete-orphans", "--exclude-pattern", "^legacy/"]);
+        assert!(name_filtered.validate().is_ok());
+
+        let tag_filtered = args(&["--delete-orphans", "--include-tag", "team=payments"]);
+        assert_eq!(
+            tag_filtered.validate().unwrap_err(),
+            "--delete-orphans can't be combined with --include-tag"
+        );
+        let described = args(&["--delete-orphans", "--description-contains", "myapp"]);
+        assert!(described.validate().is_err());
+    }
+
+    #[test]
+    fn parser_accepts_mime_types() {
+        assert_eq!(args(&[]).parser, SecretFormat::Json);
+        assert_eq!(
+            args(&["--parser", "application/x-www-form-urlencoded"]).parser,
+            SecretFormat::Form
+        );
+        assert_eq!(
+            args(&["--parser", "text/plain"]).parser,
+            SecretFormat::Plain
+        );
+    }
+
+    #[test]
+    fn concurrency_must_be_at_least_one() {
+        assert_eq!(args(&["--concurrency", "1"]).concurrency, 1);
+        assert!(try_args(&["--concurrency", "0"]).is_err());
+    }
+
+    #[test]
+    fn k8s_concurrency_must_be_at_least_one() {
+        assert_eq!(args(&["--k8s-concurrency", "1"]).k8s_concurrency, 1);
+        assert!(try_args(&["--k8s-concurrency", "0"]).is_err());
+    }
+}
@@ -0,0 +1,237 @@
+// An audit trail of every action taken on the secrets, with `--audit-log` and `--audit-syslog`.
+//
+// Events are sent over a channel to a background task doing the writes, so a slow disk or
+// syslog daemon never holds up the sync. The task flushes after each batch of events, and
+// finishes once every `AuditLog` is dropped and `AuditWriter::close` is awaited.
+
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::Serialize;
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::net::UnixDatagram;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::task::JoinHandle;
+use tracing::warn;
+
+/// The socket of the local syslog daemon
+pub const SYSLOG_SOCKET: &str = "/dev/log";
+
+/// The syslog priority of audit events, the user facility at the informational level
+const SYSLOG_PRIORITY: u8 = 14;
+
+/// The number of events written before each flush, at most
+const BATCH_SIZE: usize = 64;
+
+/// The kind of action an audit event records
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuditAction {
+    /// The secrets of a source were listed
+    List,
+    /// The value of a secret was read
+    Fetch,
+    /// A Kubernetes object was applied
+    Patch,
+    /// A secret was left out of the sync
+    Skip,
+}
+
+/// A single action taken during a sync
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct AuditEvent {
+    pub timestamp: String,
+    pub action: AuditAction,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub aws_secret: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub k8s_namespace: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub k8s_secret: Option<String>,
+    /// `ok`, `unchanged` or `error`, `skipped` for skips
+    pub result: String,
+    /// Why the action failed or the secret was skipped
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+impl AuditEvent {
+    /// An event for `action` on `aws_secret` at `now`, with an `ok` result
+    pub fn new(action: AuditAction, aws_secret: Option<&str>, now: DateTime<Utc>) -> Self {
+        AuditEvent {
+            timestamp: now.to_rfc3339_opts(SecondsFormat::Millis, true),
+            action,
+            aws_secret: aws_secret.map(String::from),
+            k8s_namespace: None,
+            k8s_secret: None,
+            result: String::from("ok"),
+            reason: None,
+        }
+    }
+
+    /// Sets the Kubernetes object the action was taken on
+    pub fn k8s(mut self, namespace: &str, name: &str) -> Self {
+        self.k8s_namespace = Some(String::from(namespace));
+        self.k8s_secret = Some(String::from(name));
+        self
+    }
+
+    /// Sets the result, along with the reason for it
+    pub fn result(mut self, result: &str, reason: Option<String>) -> Self {
+        self.result = String::from(result);
+        self.reason = reason;
+        self
+    }
+}
+
+/// Records audit events, or does nothing when no audit destination is configured
+#[derive(Clone, Debug, Default)]
+pub struct AuditLog {
+    sender: Option<UnboundedSender<AuditEvent>>,
+}
+
+impl AuditLog {
+    /// Starts writing events to `path` and/or syslog in the background; the log is disabled
+    /// when neither is given
+    pub async fn open(path: Option<&Path>, syslog: bool) -> std::io::Result<(Self, AuditWriter)> {
+        let file = match path {
+            Some(path) => {
+                let file = tokio::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .await?;
+                Some((path.to_path_buf(), BufWriter::new(file)))
+            }
+            None => None,
+        };
+        let syslog = if syslog {
+            Some(UnixDatagram::unbound()?)
+        } else {
+            None
+        };
+        if file.is_none() && syslog.is_none() {
+            return Ok((AuditLog::default(), AuditWriter(None)));
+        }
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let task = tokio::spawn(write_events(receiver, file, syslog));
+        Ok((
+            AuditLog {
+                sender: Some(sender),
+            },
+            AuditWriter(Some(task)),
+        ))
+    }
+
+    /// Queues `event` to be written
+    pub fn record(&self, event: AuditEvent) {
+        if let Some(sender) = &self.sender {
+            // the writer only stops once every sender is dropped
+            let _ = sender.send(event);
+        }
+    }
+}
+
+/// The background task writing the events of an `AuditLog`
+pub struct AuditWriter(Option<JoinHandle<()>>);
+
+impl AuditWriter {
+    /// Waits for the queued events to be written, once every `AuditLog` has been dropped
+    pub async fn close(self) {
+        if let Some(task) = self.0 {
+            if let Err(e) = task.await {
+                warn!("Audit log writer failed: {}", e);
+            }
+        }
+    }
+}
+
+// writes each event received to the file and syslog until every sender is dropped
+async fn write_events(
+    mut receiver: UnboundedReceiver<AuditEvent>,
+    mut file: Option<(PathBuf, BufWriter<tokio::fs::File>)>,
+    syslog: Option<UnixDatagram>,
+) {
+    let mut events = Vec::with_capacity(BATCH_SIZE);
+    while receiver.recv_many(&mut events, BATCH_SIZE).await > 0 {
+        for event in events.drain(..) {
+            let line = serde_json::to_string(&event).expect("audit events serialise to JSON");
+            if let Some((path, writer)) = &mut file {
+                let written = async {
+                    writer.write_all(line.as_bytes()).await?;
+                    writer.write_all(b"\n").await
+                };
+                if let Err(e) = written.await {
+                    warn!("Failed to write audit log {}: {}", path.display(), e);
+                }
+            }
+            if let Some(socket) = &syslog {
+                if let Err(e) = socket
+                    .send_to(syslog_message(&line).as_bytes(), SYSLOG_SOCKET)
+                    .await
+                {
+                    warn!("Failed to send audit event to syslog: {}", e);
+                }
+            }
+        }
+        if let Some((path, writer)) = &mut file {
+            if let Err(e) = writer.flush().await {
+                warn!("Failed to flush audit log {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+// formats `line` as a message for the local syslog daemon
+fn syslog_message(line: &str) -> String {
+    format!("<{}>k8s-aws-secrets-sync: {}", SYSLOG_PRIORITY, line)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn now() -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339("2024-11-20T10:30:00Z")
+            .unwrap()
+            .to_utc()
+    }
+
+    #[test]
+    fn audit_event_serialises_to_one_json_line() {
+        let event = AuditEvent::new(AuditAction::Patch, Some("db-creds"), now()).k8s("prod", "db");
+        assert_eq!(
+            serde_json::to_string(&event).unwrap(),
+            r#"{"timestamp":"2024-11-20T10:30:00.000Z","action":"patch","aws_secret":"db-creds","k8s_namespace":"prod","k8s_secret":"db","result":"ok"}"#
+        );
+
+        let skipped = AuditEvent::new(AuditAction::Skip, Some("db-creds"), now())
+            .result("skipped", Some(String::from("missing tag")));
+        assert_eq!(
+            serde_json::to_value(&skipped).unwrap()["reason"],
+            "missing tag"
+        );
+        assert_eq!(syslog_message("{}"), "<14>k8s-aws-secrets-sync: {}");
+    }
+
+    #[tokio::test]
+    async fn audit_log_appends_every_event_before_closing() {
+        let path = std::env::temp_dir().join(format!("audit-{}.log", std::process::id()));
+        std::fs::write(&path, "existing\n").unwrap();
+
+        let (audit, writer) = AuditLog::open(Some(&path), false).await.unwrap();
+        audit.record(AuditEvent::new(AuditAction::List, None, now()));
+        audit
+            .clone()
+            .record(AuditEvent::new(AuditAction::Fetch, Some("db-creds"), now()));
+        drop(audit);
+        writer.close().await;
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<_> = contents.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], "existing");
+        assert!(lines[2].contains(r#""action":"fetch""#));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
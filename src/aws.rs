@@ -0,0 +1,1374 @@
+// Reading secrets and their tags from AWS Secrets Manager.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use aws_config::sts::AssumeRoleProvider;
+use aws_config::{BehaviorVersion, Region, SdkConfig};
+use aws_credential_types::Credentials;
+use aws_sdk_secretsmanager::config::SharedCredentialsProvider;
+use aws_sdk_secretsmanager::error::ProvideErrorMetadata;
+use aws_sdk_secretsmanager::operation::get_secret_value::builders::GetSecretValueFluentBuilder;
+use aws_sdk_secretsmanager::types::{Filter, FilterNameStringType, SecretListEntry, Tag};
+use chrono::{DateTime, Duration, SecondsFormat, Utc};
+use k8s_openapi::api::core::v1::Secret;
+use k8s_openapi::ByteString;
+use kube::Api;
+use regex::Regex;
+use tracing::{debug, info, warn};
+
+use crate::args::{parse_key_val, Args, AwsPartition, DataFormat};
+use crate::k8s::{validate_k8s_name, NameError};
+use crate::retry::{retry_with_backoff, RetryPolicy};
+
+/// The tag recording on the AWS secret when it was last synced, with `--tag-on-success`
+pub const LAST_SYNC_TAG: &str = "k8s-aws-secrets-sync/last-sync";
+
+/// The session name used when assuming `--assume-role-arn`
+pub const ASSUME_ROLE_SESSION_NAME: &str = "k8s-aws-secrets-sync";
+
+/// Errors raised while reading the tags of an AWS secret
+#[derive(thiserror::Error, Debug)]
+pub enum TagError {
+    /// The AWS secret does not carry a tag that is required to sync it
+    #[error("AWS secret {arn} is missing required tag `{tag}`")]
+    Missing { arn: String, tag: String },
+
+    /// The secret name computed from the tags is not a valid Kubernetes name
+    #[error("AWS secret {arn} has an invalid Kubernetes secret name: {source}")]
+    InvalidName { arn: String, source: NameError },
+
+    /// The secret name template can't be resolved from the tags of the AWS secret
+    #[error("AWS secret {arn} doesn't match the secret name template: {source}")]
+    Template { arn: String, source: TemplateError },
+}
+
+/// Errors raised while resolving a `{{key}}` template from the tags of an AWS secret
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum TemplateError {
+    /// A placeholder names a tag the AWS secret doesn't carry
+    #[error("missing tag `{0}`")]
+    MissingTag(String),
+
+    /// A `{{` has no matching `}}`
+    #[error("unclosed placeholder in `{0}`")]
+    Unclosed(String),
+}
+
+/// Errors raised by `--preflight-check`
+#[derive(thiserror::Error, Debug)]
+pub enum PreflightError {
+    /// The IAM principal isn't allowed to read the value of the AWS secret
+    #[error(
+        "access denied reading {arn}; check secretsmanager:GetSecretValue is allowed by the \
+IAM policy of the principal and the resource policy of the secret: {source}"
+    )]
+    AccessDenied {
+        arn: String,
+        source: Box<aws_sdk_secretsmanager::Error>,
+    },
+
+    /// Reading the value of the AWS secret failed for another reason
+    #[error("failed to read {arn}: {source}")]
+    Aws {
+        arn: String,
+        source: Box<aws_sdk_secretsmanager::Error>,
+    },
+}
+
+/// Errors raised while reading AWS credentials from `--aws-credentials-secret`
+#[derive(thiserror::Error, Debug)]
+pub enum CredentialsError {
+    /// The Kubernetes secret could not be read
+    #[error("failed to read AWS credentials secret {namespace}/{name}: {source}")]
+    Kubernetes {
+        namespace: String,
+        name: String,
+        source: Box<kube::Error>,
+    },
+
+    /// A required key is missing from the Kubernetes secret
+    #[error("AWS credentials secret has no `{key}` key")]
+    MissingKey { key: &'static str },
+
+    /// A value of the Kubernetes secret is not UTF-8
+    #[error("the `{key}` of the AWS credentials secret is not UTF-8")]
+    NotUtf8 { key: &'static str },
+}
+
+/// The version of the AWS secrets that is read, by staging label or pinned by ID
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SecretVersion<'a> {
+    Stage(&'a str),
+    Id(&'a str),
+}
+
+impl<'a> SecretVersion<'a> {
+    /// `--version-id` when given, `--version-stage` otherwise
+    pub fn from_args(args: &'a Args) -> Self {
+        match &args.version_id {
+            Some(version_id) => SecretVersion::Id(version_id),
+            None => SecretVersion::Stage(&args.version_stage),
+        }
+    }
+
+    /// Sets the version on a GetSecretValue request
+    pub fn apply(self, request: GetSecretValueFluentBuilder) -> GetSecretValueFluentBuilder {
+        match self {
+            SecretVersion::Stage(stage) => request.version_stage(stage),
+            SecretVersion::Id(id) => request.version_id(id),
+        }
+    }
+
+    /// The version as an External Secrets Operator `remoteRef`, which prefixes IDs with `uuid/`
+    pub fn remote_ref(self) -> String {
+        match self {
+            SecretVersion::Stage(stage) => String::from(stage),
+            SecretVersion::Id(id) => format!("uuid/{}", id),
+        }
+    }
+}
+
+impl fmt::Display for SecretVersion<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SecretVersion::Stage(stage) => write!(f, "version stage {}", stage),
+            SecretVersion::Id(id) => write!(f, "version {}", id),
+        }
+    }
+}
+
+/// The tag values of an AWS secret that are needed to sync it
+#[derive(Debug)]
+pub struct ValidatedSecret {
+    pub secret_name: String,
+    pub namespaces: Vec<String>,
+    /// The files the secret values are stored in, empty to store each value under its own key
+    pub filenames: Vec<String>,
+    /// The type of the Kubernetes secret from `--secret-type-tag`, `None` for `--secret-type`
+    pub secret_type: Option<String>,
+    /// The labels added to the namespaces from `--namespace-label-tag`
+    pub namespace_labels: BTreeMap<String, String>,
+}
+
+// loads the shared AWS configuration in `--aws-region` if given, using `credentials` in place
+// of the default credential chain when given, and the credentials of `--assume-role-arn` when
+// it is set
+pub async fn load_aws_config(args: &Args, credentials: Option<Credentials>) -> SdkConfig {
+    let mut loader = aws_config::defaults(BehaviorVersion::v2024_03_28());
+    if let Some(region) = &args.aws_region {
+        loader = loader.region(Region::new(region.clone()));
+    }
+    if let Some(credentials) = credentials {
+        loader = loader.credentials_provider(credentials);
+    }
+    let config = loader.load().await;
+
+    let Some(role_arn) = &args.assume_role_arn else {
+        return config;
+    };
+    debug!(
+        "Assuming role {} with session name {}",
+        role_arn, ASSUME_ROLE_SESSION_NAME
+    );
+    let mut provider = AssumeRoleProvider::builder(role_arn)
+        .session_name(ASSUME_ROLE_SESSION_NAME)
+        .configure(&config);
+    if let Some(external_id) = &args.assume_role_external_id {
+        provider = provider.external_id(external_id);
+    }
+    config
+        .to_builder()
+        .credentials_provider(SharedCredentialsProvider::new(provider.build().await))
+        .build()
+}
+
+// reads the AWS credentials from the Kubernetes secret `name` in `namespace`; they are only held
+// by the credentials provider, rather than put in the environment of the process
+pub async fn read_credentials_secret(
+    client: &kube::Client,
+    namespace: &str,
+    name: &str,
+) -> Result<Credentials, CredentialsError> {
+    let secrets: Api<Secret> = Api::namespaced(client.clone(), namespace);
+    let secret = secrets
+        .get(name)
+        .await
+        .map_err(|source| CredentialsError::Kubernetes {
+            namespace: String::from(namespace),
+            name: String::from(name),
+            source: Box::new(source),
+        })?;
+    info!("Using the AWS credentials in secret {}/{}", namespace, name);
+    credentials_from_secret_data(&secret.data.unwrap_or_default())
+}
+
+// builds AWS credentials from the `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY` and optional
+// `AWS_SESSION_TOKEN` values of a Kubernetes secret
+pub fn credentials_from_secret_data(
+    data: &BTreeMap<String, ByteString>,
+) -> Result<Credentials, CredentialsError> {
+    let value = |key: &'static str| -> Result<Option<String>, CredentialsError> {
+        data.get(key)
+            .map(|value| {
+                String::from_utf8(value.0.clone())
+                    .map(|value| value.trim().to_owned())
+                    .map_err(|_| CredentialsError::NotUtf8 { key })
+            })
+            .transpose()
+    };
+    let required = |key: &'static str| value(key)?.ok_or(CredentialsError::MissingKey { key });
+    Ok(Credentials::new(
+        required("AWS_ACCESS_KEY_ID")?,
+        required("AWS_SECRET_ACCESS_KEY")?,
+        value("AWS_SESSION_TOKEN")?,
+        None,
+        "KubernetesSecret",
+    ))
+}
+
+// creates one Secrets Manager client for the primary region followed by one per extra region
+pub fn build_secretsmanager_clients(
+    config: &SdkConfig,
+    args: &Args,
+) -> Vec<aws_sdk_secretsmanager::Client> {
+    let primary = config.region().map(|region| region.to_string());
+    std::iter::once(primary)
+        .chain(args.extra_regions.iter().cloned().map(Some))
+        .map(|region| {
+            let config = regional_config(config, region, args.aws_partition);
+            aws_sdk_secretsmanager::Client::new(&config)
+        })
+        .collect()
+}
+
+// the configuration of the Secrets Manager client for `region`, targeting the endpoint of
+// `partition` when one is given
+pub fn regional_config(
+    config: &SdkConfig,
+    region: Option<String>,
+    partition: Option<AwsPartition>,
+) -> SdkConfig {
+    let mut builder = config.to_builder();
+    if let Some(region) = &region {
+        builder = builder.region(Region::new(region.clone()));
+    }
+    if let (Some(region), Some(partition)) = (&region, partition) {
+        builder = builder.endpoint_url(service_endpoint("secretsmanager", region, partition));
+    }
+    builder.build()
+}
+
+// the endpoint of the AWS `service` in `region` of `partition`
+pub fn service_endpoint(service: &str, region: &str, partition: AwsPartition) -> String {
+    let domain = match partition {
+        AwsPartition::Aws | AwsPartition::AwsUsGov => "amazonaws.com",
+        AwsPartition::AwsCn => "amazonaws.com.cn",
+    };
+    format!("https://{}.{}.{}", service, region, domain)
+}
+
+// logs the IAM principal `config` authenticates as, which helps debugging access denied errors
+pub async fn log_caller_identity(config: &SdkConfig) {
+    let sts = aws_sdk_sts::Client::new(config);
+    match sts.get_caller_identity().send().await {
+        Ok(identity) => info!(
+            "Using IAM principal {}",
+            identity.arn().unwrap_or("<unknown>")
+        ),
+        Err(e) => warn!(
+            "Failed to get the IAM principal: {}",
+            aws_sdk_sts::Error::from(e)
+        ),
+    }
+}
+
+// reads the value of `secret` to check access before syncing anything
+pub async fn preflight_check(
+    client: &aws_sdk_secretsmanager::Client,
+    secret: &SecretListEntry,
+    version: SecretVersion<'_>,
+) -> Result<(), PreflightError> {
+    let arn = secret
+        .arn
+        .clone()
+        .or(secret.name.clone())
+        .unwrap_or_default();
+    debug!("Preflight check reading {}", arn);
+    let result = version
+        .apply(client.get_secret_value().secret_id(&arn))
+        .send()
+        .await;
+    match result.map_err(aws_sdk_secretsmanager::Error::from) {
+        Ok(_) => Ok(()),
+        Err(e) if e.code() == Some("AccessDeniedException") => Err(PreflightError::AccessDenied {
+            arn,
+            source: Box::new(e),
+        }),
+        Err(e) => Err(PreflightError::Aws {
+            arn,
+            source: Box::new(e),
+        }),
+    }
+}
+
+// strips the region from an ARN so replicas of a secret in different regions compare equal
+pub fn arn_without_region(arn: &str) -> String {
+    arn.split(':')
+        .enumerate()
+        .map(|(i, part)| if i == 3 { "" } else { part })
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+// lists every secret matching `filters`, following `next_token` until all pages are fetched
+// and retrying each page with `retry_policy`
+pub async fn list_all_secrets(
+    client: &aws_sdk_secretsmanager::Client,
+    filters: Vec<Filter>,
+    retry_policy: &RetryPolicy,
+) -> Result<Vec<SecretListEntry>, aws_sdk_secretsmanager::Error> {
+    let mut secrets = Vec::new();
+    let mut next_token: Option<String> = None;
+    loop {
+        let page = retry_with_backoff(retry_policy, || {
+            client
+                .list_secrets()
+                .set_filters(Some(filters.clone()))
+                .set_next_token(next_token.clone())
+                .send()
+        })
+        .await?;
+        secrets.extend(page.secret_list.unwrap_or_default());
+        next_token = page.next_token;
+        if next_token.is_none() {
+            return Ok(secrets);
+        }
+    }
+}
+
+// checks that the AWS secret carries all the tags required to sync it and extracts their values
+pub fn validate_secret_tags(
+    secret: &SecretListEntry,
+    args: &Args,
+) -> Result<ValidatedSecret, TagError> {
+    let secret_name = k8s_secret_name(
+        &get_name_from_aws_secret(secret, &args.secret_name_tag)?,
+        args,
+    );
+    validate_k8s_name(&secret_name).map_err(|source| TagError::InvalidName {
+        arn: secret.arn.clone().unwrap_or_default(),
+        source,
+    })?;
+    // the filename tags take precedence over `--format`
+    let mut filenames =
+        get_filenames_from_aws_secret(secret, &args.filename_tag, &args.extra_filename_tag);
+    if filenames.is_empty() && args.format == DataFormat::EnvFile {
+        filenames.push(args.env_file_name.clone());
+    }
+    Ok(ValidatedSecret {
+        secret_name,
+        namespaces: get_namespaces_from_aws_secret(
+            secret,
+            &args.namespace_tag,
+            args.namespace_filter.as_ref(),
+        )?,
+        filenames,
+        secret_type: args
+            .secret_type_tag
+            .as_deref()
+            .and_then(|tag| get_secret_type_from_aws_secret(secret, tag)),
+        namespace_labels: args
+            .namespace_label_tag
+            .as_deref()
+            .map(|tag| get_namespace_labels_from_aws_secret(secret, tag))
+            .unwrap_or_default(),
+    })
+}
+
+// builds the filters of the list_secrets call from the tag and name options in `args`
+pub fn build_list_filters(args: &Args) -> Vec<Filter> {
+    // get secrets that have a tag with one of the keys of `namespace_tag`
+    // the values of a single filter match as OR conditions
+    let filter = Filter::builder()
+        .key(FilterNameStringType::TagKey)
+        .set_values(Some(args.namespace_tag.clone()))
+        .build();
+    let mut filters = vec![filter];
+
+    // AWS Secrets Manager applies multiple filters as AND conditions
+    if let Some(prefix) = args.secret_prefix.as_deref().filter(|p| !p.is_empty()) {
+        let filter = Filter::builder()
+            .key(FilterNameStringType::Name)
+            .values(prefix)
+            .build();
+        filters.push(filter);
+    }
+
+    // the key and value filters match independently of each other, so `is_in_scope` checks
+    // that both belong to the same tag
+    for (key, value) in &args.include_tags {
+        filters.push(
+            Filter::builder()
+                .key(FilterNameStringType::TagKey)
+                .values(key)
+                .build(),
+        );
+        filters.push(
+            Filter::builder()
+                .key(FilterNameStringType::TagValue)
+                .values(value)
+                .build(),
+        );
+    }
+    filters
+}
+
+// the name of the AWS secret `arn`, without the random suffix Secrets Manager appends to it
+pub fn secret_name_from_arn(arn: &str) -> Option<&str> {
+    let name = arn.splitn(7, ':').nth(6)?;
+    match name.rsplit_once('-') {
+        Some((name, suffix)) if suffix.len() == 6 => Some(name),
+        _ => Some(name),
+    }
+}
+
+// whether the AWS secret `name` starts with `--secret-prefix`, matches `--secret-filter-name`
+// and doesn't match `--exclude-pattern`
+pub fn is_name_in_scope(name: &str, args: &Args) -> bool {
+    let has_prefix = args
+        .secret_prefix
+        .as_deref()
+        .is_none_or(|prefix| name.starts_with(prefix));
+    let is_selected = args
+        .secret_filter_name
+        .as_ref()
+        .is_none_or(|filter| filter.is_match(name));
+    let is_excluded = args
+        .exclude_pattern
+        .as_ref()
+        .is_some_and(|pattern| pattern.is_match(name));
+    has_prefix && is_selected && !is_excluded
+}
+
+// whether a listed AWS secret has every `--include-tag`, matches `--secret-filter-name` and
+// `--description-contains`, and doesn't match `--exclude-pattern`
+pub fn is_in_scope(secret: &SecretListEntry, args: &Args) -> bool {
+    let has_included_tags = has_tags(secret, &args.include_tags);
+    let name = secret.name.as_deref().unwrap_or_default();
+    let is_selected = args
+        .secret_filter_name
+        .as_ref()
+        .is_none_or(|filter| filter.is_match(name));
+    let is_described = args.description_contains.as_ref().is_none_or(|text| {
+        secret
+            .description
+            .as_deref()
+            .unwrap_or_default()
+            .contains(text.as_str())
+    });
+    let is_excluded = args
+        .exclude_pattern
+        .as_ref()
+        .is_some_and(|pattern| pattern.is_match(name));
+    if is_excluded {
+        debug!("Excluding secret {} matching --exclude-pattern", name);
+    }
+    has_included_tags && is_selected && is_described && !is_excluded
+}
+
+// tags the AWS secret `secret_id` with the time it was synced to Kubernetes
+pub async fn tag_last_sync(
+    client: &aws_sdk_secretsmanager::Client,
+    secret_id: &str,
+    synced_at: DateTime<Utc>,
+) -> Result<(), aws_sdk_secretsmanager::Error> {
+    let tag = Tag::builder()
+        .key(LAST_SYNC_TAG)
+        .value(synced_at.to_rfc3339_opts(SecondsFormat::Secs, true))
+        .build();
+    client
+        .tag_resource()
+        .secret_id(secret_id)
+        .tags(tag)
+        .send()
+        .await?;
+    Ok(())
+}
+
+// the ARN, creation date and last changed date of the AWS secret, under keys starting with
+// `prefix`; the dates are RFC 3339 and absent fields are left out
+pub fn secret_metadata(secret: &SecretListEntry, prefix: &str) -> BTreeMap<String, String> {
+    let format_date = |date: &aws_sdk_secretsmanager::primitives::DateTime| {
+        DateTime::from_timestamp(date.secs(), date.subsec_nanos())
+            .map(|date| date.to_rfc3339_opts(SecondsFormat::Secs, true))
+    };
+    [
+        ("arn", secret.arn.clone()),
+        ("created_date", secret.created_date().and_then(format_date)),
+        (
+            "last_changed_date",
+            secret.last_changed_date().and_then(format_date),
+        ),
+    ]
+    .into_iter()
+    .filter_map(|(key, value)| Some((format!("{}{}", prefix, key), value?)))
+    .collect()
+}
+
+// the number of whole days between the last change of the AWS secret and `now`
+pub fn days_since_changed(secret: &SecretListEntry, now: DateTime<Utc>) -> Option<i64> {
+    let last_changed = secret.last_changed_date()?;
+    let last_changed = DateTime::from_timestamp(last_changed.secs(), last_changed.subsec_nanos())?;
+    Some((now - last_changed).num_days())
+}
+
+// warns when the AWS secret hasn't changed in more than `--rotation-warn-days`
+pub fn check_rotation(secret: &SecretListEntry, args: &Args, now: DateTime<Utc>) {
+    let name = secret.name.as_deref().unwrap_or_default();
+    let Some(days) = days_since_changed(secret, now) else {
+        return;
+    };
+    debug!("Secret {} last changed {} day(s) ago", name, days);
+    if let Some(limit) = args.rotation_warn_days {
+        if days > limit {
+            warn!(
+                "Secret {} hasn't been rotated in {} days, more than --rotation-warn-days {}",
+                name, days, limit
+            );
+        }
+    }
+}
+
+// whether the AWS secret has rotation enabled and was rotated less than `grace` before `now`,
+// so its rotation may still be in progress
+pub fn is_rotating(secret: &SecretListEntry, now: DateTime<Utc>, grace: Duration) -> bool {
+    let Some(last_rotated) = secret.last_rotated_date() else {
+        return false;
+    };
+    secret.rotation_enabled() == Some(true)
+        && DateTime::from_timestamp(last_rotated.secs(), last_rotated.subsec_nanos())
+            .is_some_and(|last_rotated| now - last_rotated < grace)
+}
+
+// keeps the AWS secrets that have every `key=value` tag in `labels`
+pub fn filter_secrets_by_labels(
+    secrets: Vec<SecretListEntry>,
+    labels: &[(String, String)],
+) -> Vec<SecretListEntry> {
+    secrets
+        .into_iter()
+        .filter(|secret| has_tags(secret, labels))
+        .collect()
+}
+
+// whether the AWS secret has every `key=value` tag in `tags`
+fn has_tags(secret: &SecretListEntry, tags: &[(String, String)]) -> bool {
+    tags.iter()
+        .all(|(key, value)| find_tag_value(secret, key) == Some(value.as_str()))
+}
+
+// gets the value of the tag with key `tag_key` from the AWS secret, if present
+fn find_tag_value<'a>(secret: &'a SecretListEntry, tag_key: &str) -> Option<&'a str> {
+    secret
+        .tags
+        .as_deref()
+        .unwrap_or_default()
+        .iter()
+        .find(|tag| tag.key.as_deref() == Some(tag_key))
+        .and_then(|tag| tag.value.as_deref())
+}
+
+// gets the value of the required tag with key `tag_key`, erroring if it is absent
+fn require_tag_value<'a>(secret: &'a SecretListEntry, tag_key: &str) -> Result<&'a str, TagError> {
+    find_tag_value(secret, tag_key).ok_or_else(|| TagError::Missing {
+        arn: secret.arn.clone().unwrap_or_default(),
+        tag: String::from(tag_key),
+    })
+}
+
+// gets the value of the tag with key `secret_name_tag` from the AWS secret, or resolves
+// `secret_name_tag` from its tags when it is a template such as `{{prefix}}-{{env}}-creds`
+pub fn get_name_from_aws_secret(
+    secret: &SecretListEntry,
+    secret_name_tag: &str,
+) -> Result<String, TagError> {
+    if secret_name_tag.contains("{{") {
+        return resolve_template(secret_name_tag, secret.tags()).map_err(|source| {
+            TagError::Template {
+                arn: secret.arn.clone().unwrap_or_default(),
+                source,
+            }
+        });
+    }
+    require_tag_value(secret, secret_name_tag).map(String::from)
+}
+
+// replaces each `{{key}}` placeholder of `template` with the value of the tag with key `key`
+pub fn resolve_template(template: &str, tags: &[Tag]) -> Result<String, TemplateError> {
+    let mut resolved = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        resolved.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let end = after
+            .find("}}")
+            .ok_or_else(|| TemplateError::Unclosed(String::from(template)))?;
+        let key = after[..end].trim();
+        let value = tags
+            .iter()
+            .find(|tag| tag.key() == Some(key))
+            .and_then(Tag::value)
+            .ok_or_else(|| TemplateError::MissingTag(String::from(key)))?;
+        resolved.push_str(value);
+        rest = &after[end + 2..];
+    }
+    resolved.push_str(rest);
+    Ok(resolved)
+}
+
+// adds `--secret-name-prefix` and `--secret-name-suffix` to the name in the secret name tag,
+// once `--strip-prefix` is removed from it
+pub fn k8s_secret_name(name: &str, args: &Args) -> String {
+    let name = match &args.strip_prefix {
+        Some(prefix) => strip_name_prefix(name, prefix),
+        None => String::from(name),
+    };
+    format!(
+        "{}{}{}",
+        args.secret_name_prefix.as_deref().unwrap_or_default(),
+        name,
+        args.secret_name_suffix.as_deref().unwrap_or_default()
+    )
+}
+
+// removes the leading path `prefix` from an AWS secret name and replaces the remaining
+// slashes with hyphens, e.g. `prod/myapp/db-creds` becomes `db-creds` with prefix `prod/myapp`
+pub fn strip_name_prefix(name: &str, prefix: &str) -> String {
+    let prefix = prefix.trim_end_matches('/');
+    let name = name
+        .strip_prefix(prefix)
+        .filter(|rest| rest.is_empty() || rest.starts_with('/'))
+        .unwrap_or(name);
+    name.trim_start_matches('/').replace('/', "-")
+}
+
+// gets the distinct namespaces in the tags with one of the keys `namespace_tags` from the
+// AWS secret, keeping only those matching `namespace_filter` if given; at least one of the
+// tags is required
+pub fn get_namespaces_from_aws_secret(
+    secret: &SecretListEntry,
+    namespace_tags: &[String],
+    namespace_filter: Option<&Regex>,
+) -> Result<Vec<String>, TagError> {
+    let values: Vec<_> = namespace_tags
+        .iter()
+        .filter_map(|tag| find_tag_value(secret, tag))
+        .collect();
+    if values.is_empty() {
+        return Err(TagError::Missing {
+            arn: secret.arn.clone().unwrap_or_default(),
+            tag: namespace_tags.join(" or "),
+        });
+    }
+    let mut namespaces: Vec<String> = Vec::new();
+    for namespace in values.into_iter().flat_map(split_namespaces) {
+        if !namespaces.contains(&namespace) {
+            namespaces.push(namespace);
+        }
+    }
+    if let Some(filter) = namespace_filter {
+        namespaces.retain(|namespace| filter.is_match(namespace));
+    }
+    Ok(namespaces)
+}
+
+// splits a list of namespaces delimited by any mix of commas, semicolons and whitespace
+pub fn split_namespaces(s: &str) -> Vec<String> {
+    s.split(|c: char| c == ',' || c == ';' || c.is_whitespace())
+        .map(str::trim)
+        .filter(|namespace| !namespace.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+// gets the value of the tag with key `filename_tag` from the AWS secret
+pub fn get_filename_from_aws_secret(
+    secret: &SecretListEntry,
+    filename_tag: &str,
+) -> Option<String> {
+    find_tag_value(secret, filename_tag).map(String::from)
+}
+
+// gets the Kubernetes secret type from the tag with key `secret_type_tag` of the AWS secret
+pub fn get_secret_type_from_aws_secret(
+    secret: &SecretListEntry,
+    secret_type_tag: &str,
+) -> Option<String> {
+    find_tag_value(secret, secret_type_tag)
+        .map(str::trim)
+        .filter(|secret_type| !secret_type.is_empty())
+        .map(String::from)
+}
+
+// gets the namespace labels from the comma separated `key=value` pairs of the tag with key
+// `namespace_label_tag` of the AWS secret, skipping the malformed pairs with a warning
+pub fn get_namespace_labels_from_aws_secret(
+    secret: &SecretListEntry,
+    namespace_label_tag: &str,
+) -> BTreeMap<String, String> {
+    let Some(value) = find_tag_value(secret, namespace_label_tag) else {
+        return BTreeMap::new();
+    };
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty())
+        .filter_map(|pair| match parse_key_val(pair) {
+            Ok((key, value)) => Some((key.trim().to_owned(), value.trim().to_owned())),
+            Err(e) => {
+                warn!(
+                    "Ignoring namespace label of {}: {}",
+                    secret.name.as_deref().unwrap_or_default(),
+                    e
+                );
+                None
+            }
+        })
+        .collect()
+}
+
+// gets the number of `--concurrency` slots the AWS secret takes from the tag with key
+// `parallelism_tag`, at least 1 and at most `concurrency`
+pub fn get_parallelism_from_aws_secret(
+    secret: &SecretListEntry,
+    parallelism_tag: &str,
+    concurrency: usize,
+) -> u32 {
+    let weight = find_tag_value(secret, parallelism_tag)
+        .and_then(|value| value.trim().parse::<u32>().ok())
+        .filter(|weight| *weight > 0)
+        .unwrap_or(1);
+    // a weight above the number of permits would never be acquired
+    weight.min(u32::try_from(concurrency).unwrap_or(u32::MAX).max(1))
+}
+
+// gets the distinct values of the tags with key `filename_tag` or one of `extra_filename_tags`
+// from the AWS secret, in that order
+pub fn get_filenames_from_aws_secret(
+    secret: &SecretListEntry,
+    filename_tag: &str,
+    extra_filename_tags: &[String],
+) -> Vec<String> {
+    let mut filenames: Vec<String> = Vec::new();
+    let tags = std::iter::once(filename_tag).chain(extra_filename_tags.iter().map(String::as_str));
+    for filename in tags.filter_map(|tag| get_filename_from_aws_secret(secret, tag)) {
+        if !filenames.contains(&filename) {
+            filenames.push(filename);
+        }
+    }
+    filenames
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::args::tests::args;
+
+    #[test]
+    fn regional_config_targets_the_partition_endpoint() {
+        let config = SdkConfig::builder()
+            .behavior_version(BehaviorVersion::latest())
+            .region(Region::new("eu-west-1"))
+            .build();
+
+        let china = regional_config(
+            &config,
+            Some(String::from("cn-north-1")),
+            Some(AwsPartition::AwsCn),
+        );
+        assert_eq!(china.region().unwrap().as_ref(), "cn-north-1");
+        assert_eq!(
+            china.endpoint_url(),
+            Some("https://secretsmanager.cn-north-1.amazonaws.com.cn")
+        );
+
+        let gov = regional_config(
+            &config,
+            Some(String::from("us-gov-east-1")),
+            Some(AwsPartition::AwsUsGov),
+        );
+        assert_eq!(
+            gov.endpoint_url(),
+            Some("https://secretsmanager.us-gov-east-1.amazonaws.com")
+        );
+
+        // without a partition the SDK resolves the endpoint itself
+        let default = regional_config(&config, Some(String::from("us-east-1")), None);
+        assert_eq!(default.endpoint_url(), None);
+    }
+
+    #[test]
+    fn credentials_are_read_from_secret_data() {
+        let data = |pairs: &[(&str, &str)]| -> BTreeMap<String, ByteString> {
+            pairs
+                .iter()
+                .map(|(key, value)| (String::from(*key), ByteString(value.as_bytes().to_vec())))
+                .collect()
+        };
+        let credentials = credentials_from_secret_data(&data(&[
+            ("AWS_ACCESS_KEY_ID", "AKID"),
+            ("AWS_SECRET_ACCESS_KEY", "SECRET\n"),
+        ]))
+        .unwrap();
+        assert_eq!(credentials.access_key_id(), "AKID");
+        assert_eq!(credentials.secret_access_key(), "SECRET");
+        assert_eq!(credentials.session_token(), None);
+
+        assert!(matches!(
+            credentials_from_secret_data(&data(&[("AWS_ACCESS_KEY_ID", "AKID")])),
+            Err(CredentialsError::MissingKey {
+                key: "AWS_SECRET_ACCESS_KEY"
+            })
+        ));
+    }
+
+    #[test]
+    fn secret_metadata_prefixes_each_key() {
+        let created = DateTime::parse_from_rfc3339("2024-11-20T10:30:00Z")
+            .unwrap()
+            .timestamp();
+        let secret = SecretListEntry::builder()
+            .arn("arn:aws:secretsmanager:eu-west-1:123456789012:secret:db-creds-AbCdEf")
+            .created_date(aws_sdk_secretsmanager::primitives::DateTime::from_secs(
+                created,
+            ))
+            .build();
+        assert_eq!(
+            secret_metadata(&secret, "_aws_"),
+            BTreeMap::from([
+                (
+                    String::from("_aws_arn"),
+                    String::from(
+                        "arn:aws:secretsmanager:eu-west-1:123456789012:secret:db-creds-AbCdEf"
+                    )
+                ),
+                (
+                    String::from("_aws_created_date"),
+                    String::from("2024-11-20T10:30:00Z")
+                ),
+            ])
+        );
+    }
+
+    #[test]
+    fn is_rotating_within_the_grace_period_only() {
+        let now = DateTime::parse_from_rfc3339("2024-11-20T10:30:00Z")
+            .unwrap()
+            .to_utc();
+        let rotated = |minutes_ago: i64, enabled: bool| {
+            SecretListEntry::builder()
+                .name("db-creds")
+                .rotation_enabled(enabled)
+                .last_rotated_date(aws_sdk_secretsmanager::primitives::DateTime::from_secs(
+                    (now - Duration::minutes(minutes_ago)).timestamp(),
+                ))
+                .build()
+        };
+        let grace = Duration::minutes(5);
+        assert!(is_rotating(&rotated(2, true), now, grace));
+        assert!(!is_rotating(&rotated(5, true), now, grace));
+        assert!(!is_rotating(&rotated(2, false), now, grace));
+        let never_rotated = SecretListEntry::builder().rotation_enabled(true).build();
+        assert!(!is_rotating(&never_rotated, now, grace));
+    }
+
+    #[test]
+    fn days_since_changed_counts_whole_days() {
+        let now = DateTime::parse_from_rfc3339("2024-11-20T10:30:00Z")
+            .unwrap()
+            .to_utc();
+        let changed = aws_sdk_secretsmanager::primitives::DateTime::from_secs(
+            (now - chrono::Duration::hours(24 * 90 + 5)).timestamp(),
+        );
+        let secret = SecretListEntry::builder()
+            .name("db-creds")
+            .last_changed_date(changed)
+            .build();
+        assert_eq!(days_since_changed(&secret, now), Some(90));
+
+        let never_changed = SecretListEntry::builder().name("db-creds").build();
+        assert_eq!(days_since_changed(&never_changed, now), None);
+    }
+
+    #[test]
+    fn strip_name_prefix_removes_whole_path_segments() {
+        assert_eq!(
+            strip_name_prefix("prod/myapp/db-creds", "prod/myapp"),
+            "db-creds"
+        );
+        assert_eq!(
+            strip_name_prefix("prod/myapp/db-creds", "prod/myapp/"),
+            "db-creds"
+        );
+        assert_eq!(
+            strip_name_prefix("prod/myapp/db/creds", "prod"),
+            "myapp-db-creds"
+        );
+        // a prefix that isn't a whole segment is kept
+        assert_eq!(
+            strip_name_prefix("prod/myapp-db", "prod/my"),
+            "prod-myapp-db"
+        );
+        assert_eq!(
+            strip_name_prefix("staging/db-creds", "prod"),
+            "staging-db-creds"
+        );
+    }
+
+    #[test]
+    fn validate_secret_tags_rejects_name_stripped_to_nothing() {
+        let secret = SecretListEntry::builder()
+            .name("prod/myapp")
+            .tags(tag("name", "prod/myapp"))
+            .tags(tag("ns", "prod"))
+            .build();
+        let result = validate_secret_tags(&secret, &args(&["--strip-prefix", "prod/myapp"]));
+        assert!(matches!(
+            result,
+            Err(TagError::InvalidName {
+                source: NameError::Empty,
+                ..
+            })
+        ));
+
+        let validated = validate_secret_tags(&secret, &args(&["--strip-prefix", "prod"])).unwrap();
+        assert_eq!(validated.secret_name, "myapp");
+    }
+
+    #[test]
+    fn split_namespaces_on_single_spaces() {
+        assert_eq!(split_namespaces("dev prod"), ["dev", "prod"]);
+    }
+
+    #[test]
+    fn split_namespaces_on_repeated_whitespace() {
+        assert_eq!(
+            split_namespaces("  dev   prod\tstaging "),
+            ["dev", "prod", "staging"]
+        );
+    }
+
+    #[test]
+    fn split_namespaces_on_commas_and_semicolons() {
+        assert_eq!(
+            split_namespaces("dev,prod;staging"),
+            ["dev", "prod", "staging"]
+        );
+    }
+
+    #[test]
+    fn split_namespaces_on_newlines() {
+        assert_eq!(
+            split_namespaces("dev\nprod\r\nstaging"),
+            ["dev", "prod", "staging"]
+        );
+    }
+
+    #[test]
+    fn split_namespaces_on_mixed_delimiters() {
+        assert_eq!(
+            split_namespaces("dev, prod ;\n staging,,;qa"),
+            ["dev", "prod", "staging", "qa"]
+        );
+    }
+
+    #[test]
+    fn split_namespaces_of_only_delimiters_is_empty() {
+        assert!(split_namespaces(" ,;\n").is_empty());
+    }
+
+    #[test]
+    fn arn_without_region_matches_replicas() {
+        assert_eq!(
+            arn_without_region("arn:aws:secretsmanager:us-east-1:123456789012:secret:db-AbCdEf"),
+            arn_without_region("arn:aws:secretsmanager:eu-west-1:123456789012:secret:db-AbCdEf")
+        );
+    }
+
+    #[test]
+    fn secret_name_from_arn_drops_the_random_suffix() {
+        assert_eq!(
+            secret_name_from_arn(
+                "arn:aws:secretsmanager:eu-west-1:123456789012:secret:prod/db-AbCdEf"
+            ),
+            Some("prod/db")
+        );
+        assert_eq!(secret_name_from_arn("prod/db"), None);
+    }
+
+    // the AWS tag `key=value`
+    pub(crate) fn tag(key: &str, value: &str) -> Tag {
+        Tag::builder().key(key).value(value).build()
+    }
+
+    // an AWS secret tagged with the namespaces `prod-eu, prod-us staging`
+    fn secret_with_namespaces() -> SecretListEntry {
+        SecretListEntry::builder()
+            .name("db-creds")
+            .tags(tag("ns-tag", "prod-eu, prod-us staging"))
+            .build()
+    }
+
+    fn ns_tag() -> Vec<String> {
+        vec![String::from("ns-tag")]
+    }
+
+    #[test]
+    fn get_namespaces_without_filter_keeps_all() {
+        let namespaces = get_namespaces_from_aws_secret(&secret_with_namespaces(), &ns_tag(), None);
+        assert_eq!(namespaces.unwrap(), ["prod-eu", "prod-us", "staging"]);
+    }
+
+    #[test]
+    fn get_namespaces_keeps_only_matching_filter() {
+        let filter = crate::args::parse_namespace_filter("prod-.*").unwrap();
+        let namespaces =
+            get_namespaces_from_aws_secret(&secret_with_namespaces(), &ns_tag(), Some(&filter));
+        assert_eq!(namespaces.unwrap(), ["prod-eu", "prod-us"]);
+    }
+
+    #[test]
+    fn get_namespaces_filter_matches_whole_name() {
+        let filter = crate::args::parse_namespace_filter("prod").unwrap();
+        let namespaces =
+            get_namespaces_from_aws_secret(&secret_with_namespaces(), &ns_tag(), Some(&filter));
+        assert!(namespaces.unwrap().is_empty());
+
+        let filter = crate::args::parse_namespace_filter("staging|prod-us").unwrap();
+        let namespaces =
+            get_namespaces_from_aws_secret(&secret_with_namespaces(), &ns_tag(), Some(&filter));
+        assert_eq!(namespaces.unwrap(), ["prod-us", "staging"]);
+    }
+
+    #[test]
+    fn get_namespaces_unions_every_namespace_tag() {
+        let secret = SecretListEntry::builder()
+            .name("db-creds")
+            .tags(tag("k8s/prod/namespace", "payments, billing"))
+            .tags(tag("k8s/staging/namespace", "billing, payments-staging"))
+            .build();
+        let tags = [
+            String::from("k8s/prod/namespace"),
+            String::from("k8s/staging/namespace"),
+            String::from("k8s/dev/namespace"),
+        ];
+        let namespaces = get_namespaces_from_aws_secret(&secret, &tags, None);
+        assert_eq!(
+            namespaces.unwrap(),
+            ["payments", "billing", "payments-staging"]
+        );
+
+        let missing = get_namespaces_from_aws_secret(&secret, &tags[2..], None);
+        assert!(matches!(missing, Err(TagError::Missing { .. })));
+    }
+
+    #[test]
+    fn build_list_filters_adds_included_tags() {
+        let args = args(&["--include-tag", "team=payments"]);
+        let filters: Vec<_> = build_list_filters(&args)
+            .iter()
+            .map(|filter| (filter.key.clone().unwrap(), filter.values().to_vec()))
+            .collect();
+        assert_eq!(
+            filters,
+            [
+                (FilterNameStringType::TagKey, vec![String::from("ns")]),
+                (FilterNameStringType::TagKey, vec![String::from("team")]),
+                (
+                    FilterNameStringType::TagValue,
+                    vec![String::from("payments")]
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn is_in_scope_requires_included_tag_pairs() {
+        let args = args(&["--include-tag", "team=payments"]);
+        let matching = SecretListEntry::builder()
+            .name("db-creds")
+            .tags(tag("team", "payments"))
+            .build();
+        // the key and value are both present, but on different tags
+        let mismatched = SecretListEntry::builder()
+            .name("db-creds")
+            .tags(tag("team", "search"))
+            .tags(tag("owner", "payments"))
+            .build();
+        assert!(is_in_scope(&matching, &args));
+        assert!(!is_in_scope(&mismatched, &args));
+    }
+
+    #[test]
+    fn is_in_scope_excludes_matching_names() {
+        let args = args(&["--exclude-pattern=-test$"]);
+        let named = |name: &str| SecretListEntry::builder().name(name).build();
+        assert!(is_in_scope(&named("db-creds"), &args));
+        assert!(!is_in_scope(&named("db-creds-test"), &args));
+    }
+
+    #[test]
+    fn is_in_scope_keeps_names_matching_the_filter() {
+        let args = args(&["--secret-filter-name", "prod/myapp/.*"]);
+        let named = |name: &str| SecretListEntry::builder().name(name).build();
+        assert!(is_in_scope(&named("prod/myapp/db-creds"), &args));
+        assert!(!is_in_scope(&named("prod/other/db-creds"), &args));
+    }
+
+    #[test]
+    fn is_in_scope_keeps_descriptions_containing_the_text() {
+        let args = args(&["--description-contains", "payments"]);
+        let described = |description: &str| {
+            SecretListEntry::builder()
+                .name("db-creds")
+                .description(description)
+                .build()
+        };
+        assert!(is_in_scope(
+            &described("Database for the payments API"),
+            &args
+        ));
+        assert!(!is_in_scope(
+            &described("Database for the orders API"),
+            &args
+        ));
+        assert!(!is_in_scope(
+            &SecretListEntry::builder().name("db-creds").build(),
+            &args
+        ));
+    }
+
+    #[test]
+    fn filter_secrets_by_labels_keeps_secrets_with_every_label() {
+        let secrets = vec![
+            SecretListEntry::builder()
+                .name("enabled")
+                .tags(tag("sync-enabled", "true"))
+                .tags(tag("team", "payments"))
+                .build(),
+            SecretListEntry::builder()
+                .name("disabled")
+                .tags(tag("sync-enabled", "false"))
+                .tags(tag("team", "payments"))
+                .build(),
+            SecretListEntry::builder()
+                .name("untagged")
+                .tags(tag("team", "payments"))
+                .build(),
+        ];
+        let names = |secrets: Vec<SecretListEntry>| -> Vec<_> {
+            secrets
+                .into_iter()
+                .filter_map(|secret| secret.name)
+                .collect()
+        };
+
+        assert_eq!(
+            names(filter_secrets_by_labels(secrets.clone(), &[])),
+            ["enabled", "disabled", "untagged"]
+        );
+        let labels = [
+            (String::from("sync-enabled"), String::from("true")),
+            (String::from("team"), String::from("payments")),
+        ];
+        assert_eq!(
+            names(filter_secrets_by_labels(secrets, &labels)),
+            ["enabled"]
+        );
+    }
+
+    #[test]
+    fn k8s_secret_name_adds_prefix_and_suffix() {
+        let name = |extra: &[&str]| k8s_secret_name("db-creds", &args(extra));
+        assert_eq!(name(&[]), "db-creds");
+        assert_eq!(name(&["--secret-name-prefix", "env-"]), "env-db-creds");
+        assert_eq!(name(&["--secret-name-suffix", "-v2"]), "db-creds-v2");
+        assert_eq!(
+            name(&[
+                "--secret-name-prefix",
+                "env-",
+                "--secret-name-suffix",
+                "-v2"
+            ]),
+            "env-db-creds-v2"
+        );
+    }
+
+    #[test]
+    fn get_filenames_reads_the_filename_and_extra_filename_tags() {
+        let secret = SecretListEntry::builder()
+            .tags(tag("file", "app.env"))
+            .tags(tag("file-worker", "worker.env"))
+            .tags(tag("file-copy", "app.env"))
+            .build();
+        let extra = [
+            String::from("file-worker"),
+            String::from("file-copy"),
+            String::from("file-missing"),
+        ];
+        assert_eq!(
+            get_filenames_from_aws_secret(&secret, "file", &extra),
+            ["app.env", "worker.env"]
+        );
+        assert_eq!(
+            get_filenames_from_aws_secret(&secret, "missing", &[]),
+            Vec::<String>::new()
+        );
+    }
+
+    #[test]
+    fn secret_version_prefers_the_version_id() {
+        let stage = args(&[]);
+        assert_eq!(
+            SecretVersion::from_args(&stage),
+            SecretVersion::Stage("AWSCURRENT")
+        );
+        assert_eq!(SecretVersion::from_args(&stage).remote_ref(), "AWSCURRENT");
+
+        let pinned = args(&["--version-id", "v1"]);
+        assert_eq!(SecretVersion::from_args(&pinned), SecretVersion::Id("v1"));
+        assert_eq!(SecretVersion::from_args(&pinned).remote_ref(), "uuid/v1");
+        assert_eq!(SecretVersion::from_args(&pinned).to_string(), "version v1");
+    }
+
+    #[test]
+    fn namespace_labels_are_read_from_key_value_pairs() {
+        let secret = SecretListEntry::builder()
+            .tags(tag("labels", "team=payments, env=prod,,broken"))
+            .build();
+        assert_eq!(
+            get_namespace_labels_from_aws_secret(&secret, "labels"),
+            BTreeMap::from([
+                (String::from("env"), String::from("prod")),
+                (String::from("team"), String::from("payments")),
+            ])
+        );
+        assert!(get_namespace_labels_from_aws_secret(&secret, "missing").is_empty());
+    }
+
+    #[test]
+    fn parallelism_defaults_to_one_slot() {
+        let secret = SecretListEntry::builder()
+            .tags(tag("large", "4"))
+            .tags(tag("huge", "50"))
+            .tags(tag("zero", "0"))
+            .tags(tag("invalid", "many"))
+            .build();
+        assert_eq!(get_parallelism_from_aws_secret(&secret, "large", 10), 4);
+        assert_eq!(get_parallelism_from_aws_secret(&secret, "huge", 10), 10);
+        assert_eq!(get_parallelism_from_aws_secret(&secret, "zero", 10), 1);
+        assert_eq!(get_parallelism_from_aws_secret(&secret, "invalid", 10), 1);
+        assert_eq!(get_parallelism_from_aws_secret(&secret, "missing", 10), 1);
+    }
+
+    #[test]
+    fn secret_type_tag_overrides_the_secret_type() {
+        let secret = SecretListEntry::builder()
+            .tags(tag("ns", "prod"))
+            .tags(tag("name", "tls-cert"))
+            .tags(tag("k8s/secret-type", "kubernetes.io/tls"))
+            .tags(tag("blank", " "))
+            .build();
+        assert_eq!(
+            get_secret_type_from_aws_secret(&secret, "k8s/secret-type").as_deref(),
+            Some("kubernetes.io/tls")
+        );
+        assert_eq!(get_secret_type_from_aws_secret(&secret, "blank"), None);
+        assert_eq!(get_secret_type_from_aws_secret(&secret, "missing"), None);
+
+        let validated =
+            validate_secret_tags(&secret, &args(&["--secret-type-tag", "k8s/secret-type"]))
+                .unwrap();
+        assert_eq!(validated.secret_type.as_deref(), Some("kubernetes.io/tls"));
+        assert_eq!(
+            validate_secret_tags(&secret, &args(&[]))
+                .unwrap()
+                .secret_type,
+            None
+        );
+    }
+
+    #[test]
+    fn env_file_format_is_overridden_by_the_filename_tag() {
+        let untagged = SecretListEntry::builder()
+            .tags(tag("ns", "prod"))
+            .tags(tag("name", "db-creds"))
+            .build();
+        let tagged = SecretListEntry::builder()
+            .tags(tag("ns", "prod"))
+            .tags(tag("name", "db-creds"))
+            .tags(tag("file", "app.env"))
+            .build();
+        let keys = args(&[]);
+        let env_file = args(&["--format", "env-file", "--env-file-name", "db.env"]);
+
+        let filenames = |secret, args| validate_secret_tags(secret, args).unwrap().filenames;
+        assert_eq!(filenames(&untagged, &keys), Vec::<String>::new());
+        assert_eq!(filenames(&untagged, &env_file), ["db.env"]);
+        assert_eq!(filenames(&tagged, &keys), ["app.env"]);
+        assert_eq!(filenames(&tagged, &env_file), ["app.env"]);
+    }
+
+    #[test]
+    fn resolve_template_replaces_placeholders_with_tag_values() {
+        let tags = [tag("prefix", "payments"), tag("env", "prod")];
+        assert_eq!(
+            resolve_template("{{prefix}}-{{ env }}-creds", &tags),
+            Ok(String::from("payments-prod-creds"))
+        );
+        assert_eq!(
+            resolve_template("db-creds", &tags),
+            Ok(String::from("db-creds"))
+        );
+        assert_eq!(
+            resolve_template("{{prefix}}-{{team}}", &tags),
+            Err(TemplateError::MissingTag(String::from("team")))
+        );
+        assert_eq!(
+            resolve_template("{{prefix", &tags),
+            Err(TemplateError::Unclosed(String::from("{{prefix")))
+        );
+    }
+
+    #[test]
+    fn get_name_resolves_templated_name_tags() {
+        let secret = SecretListEntry::builder()
+            .tags(tag("prefix", "payments"))
+            .tags(tag("env", "prod"))
+            .build();
+        assert_eq!(
+            get_name_from_aws_secret(&secret, "{{prefix}}-{{env}}-creds").unwrap(),
+            "payments-prod-creds"
+        );
+        assert!(matches!(
+            get_name_from_aws_secret(&secret, "{{team}}-creds"),
+            Err(TagError::Template { .. })
+        ));
+    }
+
+    #[test]
+    fn validate_secret_tags_rejects_invalid_names() {
+        let secret = SecretListEntry::builder()
+            .arn("arn:aws:secretsmanager:eu-west-1:123456789012:secret:db-creds-AbCdEf")
+            .tags(tag("ns", "prod"))
+            .tags(tag("name", "DB_Creds"))
+            .build();
+
+        let error = validate_secret_tags(&secret, &args(&[])).unwrap_err();
+        assert!(matches!(error, TagError::InvalidName { .. }));
+        let message = error.to_string();
+        assert!(message.contains("db-creds-AbCdEf"));
+        assert!(message.contains("\"DB_Creds\""));
+    }
+}
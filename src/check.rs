@@ -0,0 +1,312 @@
+// Checking the tags and values of the listed secrets without touching Kubernetes, with `--check`.
+//
+// Every secret is checked in full, rather than stopping at the first problem, so a single run
+// lists everything that needs fixing before the secrets can be synced.
+
+use std::fmt;
+
+use aws_sdk_secretsmanager::types::SecretListEntry;
+
+use crate::args::{Args, SecretFormat};
+use crate::aws::{
+    get_name_from_aws_secret, get_namespaces_from_aws_secret, k8s_secret_name, SecretVersion,
+};
+use crate::data::secret_parser;
+use crate::k8s::{validate_k8s_name, validate_namespace_name};
+use crate::retry::retry_with_backoff;
+use crate::{retry_policy, SecretSource};
+
+/// The outcome of a single check of a secret
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum CheckStatus {
+    Pass,
+    /// The secret can be synced, but perhaps not as intended
+    Warn,
+    /// The secret can't be synced
+    Fail,
+}
+
+impl fmt::Display for CheckStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(match self {
+            CheckStatus::Pass => "PASS",
+            CheckStatus::Warn => "WARN",
+            CheckStatus::Fail => "FAIL",
+        })
+    }
+}
+
+/// The outcome of checking a value, with the reason for anything but a pass
+#[derive(Debug, PartialEq)]
+pub struct CheckResult {
+    pub status: CheckStatus,
+    pub message: Option<String>,
+}
+
+impl CheckResult {
+    fn pass() -> Self {
+        CheckResult {
+            status: CheckStatus::Pass,
+            message: None,
+        }
+    }
+
+    fn warn(message: String) -> Self {
+        CheckResult {
+            status: CheckStatus::Warn,
+            message: Some(message),
+        }
+    }
+
+    fn fail(message: String) -> Self {
+        CheckResult {
+            status: CheckStatus::Fail,
+            message: Some(message),
+        }
+    }
+}
+
+/// The checks of a single listed secret
+#[derive(Debug)]
+pub struct SecretCheck {
+    pub aws_secret_name: String,
+    /// The secret name tag, and the Kubernetes secret name made from it
+    pub name: CheckResult,
+    /// The namespace tag, and the namespace names in it
+    pub namespaces: CheckResult,
+    /// The secret value, and whether it can be parsed
+    pub value: CheckResult,
+}
+
+impl SecretCheck {
+    /// The worst status of the checks
+    pub fn status(&self) -> CheckStatus {
+        self.name
+            .status
+            .max(self.namespaces.status)
+            .max(self.value.status)
+    }
+}
+
+// checks the tags and value of every secret in `secrets`
+pub async fn check_secrets(
+    args: &Args,
+    secrets: &[(SecretSource, SecretListEntry)],
+) -> Vec<SecretCheck> {
+    let mut checks = Vec::new();
+    for (source, secret) in secrets {
+        checks.push(SecretCheck {
+            aws_secret_name: secret.name.clone().unwrap_or_default(),
+            name: check_name(secret, args),
+            namespaces: check_namespaces(secret, args),
+            value: check_value(secret, args, source).await,
+        });
+    }
+    checks
+}
+
+// checks the secret name tag is present and makes a valid Kubernetes secret name
+pub fn check_name(secret: &SecretListEntry, args: &Args) -> CheckResult {
+    let name = match get_name_from_aws_secret(secret, &args.secret_name_tag) {
+        Ok(name) => k8s_secret_name(&name, args),
+        Err(e) => return CheckResult::fail(e.to_string()),
+    };
+    match validate_k8s_name(&name) {
+        Ok(()) => CheckResult::pass(),
+        Err(e) => CheckResult::fail(format!("invalid Kubernetes secret name: {}", e)),
+    }
+}
+
+// checks a namespace tag is present and every namespace in it is a valid namespace name
+pub fn check_namespaces(secret: &SecretListEntry, args: &Args) -> CheckResult {
+    let namespaces = match get_namespaces_from_aws_secret(secret, &args.namespace_tag, None) {
+        Ok(namespaces) => namespaces,
+        Err(e) => return CheckResult::fail(e.to_string()),
+    };
+    let invalid: Vec<_> = namespaces
+        .iter()
+        .filter_map(|namespace| validate_namespace_name(namespace).err())
+        .map(|e| e.to_string())
+        .collect();
+    if !invalid.is_empty() {
+        return CheckResult::fail(format!("invalid namespace name: {}", invalid.join("; ")));
+    }
+    let selected = namespaces
+        .iter()
+        .filter(|namespace| {
+            args.namespace_filter
+                .as_ref()
+                .is_none_or(|filter| filter.is_match(namespace))
+        })
+        .count();
+    match selected {
+        0 if namespaces.is_empty() => CheckResult::warn(String::from("the namespace tag is empty")),
+        0 => CheckResult::warn(String::from("no namespace matches --namespace-filter")),
+        _ => CheckResult::pass(),
+    }
+}
+
+// reads the value of the secret and checks it can be parsed
+async fn check_value(secret: &SecretListEntry, args: &Args, source: &SecretSource) -> CheckResult {
+    let aws_secret_name = secret.name.as_deref().unwrap_or_default();
+    let retry_policy = retry_policy(args, args.on_aws_error);
+    match source {
+        SecretSource::Aws(client) => {
+            let value = retry_with_backoff(&retry_policy, || {
+                SecretVersion::from_args(args)
+                    .apply(client.get_secret_value().secret_id(aws_secret_name))
+                    .send()
+            })
+            .await;
+            match value {
+                Ok(value) => match (value.secret_string, value.secret_binary) {
+                    (Some(secret_string), _) => check_secret_string(&secret_string, args),
+                    (None, Some(_)) => CheckResult::warn(format!(
+                        "binary secret, stored under the key {}",
+                        args.binary_key
+                    )),
+                    (None, None) => CheckResult::fail(String::from("AWS secret has no value")),
+                },
+                Err(e) => CheckResult::fail(format!(
+                    "failed to read secret value: {}",
+                    aws_sdk_secretsmanager::Error::from(e)
+                )),
+            }
+        }
+        SecretSource::Vault(vault) => {
+            match retry_with_backoff(&retry_policy, || vault.read_secret(aws_secret_name)).await {
+                Ok(_) => CheckResult::pass(),
+                Err(e) => CheckResult::fail(format!("failed to read secret value: {}", e)),
+            }
+        }
+    }
+}
+
+// checks the secret string parses with `--parser`; a string that isn't a JSON object is
+// synced as plain text or YAML, which is worth a warning
+pub fn check_secret_string(secret_string: &str, args: &Args) -> CheckResult {
+    if let Err(e) = secret_parser(args.parser, &args.plain_text_key).parse(secret_string) {
+        return CheckResult::fail(format!("failed to parse secret string: {}", e));
+    }
+    let is_object = matches!(
+        serde_json::from_str(secret_string),
+        Ok(serde_json::Value::Object(_))
+    );
+    if args.parser == SecretFormat::Json && !is_object {
+        return CheckResult::warn(String::from("secret string is not a JSON object"));
+    }
+    CheckResult::pass()
+}
+
+// formats the checks as a table of statuses, followed by the reason for each warning and failure
+pub fn format_table(checks: &[SecretCheck]) -> String {
+    let width = checks
+        .iter()
+        .map(|check| check.aws_secret_name.len())
+        .chain(std::iter::once("SECRET".len()))
+        .max()
+        .unwrap_or_default();
+    let mut table = format!("{:<width$}  NAME  NAMESPACES  VALUE\n", "SECRET");
+    for check in checks {
+        table.push_str(&format!(
+            "{:<width$}  {:<4}  {:<10}  {}\n",
+            check.aws_secret_name, check.name.status, check.namespaces.status, check.value.status
+        ));
+    }
+    for check in checks {
+        for result in [&check.name, &check.namespaces, &check.value] {
+            if let Some(message) = &result.message {
+                table.push_str(&format!(
+                    "{} {}: {}\n",
+                    result.status, check.aws_secret_name, message
+                ));
+            }
+        }
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::args::tests::args;
+    use crate::aws::tests::tag;
+
+    fn secret(tags: &[(&str, &str)]) -> SecretListEntry {
+        tags.iter()
+            .fold(
+                SecretListEntry::builder().name("db-creds"),
+                |secret, (key, value)| secret.tags(tag(key, value)),
+            )
+            .build()
+    }
+
+    #[test]
+    fn check_name_fails_on_missing_or_invalid_names() {
+        let args = args(&[]);
+        assert_eq!(
+            check_name(&secret(&[("name", "db-creds")]), &args),
+            CheckResult::pass()
+        );
+        assert_eq!(
+            check_name(&secret(&[("name", "DB_creds")]), &args).status,
+            CheckStatus::Fail
+        );
+        assert_eq!(check_name(&secret(&[]), &args).status, CheckStatus::Fail);
+    }
+
+    #[test]
+    fn check_namespaces_fails_on_invalid_namespaces() {
+        let args = args(&["--namespace-filter", "prod-.*"]);
+        assert_eq!(
+            check_namespaces(&secret(&[("ns", "prod-eu, staging")]), &args),
+            CheckResult::pass()
+        );
+        assert_eq!(
+            check_namespaces(&secret(&[("ns", "staging")]), &args).status,
+            CheckStatus::Warn
+        );
+        assert_eq!(
+            check_namespaces(&secret(&[("ns", "prod.eu")]), &args).status,
+            CheckStatus::Fail
+        );
+        assert_eq!(
+            check_namespaces(&secret(&[]), &args).status,
+            CheckStatus::Fail
+        );
+    }
+
+    #[test]
+    fn check_secret_string_warns_on_non_json() {
+        let args = args(&[]);
+        assert_eq!(
+            check_secret_string(r#"{"user": "admin"}"#, &args),
+            CheckResult::pass()
+        );
+        assert_eq!(
+            check_secret_string("hunter2", &args).status,
+            CheckStatus::Warn
+        );
+        assert_eq!(
+            check_secret_string(r#"{"port": 5432}"#, &args).status,
+            CheckStatus::Fail
+        );
+    }
+
+    #[test]
+    fn format_table_lists_statuses_and_reasons() {
+        let checks = [SecretCheck {
+            aws_secret_name: String::from("db-creds"),
+            name: CheckResult::pass(),
+            namespaces: CheckResult::pass(),
+            value: CheckResult::warn(String::from("secret string is not a JSON object")),
+        }];
+        assert_eq!(checks[0].status(), CheckStatus::Warn);
+        assert_eq!(
+            format_table(&checks),
+            "SECRET    NAME  NAMESPACES  VALUE\n\
+             db-creds  PASS  PASS        WARN\n\
+             WARN db-creds: secret string is not a JSON object\n"
+        );
+    }
+}
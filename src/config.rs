@@ -0,0 +1,181 @@
+// TOML configuration file, an alternative to passing every option on the command line.
+//
+// Each field mirrors the command line argument of the same name. Values from the file are
+// used as the defaults of those arguments, so flags given on the command line still win.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// Errors raised while loading the configuration file
+#[derive(thiserror::Error, Debug)]
+pub enum ConfigError {
+    /// The configuration file could not be read
+    #[error("failed to read config file {path}: {source}")]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    /// The configuration file is not valid TOML for `Config`
+    #[error("failed to parse config file {path}: {source}")]
+    Parse {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+}
+
+/// The contents of the `--config` file, one optional field per command line argument
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub source: Option<String>,
+    pub vault_mount: Option<String>,
+    pub vault_path_prefix: Option<String>,
+    pub vault_auth_method: Option<String>,
+    pub vault_role: Option<String>,
+    pub vault_auth_mount: Option<String>,
+    pub namespace_tag: Option<Vec<String>>,
+    pub secret_name_tag: Option<String>,
+    pub strip_prefix: Option<String>,
+    pub secret_name_prefix: Option<String>,
+    pub secret_name_suffix: Option<String>,
+    pub filename_tag: Option<String>,
+    pub compress_file_secret: Option<bool>,
+    pub extra_filename_tag: Option<Vec<String>>,
+    pub format: Option<String>,
+    pub env_file_name: Option<String>,
+    pub namespace_filter: Option<String>,
+    pub dry_run: Option<bool>,
+    pub plan: Option<bool>,
+    #[cfg(feature = "debug-dump")]
+    pub debug_dump_dir: Option<PathBuf>,
+    pub version_stage: Option<String>,
+    pub version_id: Option<String>,
+    pub plain_text_key: Option<String>,
+    pub parser: Option<String>,
+    pub split_yaml_documents: Option<bool>,
+    pub values_already_base64: Option<bool>,
+    pub binary_key: Option<String>,
+    pub parse_pkcs12: Option<bool>,
+    pub pkcs12_password_key: Option<String>,
+    pub openssl_path: Option<String>,
+    pub max_secret_size_bytes: Option<usize>,
+    pub rotation_warn_days: Option<i64>,
+    pub skip_rotating_secrets: Option<bool>,
+    pub rotation_grace_minutes: Option<u32>,
+    pub concurrency: Option<usize>,
+    pub parallelism_tag: Option<String>,
+    pub k8s_concurrency: Option<usize>,
+    pub log_format: Option<String>,
+    pub secret_prefix: Option<String>,
+    pub include_tags: Option<BTreeMap<String, String>>,
+    pub required_labels: Option<BTreeMap<String, String>>,
+    pub secret_filter_name: Option<String>,
+    pub exclude_pattern: Option<String>,
+    pub description_contains: Option<String>,
+    pub aws_region: Option<String>,
+    pub aws_partition: Option<String>,
+    pub ssm_parameter_prefix: Option<String>,
+    pub ssm_namespace: Option<String>,
+    pub ssm_configmap_name: Option<String>,
+    pub extra_regions: Option<Vec<String>>,
+    pub assume_role_arn: Option<String>,
+    pub assume_role_external_id: Option<String>,
+    pub aws_credentials_secret: Option<String>,
+    pub preflight_check: Option<bool>,
+    pub check: Option<bool>,
+    pub max_retries: Option<u32>,
+    pub retry_base_ms: Option<u64>,
+    pub on_aws_error: Option<String>,
+    pub on_k8s_error: Option<String>,
+    pub max_errors_before_abort: Option<usize>,
+    pub field_manager: Option<String>,
+    pub field_validation: Option<String>,
+    pub labels: Option<BTreeMap<String, String>>,
+    pub annotations: Option<BTreeMap<String, String>>,
+    pub include_keys: Option<Vec<String>>,
+    pub exclude_keys: Option<Vec<String>>,
+    pub key_map: Option<BTreeMap<String, String>>,
+    pub transform_script: Option<PathBuf>,
+    pub transform_command: Option<String>,
+    pub transform_timeout_ms: Option<u64>,
+    pub output_mode: Option<String>,
+    pub output: Option<String>,
+    pub secret_store_name: Option<String>,
+    pub secret_store_kind: Option<String>,
+    pub sealed_secrets_controller: Option<String>,
+    pub kubeseal_path: Option<String>,
+    pub secret_type: Option<String>,
+    pub secret_type_tag: Option<String>,
+    pub inject_metadata: Option<bool>,
+    pub metadata_key_prefix: Option<String>,
+    pub force_recreate: Option<bool>,
+    pub immutable: Option<bool>,
+    pub immutable_recreate: Option<bool>,
+    pub namespace_create: Option<bool>,
+    pub namespace_label_tag: Option<String>,
+    pub verify: Option<bool>,
+    pub tag_on_success: Option<bool>,
+    pub kubeconfig: Option<PathBuf>,
+    pub kube_context: Option<Vec<String>>,
+    pub delete_orphans: Option<bool>,
+    pub orphan_dry_run: Option<bool>,
+    pub leader_lock: Option<bool>,
+    pub lock_fail: Option<bool>,
+    pub lock_ttl_seconds: Option<u32>,
+    pub metrics_file: Option<PathBuf>,
+    pub report_file: Option<PathBuf>,
+    pub audit_log: Option<PathBuf>,
+    pub audit_syslog: Option<bool>,
+    pub watch: Option<bool>,
+    pub watch_interval_seconds: Option<u64>,
+    pub health_check_port: Option<u16>,
+}
+
+impl Config {
+    /// Reads and parses the configuration file at `path`
+    pub fn load(path: &Path) -> Result<Config, ConfigError> {
+        let contents = std::fs::read_to_string(path).map_err(|source| ConfigError::Read {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        toml::from_str(&contents).map_err(|source| ConfigError::Parse {
+            path: path.to_path_buf(),
+            source,
+        })
+    }
+
+    /// The values that are set, as command line argument ids and their string values
+    ///
+    /// Tables such as `labels` become `key=value` strings, as they are on the command line.
+    pub fn arg_defaults(&self) -> Vec<(String, Vec<String>)> {
+        let toml::Value::Table(table) = toml::Value::try_from(self).unwrap() else {
+            unreachable!("Config serialises to a table");
+        };
+        table
+            .into_iter()
+            .map(|(id, value)| {
+                let values = match value {
+                    toml::Value::Array(items) => items.into_iter().map(toml_to_arg).collect(),
+                    toml::Value::Table(pairs) => pairs
+                        .into_iter()
+                        .map(|(key, value)| format!("{}={}", key, toml_to_arg(value)))
+                        .collect(),
+                    value => vec![toml_to_arg(value)],
+                };
+                (id, values)
+            })
+            .collect()
+    }
+}
+
+// formats a scalar TOML value the way it would be written on the command line
+fn toml_to_arg(value: toml::Value) -> String {
+    match value {
+        toml::Value::String(s) => s,
+        value => value.to_string(),
+    }
+}
@@ -0,0 +1,196 @@
+// Reconciling `AwsSecretSync` resources with the `controller` subcommand, as an operator rather
+// than a CronJob.
+//
+// The spec of each resource has the fields of the `--config` file. The AWS secrets it selects
+// are synced whenever it changes, and again every watch interval. The AWS and Kubernetes
+// clients are those of the controller, whatever the spec says about them.
+
+use std::process::ExitCode;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+
+use clap::ArgMatches;
+use futures::StreamExt;
+use kube::runtime::controller::{Action, Controller};
+use kube::runtime::watcher;
+use kube::{Api, CustomResource, CustomResourceExt, ResourceExt};
+use schemars::gen::SchemaGenerator;
+use schemars::schema::Schema;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error, info, warn};
+
+use crate::args::{spec_args, DEFAULT_WATCH_INTERVAL};
+use crate::audit::AuditLog;
+use crate::config::Config;
+use crate::ssm::SsmClient;
+use crate::{sync_once, SecretSource, SyncError};
+
+/// The name of the subcommand printing the CustomResourceDefinition
+pub const GENERATE_CRD: &str = "generate-crd";
+
+/// The spec of an `AwsSecretSync` resource, with the fields of the `--config` file
+#[derive(CustomResource, Serialize, Deserialize, JsonSchema, Clone, Debug, Default, PartialEq)]
+#[kube(
+    group = "aws-secrets-sync.fhm.io",
+    version = "v1alpha1",
+    kind = "AwsSecretSync"
+)]
+pub struct AwsSecretSyncSpec {
+    #[serde(flatten)]
+    #[schemars(schema_with = "config_schema")]
+    pub config: Config,
+}
+
+// the schema of `Config` without `additionalProperties: false`, which Kubernetes rejects
+// alongside `properties`; the API server prunes the unknown fields instead
+fn config_schema(gen: &mut SchemaGenerator) -> Schema {
+    let mut schema = gen.subschema_for::<Config>().into_object();
+    schema.object().additional_properties = None;
+    Schema::Object(schema)
+}
+
+// the CustomResourceDefinition of `AwsSecretSync` as a YAML document
+pub fn crd_yaml() -> Result<String, serde_yaml::Error> {
+    serde_yaml::to_string(&AwsSecretSync::crd())
+}
+
+/// What the reconciliation of every resource shares
+pub struct Context {
+    pub sources: Vec<SecretSource>,
+    pub ssm: Option<SsmClient>,
+    pub kube_clients: Vec<kube::Client>,
+    pub audit: AuditLog,
+    /// The arguments of the controller, used for the fields missing from the specs
+    pub fallback: ArgMatches,
+}
+
+// reconciles the `AwsSecretSync` resources of the first cluster until SIGINT or SIGTERM; the
+// reconciliations in progress are finished first
+pub async fn run_controller(context: Context) -> Result<ExitCode, SyncError> {
+    let api: Api<AwsSecretSync> = Api::all(context.kube_clients[0].clone());
+    info!("Watching AwsSecretSync resources");
+    Controller::new(api, watcher::Config::default())
+        .shutdown_on_signal()
+        .run(reconcile, error_policy, Arc::new(context))
+        .for_each(|result| async move {
+            match result {
+                Ok((resource, _)) => debug!("Reconciled AwsSecretSync {}", resource.name),
+                Err(e) => warn!("Reconcile failed: {}", e),
+            }
+        })
+        .await;
+    info!("Received shutdown signal, exiting");
+    Ok(ExitCode::SUCCESS)
+}
+
+// syncs the AWS secrets selected by the spec of `resource`, then again after its watch interval
+async fn reconcile(
+    resource: Arc<AwsSecretSync>,
+    context: Arc<Context>,
+) -> Result<Action, SyncError> {
+    let name = resource.name_any();
+    let args =
+        spec_args(&context.fallback, &resource.spec.config).map_err(|e| SyncError::Spec {
+            name: name.clone(),
+            message: e.to_string().trim().to_string(),
+        })?;
+    let args = Arc::new(args);
+    info!("Reconciling AwsSecretSync {}", name);
+    // the controller handles the shutdown signal between reconciliations
+    let never = Arc::new(AtomicBool::new(false));
+    let summary = sync_once(
+        &args,
+        &context.sources,
+        context.ssm.as_ref(),
+        &context.kube_clients,
+        &never,
+        &context.audit,
+    )
+    .await?;
+    if summary.had_errors() {
+        warn!("AwsSecretSync {} synced with errors", name);
+    }
+    Ok(Action::requeue(
+        args.watch_interval().unwrap_or(DEFAULT_WATCH_INTERVAL),
+    ))
+}
+
+// retries a failed reconciliation after the default watch interval
+fn error_policy(resource: Arc<AwsSecretSync>, error: &SyncError, _: Arc<Context>) -> Action {
+    error!(
+        "Failed to reconcile AwsSecretSync {}: {}",
+        resource.name_any(),
+        error
+    );
+    Action::requeue(DEFAULT_WATCH_INTERVAL)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    use crate::args::tests::env_guard;
+    use crate::args::Args;
+
+    #[test]
+    fn spec_fields_fall_back_to_the_controller_arguments() {
+        let _env = env_guard();
+        let fallback = Args::command()
+            .try_get_matches_from([
+                "k8s_aws_secrets_sync",
+                "-n",
+                "ns",
+                "-s",
+                "name",
+                "-f",
+                "file",
+                "--secret-type",
+                "kubernetes.io/tls",
+                "controller",
+            ])
+            .unwrap();
+        let resource: AwsSecretSync = serde_yaml::from_str(
+            r#"
+apiVersion: aws-secrets-sync.fhm.io/v1alpha1
+kind: AwsSecretSync
+metadata:
+  name: prod
+spec:
+  secret_name_tag: k8s/name
+  secret_prefix: prod/
+  watch_interval_seconds: 30
+"#,
+        )
+        .unwrap();
+
+        let args = spec_args(&fallback, &resource.spec.config).unwrap();
+        assert_eq!(args.secret_name_tag, "k8s/name");
+        assert_eq!(args.namespace_tag, ["ns"]);
+        assert_eq!(args.secret_type, "kubernetes.io/tls");
+        assert_eq!(args.secret_prefix.as_deref(), Some("prod/"));
+        assert_eq!(
+            args.watch_interval(),
+            Some(std::time::Duration::from_secs(30))
+        );
+        assert_eq!(args.command, None);
+    }
+
+    #[test]
+    fn crd_schema_has_the_config_fields() {
+        let crd = serde_json::to_value(AwsSecretSync::crd()).unwrap();
+        assert_eq!(crd["spec"]["names"]["kind"], "AwsSecretSync");
+        assert_eq!(crd["spec"]["scope"], "Cluster");
+        let spec = &crd["spec"]["versions"][0]["schema"]["openAPIV3Schema"]["properties"]["spec"];
+        assert_eq!(spec["properties"]["secret_name_tag"]["type"], "string");
+        assert!(spec.get("additionalProperties").is_none());
+
+        let yaml: serde_yaml::Value = serde_yaml::from_str(&crd_yaml().unwrap()).unwrap();
+        assert_eq!(yaml["kind"], "CustomResourceDefinition");
+        assert_eq!(
+            yaml["metadata"]["name"],
+            "awssecretsyncs.aws-secrets-sync.fhm.io"
+        );
+    }
+}
@@ -0,0 +1,728 @@
+// Parsing AWS secret values into the base64 encoded data of a Kubernetes secret.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::ops::Index;
+
+use aws_sdk_secretsmanager::primitives::Blob;
+use base64::engine::general_purpose;
+use base64::Engine;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::Deserialize;
+
+use crate::args::SecretFormat;
+
+/// Errors raised while parsing the secret string of an AWS secret
+#[derive(thiserror::Error, Debug)]
+pub enum ParseError {
+    /// The secret is a JSON object or YAML mapping, but one of its values is not a string
+    #[error("value of key `{key}` is not a string")]
+    NonStringValue { key: String },
+
+    /// The secret is a YAML mapping, but one of its keys is not a string
+    #[error("YAML mapping has a key that is not a string")]
+    NonStringKey,
+
+    /// The secret is YAML, but not a mapping of keys to values
+    #[error("YAML secret is a sequence, expected a mapping of keys to values")]
+    NotAMapping,
+
+    /// A value given as already base64 encoded is not valid base64
+    #[error("value of key `{key}` is not valid base64")]
+    NotBase64 { key: String },
+}
+
+/// The key/value pairs of a secret: the plain values once read from the secret store, and
+/// the base64 encoded values once converted into the data of a Kubernetes secret
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SecretData(pub HashMap<String, String>);
+
+impl SecretData {
+    /// The keys of the secret, in no particular order
+    pub fn keys(&self) -> impl Iterator<Item = &str> {
+        self.0.keys().map(String::as_str)
+    }
+
+    /// The pairs of the secret, in no particular order
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+
+    /// The size in bytes of the keys and values
+    pub fn byte_size(&self) -> usize {
+        self.iter()
+            .map(|(key, value)| key.len() + value.len())
+            .sum()
+    }
+
+    /// The number of keys
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Whether the secret has no keys
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl From<HashMap<String, String>> for SecretData {
+    fn from(map: HashMap<String, String>) -> Self {
+        SecretData(map)
+    }
+}
+
+impl<const N: usize> From<[(String, String); N]> for SecretData {
+    fn from(pairs: [(String, String); N]) -> Self {
+        SecretData(HashMap::from(pairs))
+    }
+}
+
+impl FromIterator<(String, String)> for SecretData {
+    fn from_iter<I: IntoIterator<Item = (String, String)>>(pairs: I) -> Self {
+        SecretData(pairs.into_iter().collect())
+    }
+}
+
+impl IntoIterator for SecretData {
+    type Item = (String, String);
+    type IntoIter = std::collections::hash_map::IntoIter<String, String>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.into_iter()
+    }
+}
+
+impl Index<&str> for SecretData {
+    type Output = String;
+
+    fn index(&self, key: &str) -> &String {
+        &self.0[key]
+    }
+}
+
+impl From<SecretData> for serde_json::Value {
+    fn from(data: SecretData) -> Self {
+        data.into_iter()
+            .map(|(key, value)| (key, serde_json::Value::String(value)))
+            .collect::<serde_json::Map<_, _>>()
+            .into()
+    }
+}
+
+// parses the secret string of an AWS secret into key/value pairs
+// a JSON object or YAML mapping is used as-is, anything else is stored as plain text
+// under `plain_text_key`
+pub fn parse_secret_string(
+    secret_string: &str,
+    plain_text_key: &str,
+) -> Result<SecretData, ParseError> {
+    if let Ok(serde_json::Value::Object(object)) = serde_json::from_str(secret_string) {
+        return object
+            .into_iter()
+            .map(|(key, value)| match value {
+                serde_json::Value::String(value) => Ok((key, value)),
+                _ => Err(ParseError::NonStringValue { key }),
+            })
+            .collect();
+    }
+    match serde_yaml::from_str(secret_string) {
+        Ok(serde_yaml::Value::Mapping(mapping)) => mapping
+            .into_iter()
+            .map(|(key, value)| match (key, value) {
+                (serde_yaml::Value::String(key), serde_yaml::Value::String(value)) => {
+                    Ok((key, value))
+                }
+                (serde_yaml::Value::String(key), _) => Err(ParseError::NonStringValue { key }),
+                _ => Err(ParseError::NonStringKey),
+            })
+            .collect(),
+        Ok(serde_yaml::Value::Sequence(_)) => Err(ParseError::NotAMapping),
+        _ => Ok(SecretData::from([(
+            String::from(plain_text_key),
+            String::from(secret_string),
+        )])),
+    }
+}
+
+// splits a YAML secret string of several `---` separated documents into one single-document
+// YAML string per document, under the keys `doc0`, `doc1`, ...; `None` when the string isn't
+// YAML or is a single document
+pub fn split_yaml_documents(secret_string: &str) -> Option<SecretData> {
+    let documents = serde_yaml::Deserializer::from_str(secret_string)
+        .map(serde_yaml::Value::deserialize)
+        .collect::<Result<Vec<_>, _>>()
+        .ok()?;
+    if documents.len() < 2 {
+        return None;
+    }
+    documents
+        .iter()
+        .enumerate()
+        .map(|(i, document)| Some((format!("doc{}", i), serde_yaml::to_string(document).ok()?)))
+        .collect()
+}
+
+/// Parses the secret string of an AWS secret into key/value pairs
+pub trait SecretParser: Send + Sync {
+    fn parse(&self, input: &str) -> Result<SecretData, ParseError>;
+}
+
+/// Parses a JSON object or YAML mapping, storing anything else as plain text under
+/// `plain_text_key`
+pub struct JsonParser {
+    pub plain_text_key: String,
+}
+
+impl SecretParser for JsonParser {
+    fn parse(&self, input: &str) -> Result<SecretData, ParseError> {
+        parse_secret_string(input, &self.plain_text_key)
+    }
+}
+
+/// Parses URL encoded `key=value` pairs joined by `&`
+pub struct FormParser;
+
+impl SecretParser for FormParser {
+    fn parse(&self, input: &str) -> Result<SecretData, ParseError> {
+        Ok(form_urlencoded::parse(input.trim().as_bytes())
+            .into_owned()
+            .collect())
+    }
+}
+
+/// Stores the whole string as the value of `plain_text_key`
+pub struct PlainParser {
+    pub plain_text_key: String,
+}
+
+impl SecretParser for PlainParser {
+    fn parse(&self, input: &str) -> Result<SecretData, ParseError> {
+        Ok(SecretData::from([(
+            self.plain_text_key.clone(),
+            String::from(input),
+        )]))
+    }
+}
+
+// the parser for secret strings of the MIME type `format`
+pub fn secret_parser(format: SecretFormat, plain_text_key: &str) -> Box<dyn SecretParser> {
+    let plain_text_key = String::from(plain_text_key);
+    match format {
+        SecretFormat::Json => Box::new(JsonParser { plain_text_key }),
+        SecretFormat::Form => Box::new(FormParser),
+        SecretFormat::Plain => Box::new(PlainParser { plain_text_key }),
+    }
+}
+
+// removes the secret values whose key is one of `excluded`
+pub fn exclude_keys(secret_value: SecretData, excluded: &[String]) -> SecretData {
+    secret_value
+        .into_iter()
+        .filter(|(key, _)| !excluded.contains(key))
+        .collect()
+}
+
+// keeps only the secret values whose key is one of `included`, or all of them when it's empty
+pub fn include_keys(secret_value: SecretData, included: &[String]) -> SecretData {
+    if included.is_empty() {
+        return secret_value;
+    }
+    secret_value
+        .into_iter()
+        .filter(|(key, _)| included.contains(key))
+        .collect()
+}
+
+// the keys of `keys` that the secret values don't have
+pub fn missing_keys<'a>(secret_value: &SecretData, keys: &'a [String]) -> Vec<&'a str> {
+    keys.iter()
+        .map(String::as_str)
+        .filter(|key| !secret_value.0.contains_key(*key))
+        .collect()
+}
+
+// renames the keys of the secret values found in `key_map`, leaving the other keys unchanged
+pub fn remap_keys(secret_value: SecretData, key_map: &[(String, String)]) -> SecretData {
+    secret_value
+        .into_iter()
+        .map(
+            |(key, value)| match key_map.iter().find(|(aws_key, _)| *aws_key == key) {
+                Some((_, k8s_key)) => (k8s_key.clone(), value),
+                None => (key, value),
+            },
+        )
+        .collect()
+}
+
+// creates the secret data with the secret values encoded as a single value with in base64 and a key as the filename
+pub fn create_filesecret_from_aws_secret(secrets: SecretData, filename: String) -> SecretData {
+    let engine = general_purpose::STANDARD;
+    let encoded = engine.encode(env_file(secrets).as_bytes());
+
+    SecretData::from([(filename, encoded)])
+}
+
+// creates the secret data like `create_filesecret_from_aws_secret`, with the file gzipped
+// before it is base64 encoded
+pub fn create_gzip_filesecret_from_aws_secret(secrets: SecretData, filename: String) -> SecretData {
+    use std::io::Write;
+
+    let engine = general_purpose::STANDARD;
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+    // writing to a Vec can't fail
+    encoder.write_all(env_file(secrets).as_bytes()).unwrap();
+    let encoded = engine.encode(encoder.finish().unwrap());
+
+    SecretData::from([(filename, encoded)])
+}
+
+// the env file of the secret values, one `key=value` line each
+fn env_file(secrets: SecretData) -> String {
+    use std::fmt::Write;
+
+    // sort the keys so the same secret always produces the same file
+    let mut secrets: Vec<_> = secrets.into_iter().collect();
+    secrets.sort();
+    secrets.into_iter().fold(String::new(), |mut res, (k, v)| {
+        writeln!(&mut res, "{}={}", k, env_file_value(&v)).unwrap();
+        res
+    })
+}
+
+// quotes a value spanning several lines, e.g. a PEM certificate, so it stays a single entry of
+// the env file, escaping its backslashes and double quotes
+pub fn env_file_value(value: &str) -> Cow<'_, str> {
+    if !value.contains(['\n', '\r']) {
+        return Cow::Borrowed(value);
+    }
+    let escaped = value.replace('\\', "\\\\").replace('"', "\\\"");
+    Cow::Owned(format!("\"{}\"", escaped))
+}
+
+// creates the secret data with the binary secret encoded in base64 as a single value under `binary_key`
+pub fn handle_binary_secret(binary: Blob, binary_key: &str) -> SecretData {
+    let engine = general_purpose::STANDARD;
+    SecretData::from([(String::from(binary_key), engine.encode(binary.into_inner()))])
+}
+
+// creates the secret data with the secret values encoded in base64
+// each key and value is encoded separately
+pub fn create_datamap_from_aws_secret(secret_value: SecretData) -> SecretData {
+    let engine = general_purpose::STANDARD;
+    secret_value
+        .into_iter()
+        .map(|(key, value)| (key, engine.encode(value.as_bytes())))
+        .collect()
+}
+
+// creates the secret data from secret values that are already encoded in base64, using them
+// as they are once each of them is checked to be valid base64
+pub fn create_datamap_from_base64_values(
+    secret_value: SecretData,
+) -> Result<SecretData, ParseError> {
+    let engine = general_purpose::STANDARD;
+    secret_value
+        .into_iter()
+        .map(|(key, value)| match engine.decode(value.as_bytes()) {
+            Ok(_) => Ok((key, value)),
+            Err(_) => Err(ParseError::NotBase64 { key }),
+        })
+        .collect()
+}
+
+// whether the base64 encoded data of a Kubernetes secret fits within `limit` bytes
+pub fn check_secret_size(data: &SecretData, limit: usize) -> bool {
+    data.byte_size() <= limit
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn handle_binary_secret_encodes_bytes_under_key() {
+        let data_map = handle_binary_secret(Blob::new(vec![0u8, 159, 146, 150]), "keystore");
+        assert_eq!(
+            data_map,
+            SecretData::from([(String::from("keystore"), String::from("AJ+Slg=="))])
+        );
+    }
+
+    #[test]
+    fn parse_secret_string_reads_json_object() {
+        let parsed = parse_secret_string(r#"{"username":"admin","password":"hunter2"}"#, "value");
+        assert_eq!(
+            parsed.unwrap(),
+            SecretData::from([
+                (String::from("username"), String::from("admin")),
+                (String::from("password"), String::from("hunter2")),
+            ])
+        );
+    }
+
+    #[test]
+    fn parse_secret_string_reads_yaml_mapping() {
+        let parsed = parse_secret_string("username: admin\npassword: hunter2\n", "value");
+        assert_eq!(
+            parsed.unwrap(),
+            SecretData::from([
+                (String::from("username"), String::from("admin")),
+                (String::from("password"), String::from("hunter2")),
+            ])
+        );
+    }
+
+    #[test]
+    fn parse_secret_string_rejects_yaml_sequence() {
+        let parsed = parse_secret_string("- admin\n- hunter2\n", "value");
+        assert!(matches!(parsed, Err(ParseError::NotAMapping)));
+    }
+
+    #[test]
+    fn parse_secret_string_stores_plain_text_under_key() {
+        let parsed = parse_secret_string("mysupersecretpassword", "password");
+        assert_eq!(
+            parsed.unwrap(),
+            SecretData::from([(
+                String::from("password"),
+                String::from("mysupersecretpassword")
+            )])
+        );
+    }
+
+    #[test]
+    fn parse_secret_string_treats_non_object_json_as_plain_text() {
+        let parsed = parse_secret_string("12345", "value");
+        assert_eq!(
+            parsed.unwrap(),
+            SecretData::from([(String::from("value"), String::from("12345"))])
+        );
+    }
+
+    #[test]
+    fn parse_secret_string_rejects_non_string_values() {
+        let parsed = parse_secret_string(r#"{"port":5432}"#, "value");
+        assert!(matches!(parsed, Err(ParseError::NonStringValue { key }) if key == "port"));
+    }
+
+    // decodes a value of the data map back into a string
+    fn decode(value: &str) -> String {
+        let bytes = general_purpose::STANDARD.decode(value).unwrap();
+        String::from_utf8(bytes).unwrap()
+    }
+
+    #[test]
+    fn create_filesecret_writes_env_file_under_filename() {
+        let secrets = SecretData::from([
+            (String::from("USERNAME"), String::from("admin")),
+            (String::from("PASSWORD"), String::from("hunter2")),
+        ]);
+        let data_map = create_filesecret_from_aws_secret(secrets, String::from("app.env"));
+
+        assert_eq!(data_map.len(), 1);
+        assert_eq!(
+            decode(&data_map["app.env"]),
+            "PASSWORD=hunter2\nUSERNAME=admin\n"
+        );
+    }
+
+    // parses an env file the way dotenv does, with double quoted values spanning lines
+    fn parse_env_file(contents: &str) -> Vec<(String, String)> {
+        let mut entries = Vec::new();
+        let mut rest = contents;
+        while let Some((key, after)) = rest.split_once('=') {
+            let Some(quoted) = after.strip_prefix('"') else {
+                let (value, next) = after.split_once('\n').unwrap_or((after, ""));
+                entries.push((String::from(key), String::from(value)));
+                rest = next;
+                continue;
+            };
+            let mut value = String::new();
+            let mut chars = quoted.char_indices();
+            let end = loop {
+                match chars.next().unwrap() {
+                    (_, '\\') => value.push(chars.next().unwrap().1),
+                    (i, '"') => break i,
+                    (_, c) => value.push(c),
+                }
+            };
+            entries.push((String::from(key), value));
+            rest = quoted[end + 1..].strip_prefix('\n').unwrap();
+        }
+        entries
+    }
+
+    #[test]
+    fn create_filesecret_quotes_multi_line_values() {
+        let pem = "-----BEGIN CERTIFICATE-----\nMIIB\\x\"y\n-----END CERTIFICATE-----\n";
+        let secrets = SecretData::from([
+            (String::from("CERT"), String::from(pem)),
+            (String::from("USER"), String::from("admin")),
+        ]);
+        let data_map = create_filesecret_from_aws_secret(secrets, String::from("app.env"));
+        let contents = decode(&data_map["app.env"]);
+
+        assert_eq!(
+            contents,
+            "CERT=\"-----BEGIN CERTIFICATE-----\nMIIB\\\\x\\\"y\n-----END CERTIFICATE-----\n\"\n\
+             USER=admin\n"
+        );
+        assert_eq!(
+            parse_env_file(&contents),
+            [
+                (String::from("CERT"), String::from(pem)),
+                (String::from("USER"), String::from("admin")),
+            ]
+        );
+        assert_eq!(env_file_value(r#"say "hi""#), r#"say "hi""#);
+    }
+
+    #[test]
+    fn create_filesecret_is_deterministic() {
+        let secrets: SecretData = (0..20)
+            .map(|i| (format!("KEY_{}", i), format!("value-{}", i)))
+            .collect();
+        let first = create_filesecret_from_aws_secret(secrets.clone(), String::from(".env"));
+        for _ in 0..10 {
+            // a new map iterates in a different order
+            let secrets: SecretData = secrets.clone().into_iter().collect();
+            let again = create_filesecret_from_aws_secret(secrets, String::from(".env"));
+            assert_eq!(again, first);
+        }
+    }
+
+    #[test]
+    fn create_filesecret_of_single_pair_is_one_line() {
+        let secrets = SecretData::from([(String::from("TOKEN"), String::from("abc"))]);
+        let data_map = create_filesecret_from_aws_secret(secrets, String::from(".env"));
+        assert_eq!(data_map[".env"], "VE9LRU49YWJjCg==");
+        assert_eq!(decode(&data_map[".env"]), "TOKEN=abc\n");
+    }
+
+    #[test]
+    fn create_filesecret_of_empty_map_is_empty_file() {
+        let data_map =
+            create_filesecret_from_aws_secret(SecretData::default(), String::from(".env"));
+        assert_eq!(
+            data_map,
+            SecretData::from([(String::from(".env"), String::new())])
+        );
+    }
+
+    #[test]
+    fn create_gzip_filesecret_gunzips_to_the_env_file() {
+        use flate2::read::GzDecoder;
+        use std::io::Read;
+
+        let secrets = SecretData::from([
+            (String::from("USER"), String::from("admin")),
+            (String::from("PASSWORD"), String::from("hunter2")),
+        ]);
+        let data_map = create_gzip_filesecret_from_aws_secret(secrets, String::from(".env"));
+
+        let compressed = general_purpose::STANDARD.decode(&data_map[".env"]).unwrap();
+        let mut env_file = String::new();
+        GzDecoder::new(compressed.as_slice())
+            .read_to_string(&mut env_file)
+            .unwrap();
+        assert_eq!(env_file, "PASSWORD=hunter2\nUSER=admin\n");
+    }
+
+    #[test]
+    fn create_datamap_encodes_each_value() {
+        let secrets = SecretData::from([
+            (String::from("username"), String::from("admin")),
+            (String::from("password"), String::from("hunter2")),
+        ]);
+        let data_map = create_datamap_from_aws_secret(secrets);
+        assert_eq!(
+            data_map,
+            SecretData::from([
+                (String::from("username"), String::from("YWRtaW4=")),
+                (String::from("password"), String::from("aHVudGVyMg==")),
+            ])
+        );
+        assert_eq!(decode(&data_map["password"]), "hunter2");
+    }
+
+    #[test]
+    fn create_datamap_from_base64_values_doesnt_encode_twice() {
+        let encoded = general_purpose::STANDARD.encode("hunter2");
+        let secrets = SecretData::from([(String::from("password"), encoded.clone())]);
+        let data_map = create_datamap_from_base64_values(secrets).unwrap();
+        assert_eq!(data_map["password"], encoded);
+        assert_eq!(decode(&data_map["password"]), "hunter2");
+
+        let plain = SecretData::from([(String::from("password"), String::from("hunter2!"))]);
+        assert!(matches!(
+            create_datamap_from_base64_values(plain),
+            Err(ParseError::NotBase64 { key }) if key == "password"
+        ));
+    }
+
+    #[test]
+    fn create_datamap_of_empty_map_is_empty() {
+        assert!(create_datamap_from_aws_secret(SecretData::default()).is_empty());
+    }
+
+    #[test]
+    fn split_yaml_documents_stores_each_document_under_its_index() {
+        let secret_string = "host: db.internal\nport: 5432\n---\n- admin\n- reader\n";
+        let data = split_yaml_documents(secret_string).unwrap();
+        assert_eq!(
+            data,
+            SecretData::from([
+                (
+                    String::from("doc0"),
+                    String::from("host: db.internal\nport: 5432\n")
+                ),
+                (String::from("doc1"), String::from("- admin\n- reader\n")),
+            ])
+        );
+
+        assert_eq!(split_yaml_documents("host: db.internal\n"), None);
+        assert_eq!(split_yaml_documents("{\"user\": \"admin\"}"), None);
+    }
+
+    #[test]
+    fn form_parser_decodes_url_encoded_pairs() {
+        let parser = secret_parser(SecretFormat::Form, "value");
+        assert_eq!(
+            parser
+                .parse("username=admin&password=hunter%262+%3D\n")
+                .unwrap(),
+            SecretData::from([
+                (String::from("username"), String::from("admin")),
+                (String::from("password"), String::from("hunter&2 =")),
+            ])
+        );
+    }
+
+    #[test]
+    fn plain_parser_keeps_json_as_is() {
+        let parser = secret_parser(SecretFormat::Plain, "config");
+        assert_eq!(
+            parser.parse(r#"{"username":"admin"}"#).unwrap(),
+            SecretData::from([(
+                String::from("config"),
+                String::from(r#"{"username":"admin"}"#)
+            )])
+        );
+    }
+
+    #[test]
+    fn json_parser_parses_objects() {
+        let parser = secret_parser(SecretFormat::Json, "value");
+        assert_eq!(
+            parser.parse(r#"{"username":"admin"}"#).unwrap(),
+            SecretData::from([(String::from("username"), String::from("admin"))])
+        );
+    }
+
+    #[test]
+    fn exclude_keys_removes_listed_keys_only() {
+        let excluded = [String::from("_comment"), String::from("_created_by")];
+        let expected = SecretData::from([(String::from("password"), String::from("hunter2"))]);
+        let pairs = [
+            (String::from("_created_by"), String::from("terraform")),
+            (String::from("password"), String::from("hunter2")),
+            (String::from("_comment"), String::from("rotated monthly")),
+        ];
+        // the order of the input and of the exclusions doesn't matter
+        for rotation in 0..pairs.len() {
+            let mut pairs = pairs.clone();
+            pairs.rotate_left(rotation);
+            let secrets: SecretData = pairs.into_iter().collect();
+            assert_eq!(exclude_keys(secrets.clone(), &excluded), expected);
+            let reversed: Vec<_> = excluded.iter().rev().cloned().collect();
+            assert_eq!(exclude_keys(secrets, &reversed), expected);
+        }
+    }
+
+    #[test]
+    fn exclude_keys_without_exclusions_keeps_everything() {
+        let secrets = SecretData::from([(String::from("_comment"), String::from("note"))]);
+        assert_eq!(exclude_keys(secrets.clone(), &[]), secrets);
+    }
+
+    #[test]
+    fn include_keys_keeps_listed_keys_only() {
+        let secrets = SecretData::from([
+            (String::from("username"), String::from("admin")),
+            (String::from("password"), String::from("hunter2")),
+            (String::from("_comment"), String::from("note")),
+        ]);
+        let included = [String::from("password"), String::from("username")];
+        assert_eq!(
+            include_keys(secrets.clone(), &included),
+            SecretData::from([
+                (String::from("username"), String::from("admin")),
+                (String::from("password"), String::from("hunter2")),
+            ])
+        );
+        assert_eq!(include_keys(secrets.clone(), &[]), secrets);
+    }
+
+    #[test]
+    fn missing_keys_lists_keys_absent_from_the_secret() {
+        let secrets = SecretData::from([(String::from("username"), String::from("admin"))]);
+        let included = [String::from("username"), String::from("password")];
+        assert_eq!(missing_keys(&secrets, &included), ["password"]);
+        assert!(include_keys(secrets, &included).keys().eq(["username"]));
+    }
+
+    #[test]
+    fn remap_keys_renames_mapped_keys_only() {
+        let secrets = SecretData::from([
+            (String::from("db_password"), String::from("hunter2")),
+            (String::from("db_user"), String::from("admin")),
+        ]);
+        let key_map = [(
+            String::from("db_password"),
+            String::from("DATABASE_PASSWORD"),
+        )];
+        assert_eq!(
+            remap_keys(secrets, &key_map),
+            SecretData::from([
+                (String::from("DATABASE_PASSWORD"), String::from("hunter2")),
+                (String::from("db_user"), String::from("admin")),
+            ])
+        );
+    }
+
+    #[test]
+    fn secret_data_converts_into_a_json_object() {
+        let data = SecretData::from([(String::from("username"), String::from("admin"))]);
+        assert_eq!(data.keys().collect::<Vec<_>>(), ["username"]);
+        assert_eq!(
+            serde_json::Value::from(data),
+            serde_json::json!({"username": "admin"})
+        );
+    }
+
+    #[test]
+    fn check_secret_size_allows_exactly_the_limit() {
+        // 8 bytes of key and 4 bytes of base64
+        let data = SecretData::from([(String::from("password"), String::from("YWJj"))]);
+        assert_eq!(data.byte_size(), 12);
+        assert!(check_secret_size(&data, 13));
+        assert!(check_secret_size(&data, 12));
+        assert!(!check_secret_size(&data, 11));
+        assert!(!check_secret_size(&data, 0));
+    }
+
+    #[test]
+    fn check_secret_size_sums_every_entry() {
+        let data = SecretData::from([
+            (String::from("a"), String::from("YQ==")),
+            (String::from("b"), String::from("Yg==")),
+        ]);
+        assert_eq!(data.byte_size(), 10);
+        assert!(!check_secret_size(&data, 9));
+        assert!(check_secret_size(&SecretData::default(), 0));
+    }
+}
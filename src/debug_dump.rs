@@ -0,0 +1,73 @@
+// Writing the decoded data of each Kubernetes secret to local files, with `--debug-dump-dir`.
+//
+// This is for debugging the parsing of secret strings only, and is compiled in with the
+// `debug-dump` feature alone so production builds can't write secret values to disk.
+
+use std::fs::{OpenOptions, Permissions};
+use std::io::Write;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+use base64::engine::general_purpose;
+use base64::Engine;
+
+use crate::data::SecretData;
+
+// the file the data of the Kubernetes secret `namespace/name` is written to
+pub fn dump_path(dir: &Path, namespace: &str, name: &str) -> PathBuf {
+    dir.join(format!("{}-{}.json", namespace, name))
+}
+
+// writes the base64 encoded `data` of the Kubernetes secret `namespace/name` to `dir` as a
+// JSON object of decoded values, readable by the owner only
+pub fn dump_secret(
+    dir: &Path,
+    namespace: &str,
+    name: &str,
+    data: &SecretData,
+) -> std::io::Result<PathBuf> {
+    let decoded: serde_json::Map<String, serde_json::Value> = data
+        .iter()
+        .map(|(key, value)| {
+            let value = match general_purpose::STANDARD.decode(value) {
+                Ok(bytes) => String::from_utf8_lossy(&bytes).into_owned(),
+                Err(_) => String::from(value),
+            };
+            (String::from(key), serde_json::Value::String(value))
+        })
+        .collect();
+    let path = dump_path(dir, namespace, name);
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&path)?;
+    // the mode only applies to new files
+    file.set_permissions(Permissions::from_mode(0o600))?;
+    serde_json::to_writer_pretty(&mut file, &decoded)?;
+    file.write_all(b"\n")?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dump_secret_writes_decoded_values_for_the_owner_only() {
+        let dir = std::env::temp_dir().join(format!("debug-dump-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let data = SecretData::from([(String::from("password"), String::from("aHVudGVyMg=="))]);
+
+        let path = dump_secret(&dir, "prod", "db-creds", &data).unwrap();
+
+        assert_eq!(path, dir.join("prod-db-creds.json"));
+        let dumped: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(dumped, serde_json::json!({"password": "hunter2"}));
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
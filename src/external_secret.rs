@@ -0,0 +1,134 @@
+// ExternalSecret resources of the External Secrets Operator, created instead of Kubernetes
+// secrets with `--output-mode external-secret`.
+//
+// The operator fetches the AWS secret itself, so only a reference to the secret is synced.
+
+use kube::api::{Api, ApiResource, DynamicObject, GroupVersionKind, Patch, PatchParams};
+
+use crate::args::Args;
+use crate::aws::SecretVersion;
+use crate::k8s::{Applied, SecretTemplate, SOURCE_ARN_ANNOTATION};
+
+/// The API group of the External Secrets Operator resources
+pub const EXTERNAL_SECRETS_GROUP: &str = "external-secrets.io";
+
+/// The version of the `ExternalSecret` resources that are created
+pub const EXTERNAL_SECRETS_VERSION: &str = "v1beta1";
+
+// the `ExternalSecret` custom resource, which isn't known to k8s-openapi
+pub fn external_secret_resource() -> ApiResource {
+    let gvk = GroupVersionKind::gvk(
+        EXTERNAL_SECRETS_GROUP,
+        EXTERNAL_SECRETS_VERSION,
+        "ExternalSecret",
+    );
+    ApiResource::from_gvk_with_plural(&gvk, "externalsecrets")
+}
+
+// builds the ExternalSecret `namespace`/`name` extracting every key of the AWS secret
+// `remote_key` into a Kubernetes secret of the same name, using the store given in `args`
+//
+// the labels, annotations and type in `template` are set on both the ExternalSecret and the
+// secret the operator creates from it
+pub fn build_external_secret(
+    name: &str,
+    namespace: &str,
+    remote_key: &str,
+    args: &Args,
+    template: &SecretTemplate,
+) -> DynamicObject {
+    let mut annotations = template.annotations.clone();
+    annotations.insert(
+        String::from(SOURCE_ARN_ANNOTATION),
+        String::from(remote_key),
+    );
+
+    let mut external_secret =
+        DynamicObject::new(name, &external_secret_resource()).within(namespace);
+    external_secret.metadata.labels = Some(template.labels.clone());
+    external_secret.metadata.annotations = Some(annotations);
+    external_secret.data = serde_json::json!({
+        "spec": {
+            "secretStoreRef": {
+                "name": args.secret_store_name,
+                "kind": args.secret_store_kind,
+            },
+            "target": {
+                "name": name,
+                "template": {
+                    "type": template.secret_type,
+                    "metadata": {
+                        "labels": template.labels,
+                        "annotations": template.annotations,
+                    },
+                },
+            },
+            "dataFrom": [{
+                "extract": {
+                    "key": remote_key,
+                    "version": SecretVersion::from_args(args).remote_ref(),
+                },
+            }],
+        },
+    });
+    external_secret
+}
+
+// applies `external_secret` unless the existing ExternalSecret already has the same spec
+pub async fn apply_external_secret(
+    external_secrets: &Api<DynamicObject>,
+    external_secret: &DynamicObject,
+    params: &PatchParams,
+) -> Result<Applied, kube::Error> {
+    let name = external_secret.metadata.name.as_deref().unwrap();
+    if let Some(existing) = external_secrets.get_opt(name).await? {
+        if existing.data["spec"] == external_secret.data["spec"] {
+            return Ok(Applied::Unchanged);
+        }
+    }
+    external_secrets
+        .patch(name, params, &Patch::Apply(external_secret))
+        .await?;
+    Ok(Applied::Updated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::args::tests::args;
+    use chrono::Utc;
+
+    #[test]
+    fn build_external_secret_references_the_aws_secret() {
+        let args = args(&[
+            "--output-mode",
+            "external-secret",
+            "--secret-store-name",
+            "aws",
+            "--secret-store-kind",
+            "ClusterSecretStore",
+        ]);
+        let template = SecretTemplate::from_args(&args, Utc::now());
+        let arn = "arn:aws:secretsmanager:eu-west-1:123456789012:secret:db-creds-AbCdEf";
+        let external_secret = build_external_secret("db-creds", "prod", arn, &args, &template);
+
+        let patch = serde_json::to_value(&external_secret).unwrap();
+        assert_eq!(patch["apiVersion"], "external-secrets.io/v1beta1");
+        assert_eq!(patch["kind"], "ExternalSecret");
+        assert_eq!(patch["metadata"]["namespace"], "prod");
+        assert_eq!(
+            patch["metadata"]["annotations"]["k8s-aws-secrets-sync/source-arn"],
+            arn
+        );
+        assert_eq!(
+            patch["spec"]["secretStoreRef"],
+            serde_json::json!({"name": "aws", "kind": "ClusterSecretStore"})
+        );
+        assert_eq!(patch["spec"]["target"]["name"], "db-creds");
+        assert_eq!(patch["spec"]["target"]["template"]["type"], "Opaque");
+        assert_eq!(
+            patch["spec"]["dataFrom"],
+            serde_json::json!([{"extract": {"key": arn, "version": "AWSCURRENT"}}])
+        );
+    }
+}
@@ -0,0 +1,98 @@
+// HTTP endpoints for Kubernetes probes, with `--health-check-port`.
+//
+// `/healthz` answers as long as the process is running, for liveness probes. `/readyz` only
+// succeeds once the first sync has completed, so a watch-mode pod isn't reported ready before
+// its secrets exist.
+
+use std::convert::Infallible;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use tokio::net::TcpListener;
+use tracing::{debug, info};
+
+/// Binds the health check server to `port` on every interface and serves it in the
+/// background; `ready` is set once the first sync has completed
+pub async fn spawn_health_server(port: u16, ready: Arc<AtomicBool>) -> std::io::Result<()> {
+    let listener = TcpListener::bind(SocketAddr::from((Ipv4Addr::UNSPECIFIED, port))).await?;
+    info!("Serving health checks on port {}", port);
+    tokio::spawn(async move {
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    debug!("Failed to accept health check connection: {}", e);
+                    continue;
+                }
+            };
+            let ready = ready.clone();
+            tokio::spawn(async move {
+                let service = service_fn(move |request: Request<hyper::body::Incoming>| {
+                    let response = respond(request.method(), request.uri().path(), &ready);
+                    async move { Ok::<_, Infallible>(response) }
+                });
+                if let Err(e) = http1::Builder::new()
+                    .serve_connection(TokioIo::new(stream), service)
+                    .await
+                {
+                    debug!("Health check connection failed: {}", e);
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+// the response to a health check request for `path`
+fn respond(method: &Method, path: &str, ready: &AtomicBool) -> Response<Full<Bytes>> {
+    let (status, body) = match (method, path) {
+        (&Method::GET, "/healthz") => (StatusCode::OK, r#"{"status":"ok"}"#),
+        (&Method::GET, "/readyz") if ready.load(Ordering::SeqCst) => {
+            (StatusCode::OK, r#"{"status":"ready"}"#)
+        }
+        (&Method::GET, "/readyz") => (StatusCode::SERVICE_UNAVAILABLE, r#"{"status":"not ready"}"#),
+        _ => (StatusCode::NOT_FOUND, r#"{"status":"not found"}"#),
+    };
+    let mut response = Response::new(Full::new(Bytes::from_static(body.as_bytes())));
+    *response.status_mut() = status;
+    response.headers_mut().insert(
+        hyper::header::CONTENT_TYPE,
+        hyper::header::HeaderValue::from_static("application/json"),
+    );
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn readyz_fails_until_the_first_sync() {
+        let ready = AtomicBool::new(false);
+        assert_eq!(
+            respond(&Method::GET, "/healthz", &ready).status(),
+            StatusCode::OK
+        );
+        assert_eq!(
+            respond(&Method::GET, "/readyz", &ready).status(),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+
+        ready.store(true, Ordering::SeqCst);
+        assert_eq!(
+            respond(&Method::GET, "/readyz", &ready).status(),
+            StatusCode::OK
+        );
+        assert_eq!(
+            respond(&Method::GET, "/metrics", &ready).status(),
+            StatusCode::NOT_FOUND
+        );
+    }
+}
@@ -0,0 +1,87 @@
+// Generating Helm values for a CronJob chart from the command line, with `generate-helm-values`.
+//
+// The arguments given before the subcommand are passed on as they are, along with the
+// environment variables of the flags that are set, so the CronJob runs the sync exactly as it
+// would have run here.
+
+use clap::CommandFactory;
+use serde::Serialize;
+
+use crate::args::Args;
+
+/// The name of the subcommand, which ends the arguments passed on to the CronJob
+pub const SUBCOMMAND: &str = "generate-helm-values";
+
+/// The values overriding a standard CronJob chart
+#[derive(Debug, PartialEq, Serialize)]
+pub struct HelmValues {
+    /// The version of k8s_aws_secrets_sync the values were generated with
+    pub version: String,
+    pub schedule: String,
+    pub args: Vec<String>,
+    pub env: Vec<HelmEnvVar>,
+}
+
+/// An environment variable of the CronJob container
+#[derive(Debug, PartialEq, Serialize)]
+pub struct HelmEnvVar {
+    pub name: String,
+    pub value: String,
+}
+
+// builds the values running the sync on `schedule` with the arguments of `argv` before the
+// subcommand, and the flags set in the environment read with `env`
+pub fn helm_values(
+    argv: &[String],
+    env: impl Fn(&str) -> Option<String>,
+    schedule: &str,
+) -> HelmValues {
+    let args = argv
+        .iter()
+        .take_while(|arg| *arg != SUBCOMMAND)
+        .cloned()
+        .collect();
+    let env = Args::command()
+        .get_arguments()
+        .filter_map(|arg| arg.get_env())
+        .filter_map(|name| {
+            let name = name.to_string_lossy().into_owned();
+            env(&name).map(|value| HelmEnvVar { name, value })
+        })
+        .collect();
+    HelmValues {
+        version: String::from(env!("CARGO_PKG_VERSION")),
+        schedule: String::from(schedule),
+        args,
+        env,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn helm_values_pass_on_the_arguments_and_environment() {
+        let argv: Vec<String> = ["-n", "ns", "--dry-run", SUBCOMMAND, "--schedule", "@daily"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        let env = |name: &str| (name == "SECRET_NAME_TAG").then(|| String::from("name"));
+        let values = helm_values(&argv, env, "@daily");
+
+        assert_eq!(values.args, ["-n", "ns", "--dry-run"]);
+        assert_eq!(
+            values.env,
+            [HelmEnvVar {
+                name: String::from("SECRET_NAME_TAG"),
+                value: String::from("name"),
+            }]
+        );
+        let yaml: serde_yaml::Value =
+            serde_yaml::from_str(&serde_yaml::to_string(&values).unwrap()).unwrap();
+        assert_eq!(yaml["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(yaml["schedule"], "@daily");
+        assert_eq!(yaml["env"][0]["name"], "SECRET_NAME_TAG");
+    }
+}
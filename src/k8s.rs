@@ -0,0 +1,1157 @@
+// Building and applying the Kubernetes secrets.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+use base64::engine::general_purpose;
+use base64::Engine;
+use chrono::{DateTime, SecondsFormat, Utc};
+use k8s_openapi::api::core::v1::{ConfigMap, Namespace, ObjectReference, Secret};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
+use k8s_openapi::ByteString;
+use kube::api::{
+    Api, DeleteParams, ListParams, Patch, PatchParams, PostParams, ValidationDirective,
+};
+use kube::config::{KubeConfigOptions, Kubeconfig, KubeconfigError};
+use kube::runtime::events::{Event, EventType, Recorder, Reporter};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::{info, warn};
+
+use crate::args::{Args, FieldValidation};
+use crate::aws::{is_name_in_scope, secret_name_from_arn};
+use crate::data::SecretData;
+use crate::lock::own_pod_name;
+
+/// The label marking Kubernetes secrets as managed by this tool
+pub const MANAGED_LABEL: &str = "k8s-aws-secrets-sync/managed";
+
+/// The label holding the `--field-manager` of the deployment managing a Kubernetes secret,
+/// so that `--delete-orphans` leaves the secrets of other deployments alone
+pub const OWNER_LABEL: &str = "k8s-aws-secrets-sync/owner";
+
+/// The annotation holding the time of the run that last synced a Kubernetes secret
+pub const LAST_SYNC_TIME_ANNOTATION: &str = "k8s-aws-secrets-sync/last-sync-time";
+
+/// The annotation holding the time of the sync alongside `SOURCE_ARN_ANNOTATION`, with the
+/// same value as `LAST_SYNC_TIME_ANNOTATION`
+pub const SYNCED_AT_ANNOTATION: &str = "k8s-aws-secrets-sync/synced-at";
+
+// the annotations that change on every sync, so they don't count as changed metadata
+const SYNC_TIME_ANNOTATIONS: [&str; 2] = [LAST_SYNC_TIME_ANNOTATION, SYNCED_AT_ANNOTATION];
+
+/// The annotation holding the ARN of the AWS secret a Kubernetes secret was synced from
+pub const SOURCE_ARN_ANNOTATION: &str = "k8s-aws-secrets-sync/source-arn";
+
+/// The annotation telling consumers how the files of a Kubernetes secret are encoded
+pub const ENCODING_ANNOTATION: &str = "k8s-aws-secrets-sync/encoding";
+
+/// The value of `ENCODING_ANNOTATION` for files compressed with `--compress-file-secret`
+pub const GZIP_ENCODING: &str = "gzip+base64";
+
+/// The controller named as the reporter of the events recorded on Kubernetes secrets
+pub const EVENT_REPORTER: &str = "k8s-aws-secrets-sync";
+
+/// Errors raised while creating the Kubernetes clients
+#[derive(thiserror::Error, Debug)]
+pub enum KubeClientError {
+    /// The kubeconfig could not be read or has no such context
+    #[error("failed to load kubeconfig: {0}")]
+    Kubeconfig(#[from] KubeconfigError),
+
+    /// The client could not be created from the configuration
+    #[error("failed to create Kubernetes client: {0}")]
+    Client(#[from] kube::Error),
+}
+
+/// The maximum length of a Kubernetes object name
+pub const MAX_NAME_LENGTH: usize = 253;
+
+/// The maximum length of a Kubernetes namespace name
+pub const MAX_NAMESPACE_LENGTH: usize = 63;
+
+/// Errors raised for names Kubernetes would reject
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum NameError {
+    /// The name is empty
+    #[error("the name is empty")]
+    Empty,
+
+    /// The name is longer than `MAX_NAME_LENGTH`, or `MAX_NAMESPACE_LENGTH` for namespaces
+    #[error("{name:?} is {length} characters long, the maximum is {max}")]
+    TooLong {
+        name: String,
+        length: usize,
+        max: usize,
+    },
+
+    /// The name isn't made of lowercase alphanumeric labels joined by `-` or `.`
+    #[error(
+        "{name:?} must consist of lowercase alphanumeric characters, '-' or '.', \
+and start and end with an alphanumeric character"
+    )]
+    InvalidCharacters { name: String },
+
+    /// The namespace name isn't a lowercase alphanumeric label that may contain `-`
+    #[error(
+        "{name:?} must consist of lowercase alphanumeric characters or '-', and start and end \
+with an alphanumeric character"
+    )]
+    InvalidLabel { name: String },
+}
+
+/// The parts of every synced Kubernetes secret that don't depend on the AWS secret
+#[derive(Clone, Debug, Default)]
+pub struct SecretTemplate {
+    pub labels: BTreeMap<String, String>,
+    pub annotations: BTreeMap<String, String>,
+    pub secret_type: Option<String>,
+    /// Whether the secrets are immutable, `None` to leave it to the API server
+    pub immutable: Option<bool>,
+}
+
+impl SecretTemplate {
+    /// The labels, annotations and type given on the command line, plus the managed and
+    /// owner labels and the time of this run
+    pub fn from_args(args: &Args, synced_at: DateTime<Utc>) -> Self {
+        let mut annotations: BTreeMap<_, _> = args.annotations.iter().cloned().collect();
+        let synced_at = synced_at.to_rfc3339_opts(SecondsFormat::Secs, true);
+        for annotation in SYNC_TIME_ANNOTATIONS {
+            annotations.insert(String::from(annotation), synced_at.clone());
+        }
+        let mut labels: BTreeMap<_, _> = args.labels.iter().cloned().collect();
+        labels.insert(String::from(MANAGED_LABEL), String::from("true"));
+        labels.insert(String::from(OWNER_LABEL), args.field_manager.clone());
+        SecretTemplate {
+            labels,
+            annotations,
+            secret_type: Some(args.secret_type.clone()),
+            immutable: args.immutable.then_some(true),
+        }
+    }
+}
+
+/// The result of applying a single Kubernetes secret
+pub enum Applied {
+    /// The secret was created or its data was changed
+    Updated,
+    /// The secret already held the same data and was not patched
+    Unchanged,
+}
+
+/// Limits the patches in flight to each Kubernetes namespace, while different namespaces
+/// are patched concurrently
+#[derive(Debug)]
+pub struct NamespaceLimiter {
+    limit: usize,
+    semaphores: Mutex<HashMap<String, Arc<Semaphore>>>,
+}
+
+impl NamespaceLimiter {
+    /// A limiter allowing `limit` patches in flight to the same namespace
+    pub fn new(limit: usize) -> Self {
+        NamespaceLimiter {
+            limit,
+            semaphores: Mutex::default(),
+        }
+    }
+
+    /// Waits until a patch to `namespace` may be sent; the permit is held until dropped
+    pub async fn acquire(&self, namespace: &str) -> OwnedSemaphorePermit {
+        let semaphore = self.semaphore(namespace);
+        semaphore.acquire_owned().await.unwrap()
+    }
+
+    // the semaphore of `namespace`, created on first use
+    fn semaphore(&self, namespace: &str) -> Arc<Semaphore> {
+        let mut semaphores = self.semaphores.lock().unwrap();
+        semaphores
+            .entry(String::from(namespace))
+            .or_insert_with(|| Arc::new(Semaphore::new(self.limit)))
+            .clone()
+    }
+}
+
+// creates one Kubernetes client per `--kube-context`, or a single default client without any
+pub async fn build_kube_clients(args: &Args) -> Result<Vec<kube::Client>, KubeClientError> {
+    if args.kubeconfig.is_none() && args.kube_context.is_empty() {
+        return Ok(vec![kube::Client::try_default().await?]);
+    }
+    let contexts = if args.kube_context.is_empty() {
+        vec![None]
+    } else {
+        args.kube_context.iter().cloned().map(Some).collect()
+    };
+    let mut clients = Vec::new();
+    for context in contexts {
+        let options = KubeConfigOptions {
+            context,
+            ..KubeConfigOptions::default()
+        };
+        let config = match &args.kubeconfig {
+            Some(path) => {
+                let kubeconfig = Kubeconfig::read_from(path)?;
+                kube::Config::from_custom_kubeconfig(kubeconfig, &options).await?
+            }
+            None => kube::Config::from_kubeconfig(&options).await?,
+        };
+        clients.push(kube::Client::try_from(config)?);
+    }
+    Ok(clients)
+}
+
+// checks that `name` is a valid Kubernetes secret name, a DNS subdomain as defined in RFC 1123
+pub fn validate_k8s_name(name: &str) -> Result<(), NameError> {
+    if name.is_empty() {
+        return Err(NameError::Empty);
+    }
+    if name.len() > MAX_NAME_LENGTH {
+        return Err(NameError::TooLong {
+            name: String::from(name),
+            length: name.len(),
+            max: MAX_NAME_LENGTH,
+        });
+    }
+    if !name.split('.').all(is_dns_label) {
+        return Err(NameError::InvalidCharacters {
+            name: String::from(name),
+        });
+    }
+    Ok(())
+}
+
+// checks that `name` is a valid Kubernetes namespace name, a DNS label as defined in RFC 1123
+pub fn validate_namespace_name(name: &str) -> Result<(), NameError> {
+    if name.is_empty() {
+        return Err(NameError::Empty);
+    }
+    if name.len() > MAX_NAMESPACE_LENGTH {
+        return Err(NameError::TooLong {
+            name: String::from(name),
+            length: name.len(),
+            max: MAX_NAMESPACE_LENGTH,
+        });
+    }
+    if !is_dns_label(name) {
+        return Err(NameError::InvalidLabel {
+            name: String::from(name),
+        });
+    }
+    Ok(())
+}
+
+// whether `label` is lowercase alphanumeric characters or `-`, starting and ending with an
+// alphanumeric character
+fn is_dns_label(label: &str) -> bool {
+    let is_alphanumeric = |c: char| c.is_ascii_lowercase() || c.is_ascii_digit();
+    label.starts_with(is_alphanumeric)
+        && label.ends_with(is_alphanumeric)
+        && label.chars().all(|c| is_alphanumeric(c) || c == '-')
+}
+
+// builds the server-side apply parameters used when patching Kubernetes secrets
+pub fn build_patch_params(args: &Args) -> PatchParams {
+    let validation = match args.field_validation {
+        FieldValidation::Strict => ValidationDirective::Strict,
+        FieldValidation::Warn => ValidationDirective::Warn,
+        FieldValidation::Ignore => ValidationDirective::Ignore,
+    };
+    PatchParams::apply(&args.field_manager).validation(validation)
+}
+
+// builds the Kubernetes secret `namespace`/`name` holding the base64 encoded `data`
+// along with the labels, annotations and type in `template`, annotated with `source_arn`
+pub fn build_k8s_secret(
+    name: &str,
+    namespace: &str,
+    data: SecretData,
+    template: &SecretTemplate,
+    source_arn: Option<&str>,
+) -> Secret {
+    let engine = general_purpose::STANDARD;
+    let data = data
+        .into_iter()
+        .map(|(key, value)| {
+            let value = engine
+                .decode(value)
+                .expect("data map values are base64 encoded");
+            (key, ByteString(value))
+        })
+        .collect();
+    let mut annotations = template.annotations.clone();
+    if let Some(arn) = source_arn {
+        annotations.insert(String::from(SOURCE_ARN_ANNOTATION), String::from(arn));
+    }
+    Secret {
+        metadata: ObjectMeta {
+            name: Some(String::from(name)),
+            namespace: Some(String::from(namespace)),
+            labels: Some(template.labels.clone()).filter(|labels| !labels.is_empty()),
+            annotations: Some(annotations).filter(|annotations| !annotations.is_empty()),
+            ..ObjectMeta::default()
+        },
+        data: Some(data),
+        type_: template.secret_type.clone(),
+        immutable: template.immutable,
+        ..Secret::default()
+    }
+}
+
+// applies `k8s_secret` unless the existing secret already holds exactly the same data,
+// labels and annotations
+pub async fn apply_k8s_secret(
+    secrets: &Api<Secret>,
+    k8s_secret: &Secret,
+    params: &PatchParams,
+) -> Result<Applied, kube::Error> {
+    let name = k8s_secret.metadata.name.as_deref().unwrap();
+    if let Some(existing) = secrets.get_opt(name).await? {
+        if existing.type_ != k8s_secret.type_ {
+            warn!(
+                "Secret {}/{} has type {}, not {}; the type can only be changed by recreating \
+the secret with --force-recreate",
+                existing.metadata.namespace.as_deref().unwrap_or_default(),
+                name,
+                existing.type_.as_deref().unwrap_or("Opaque"),
+                k8s_secret.type_.as_deref().unwrap_or("Opaque"),
+            );
+        }
+        if is_unchanged(&existing, k8s_secret, params.field_manager.as_deref()) {
+            return Ok(Applied::Unchanged);
+        }
+    }
+    secrets
+        .patch(name, params, &Patch::Apply(k8s_secret))
+        .await?;
+    Ok(Applied::Updated)
+}
+
+// whether applying `k8s_secret` would leave the `existing` secret as it is: the same type,
+// immutability, labels, annotations and data
+pub fn is_unchanged(existing: &Secret, k8s_secret: &Secret, field_manager: Option<&str>) -> bool {
+    existing.type_ == k8s_secret.type_
+        && existing.immutable == k8s_secret.immutable
+        && !metadata_changed(existing, k8s_secret, field_manager)
+        && existing.data.clone().unwrap_or_default() == expected_data(k8s_secret)
+}
+
+// whether applying `k8s_secret` would change the labels or annotations of the `existing`
+// secret, other than the time of the sync; those `field_manager` applied before and
+// `k8s_secret` no longer has would be removed, so they count as changes too
+pub fn metadata_changed(
+    existing: &Secret,
+    k8s_secret: &Secret,
+    field_manager: Option<&str>,
+) -> bool {
+    let changed = |field: &str,
+                   actual: &Option<BTreeMap<String, String>>,
+                   expected: &Option<BTreeMap<String, String>>| {
+        let expected: BTreeMap<&str, &str> = expected
+            .iter()
+            .flatten()
+            .filter(|(key, _)| !SYNC_TIME_ANNOTATIONS.contains(&key.as_str()))
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+            .collect();
+        let differs = expected.iter().any(|(key, value)| {
+            actual
+                .as_ref()
+                .and_then(|actual| actual.get(*key))
+                .map(String::as_str)
+                != Some(*value)
+        });
+        let removed = applied_keys(existing, field_manager, field)
+            .iter()
+            .any(|key| {
+                !SYNC_TIME_ANNOTATIONS.contains(&key.as_str())
+                    && !expected.contains_key(key.as_str())
+            });
+        differs || removed
+    };
+    changed(
+        "labels",
+        &existing.metadata.labels,
+        &k8s_secret.metadata.labels,
+    ) || changed(
+        "annotations",
+        &existing.metadata.annotations,
+        &k8s_secret.metadata.annotations,
+    )
+}
+
+// the keys of the `field` of the metadata of `existing`, e.g. `labels`, last applied by
+// `field_manager` according to its managed fields
+fn applied_keys(existing: &Secret, field_manager: Option<&str>, field: &str) -> Vec<String> {
+    let field = format!("f:{}", field);
+    existing
+        .metadata
+        .managed_fields
+        .iter()
+        .flatten()
+        .filter(|entry| {
+            entry.manager.as_deref() == field_manager && entry.operation.as_deref() == Some("Apply")
+        })
+        .filter_map(|entry| {
+            entry
+                .fields_v1
+                .as_ref()?
+                .0
+                .get("f:metadata")?
+                .get(&field)?
+                .as_object()
+        })
+        .flat_map(|keys| keys.keys())
+        .filter_map(|key| key.strip_prefix("f:"))
+        .map(String::from)
+        .collect()
+}
+
+// builds the ConfigMap `namespace`/`name` holding `data`, with the labels and annotations
+// in `template`
+pub fn build_config_map(
+    name: &str,
+    namespace: &str,
+    data: BTreeMap<String, String>,
+    template: &SecretTemplate,
+) -> ConfigMap {
+    ConfigMap {
+        metadata: ObjectMeta {
+            name: Some(String::from(name)),
+            namespace: Some(String::from(namespace)),
+            labels: Some(template.labels.clone()).filter(|labels| !labels.is_empty()),
+            annotations: Some(template.annotations.clone())
+                .filter(|annotations| !annotations.is_empty()),
+            ..ObjectMeta::default()
+        },
+        data: Some(data),
+        ..ConfigMap::default()
+    }
+}
+
+// applies `config_map` unless the existing ConfigMap already holds exactly the same data
+pub async fn apply_config_map(
+    config_maps: &Api<ConfigMap>,
+    config_map: &ConfigMap,
+    params: &PatchParams,
+) -> Result<Applied, kube::Error> {
+    let name = config_map.metadata.name.as_deref().unwrap();
+    if let Some(existing) = config_maps.get_opt(name).await? {
+        if existing.data == config_map.data {
+            return Ok(Applied::Unchanged);
+        }
+    }
+    config_maps
+        .patch(name, params, &Patch::Apply(config_map))
+        .await?;
+    Ok(Applied::Updated)
+}
+
+// the data the API server stores for `k8s_secret`, with its `stringData` merged into `data`
+pub fn expected_data(k8s_secret: &Secret) -> BTreeMap<String, ByteString> {
+    let mut data = k8s_secret.data.clone().unwrap_or_default();
+    for (key, value) in k8s_secret.string_data.iter().flatten() {
+        data.insert(key.clone(), ByteString(value.clone().into_bytes()));
+    }
+    data
+}
+
+// the keys whose value differs between the data of the `expected` and `actual` secrets,
+// including keys only one of them has
+pub fn mismatched_keys(expected: &Secret, actual: &Secret) -> Vec<String> {
+    let empty = BTreeMap::new();
+    let expected = expected_data(expected);
+    let actual = actual.data.as_ref().unwrap_or(&empty);
+    let keys: BTreeSet<_> = expected.keys().chain(actual.keys()).collect();
+    keys.into_iter()
+        .filter(|key| expected.get(*key) != actual.get(*key))
+        .cloned()
+        .collect()
+}
+
+// reads back the applied `k8s_secret` and warns when its data was changed, e.g. by a
+// mutating admission webhook
+pub async fn verify_secret(secrets: &Api<Secret>, k8s_secret: &Secret) -> Result<(), kube::Error> {
+    let name = k8s_secret.metadata.name.as_deref().unwrap();
+    let applied = secrets.get(name).await?;
+    let mismatched = mismatched_keys(k8s_secret, &applied);
+    if !mismatched.is_empty() {
+        warn!(
+            "Secret {}/{} doesn't hold the applied data for key(s) {}",
+            k8s_secret.metadata.namespace.as_deref().unwrap_or_default(),
+            name,
+            mismatched.join(", ")
+        );
+    }
+    Ok(())
+}
+
+// whether the API rejected a patch because it changes an immutable field, such as the type;
+// the API server lists the invalid fields in the message, so other invalid patches, e.g. of
+// a key that isn't a valid name, aren't mistaken for one
+pub fn is_immutable_field_error(e: &kube::Error) -> bool {
+    matches!(
+        e,
+        kube::Error::Api(response)
+            if response.code == 422 && response.message.contains("field is immutable")
+    )
+}
+
+// deletes the existing secret and applies `k8s_secret` in its place, for changes to
+// immutable fields that can't be patched
+pub async fn recreate_secret(
+    secrets: &Api<Secret>,
+    k8s_secret: &Secret,
+    params: &PatchParams,
+) -> Result<Applied, kube::Error> {
+    let name = k8s_secret.metadata.name.as_deref().unwrap();
+    let namespace = k8s_secret.metadata.namespace.as_deref().unwrap_or_default();
+    warn!(
+        "Deleting secret {}/{} to recreate it, as it can't be patched in place",
+        namespace, name
+    );
+    secrets.delete(name, &DeleteParams::default()).await?;
+    secrets
+        .patch(name, params, &Patch::Apply(k8s_secret))
+        .await?;
+    info!("Secret {}/{} recreated", namespace, name);
+    Ok(Applied::Updated)
+}
+
+// the event recorded on a Kubernetes secret once it is synced, or failed to sync with `error`
+pub fn sync_event(error: Option<&str>) -> Event {
+    match error {
+        None => Event {
+            type_: EventType::Normal,
+            reason: String::from("Synced"),
+            note: Some(String::from("Secret synced from AWS Secrets Manager")),
+            action: String::from("Sync"),
+            secondary: None,
+        },
+        Some(error) => Event {
+            type_: EventType::Warning,
+            reason: String::from("SyncFailed"),
+            note: Some(format!("Failed to sync secret: {}", error)),
+            action: String::from("Sync"),
+            secondary: None,
+        },
+    }
+}
+
+// records `event` on the Kubernetes secret `namespace/name`, so it shows in
+// `kubectl describe secret`
+pub async fn record_event(
+    client: &kube::Client,
+    namespace: &str,
+    name: &str,
+    event: Event,
+) -> Result<(), kube::Error> {
+    let reporter = Reporter {
+        controller: String::from(EVENT_REPORTER),
+        instance: Some(own_pod_name()),
+    };
+    let reference = ObjectReference {
+        api_version: Some(String::from("v1")),
+        kind: Some(String::from("Secret")),
+        name: Some(String::from(name)),
+        namespace: Some(String::from(namespace)),
+        ..ObjectReference::default()
+    };
+    Recorder::new(client.clone(), reporter, reference)
+        .publish(event)
+        .await
+}
+
+// builds the namespace `name`, labelled as managed by this tool
+pub fn build_namespace(name: &str) -> Namespace {
+    Namespace {
+        metadata: ObjectMeta {
+            name: Some(String::from(name)),
+            labels: Some(BTreeMap::from([(
+                String::from(MANAGED_LABEL),
+                String::from("true"),
+            )])),
+            ..ObjectMeta::default()
+        },
+        ..Namespace::default()
+    }
+}
+
+// creates the namespace `name` unless it already exists
+pub async fn ensure_namespace(client: &kube::Client, name: &str) -> Result<(), kube::Error> {
+    let namespaces: Api<Namespace> = Api::all(client.clone());
+    if namespaces.get_opt(name).await?.is_some() {
+        return Ok(());
+    }
+    match namespaces
+        .create(&PostParams::default(), &build_namespace(name))
+        .await
+    {
+        Ok(_) => {
+            info!("Namespace {} created", name);
+            Ok(())
+        }
+        // another secret synced to the same namespace created it first
+        Err(kube::Error::Api(response)) if response.code == 409 => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+// adds `labels` to the namespace `name`, returning false when it doesn't exist
+pub async fn label_namespace(
+    client: &kube::Client,
+    name: &str,
+    labels: &BTreeMap<String, String>,
+) -> Result<bool, kube::Error> {
+    let namespaces: Api<Namespace> = Api::all(client.clone());
+    let patch = serde_json::json!({"metadata": {"labels": labels}});
+    // a merge patch, unlike an apply, doesn't create a missing namespace
+    match namespaces
+        .patch(name, &PatchParams::default(), &Patch::Merge(&patch))
+        .await
+    {
+        Ok(_) => Ok(true),
+        Err(kube::Error::Api(response)) if response.code == 404 => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+// whether the managed Kubernetes secret `metadata` in `namespace` is within the scope of this
+// run, i.e. its namespace matches `--namespace-filter` and, with the name filters, the AWS
+// secret of its source ARN matches them; a secret filtered out of the run is not its orphan
+pub fn is_orphan_in_scope(namespace: &str, metadata: &ObjectMeta, args: &Args) -> bool {
+    if let Some(filter) = &args.namespace_filter {
+        if !filter.is_match(namespace) {
+            return false;
+        }
+    }
+    let has_name_filters = args.secret_prefix.as_deref().is_some_and(|p| !p.is_empty())
+        || args.secret_filter_name.is_some()
+        || args.exclude_pattern.is_some();
+    if !has_name_filters {
+        return true;
+    }
+    metadata
+        .annotations
+        .as_ref()
+        .and_then(|annotations| annotations.get(SOURCE_ARN_ANNOTATION))
+        .and_then(|arn| secret_name_from_arn(arn))
+        .is_some_and(|name| is_name_in_scope(name, args))
+}
+
+// deletes the Kubernetes secrets managed by this `--field-manager` in the namespaces of
+// `synced` that are no longer backed by an AWS secret, i.e. were not synced as one of the
+// `(namespace, name)` pairs, leaving those filtered out of this run
+pub async fn delete_orphans(
+    client: &kube::Client,
+    synced: &HashSet<(String, String)>,
+    args: &Args,
+) -> Result<(), kube::Error> {
+    let namespaces: BTreeSet<_> = synced.iter().map(|(namespace, _)| namespace).collect();
+    let params = ListParams::default().labels(&format!(
+        "{}=true,{}={}",
+        MANAGED_LABEL, OWNER_LABEL, args.field_manager
+    ));
+    for namespace in namespaces {
+        let secrets: Api<Secret> = Api::namespaced(client.clone(), namespace);
+        for secret in secrets.list_metadata(&params).await? {
+            let name = secret.metadata.name.clone().unwrap_or_default();
+            if synced.contains(&(namespace.clone(), name.clone()))
+                || !is_orphan_in_scope(namespace, &secret.metadata, args)
+            {
+                continue;
+            }
+            if args.orphan_dry_run {
+                info!(
+                    "[orphan-dry-run] would delete secret {}/{}",
+                    namespace, name
+                );
+                continue;
+            }
+            secrets.delete(&name, &DeleteParams::default()).await?;
+            info!("Orphaned secret {}/{} deleted", namespace, name);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::args::tests::args;
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::{FieldsV1, ManagedFieldsEntry};
+
+    #[test]
+    fn metadata_changed_compares_the_labels_and_annotations() {
+        let template = SecretTemplate::from_args(&args(&["--label", "team=payments"]), Utc::now());
+        let k8s_secret =
+            build_k8s_secret("db-creds", "prod", SecretData::default(), &template, None);
+
+        // only the sync time differs from the previous run
+        let mut existing = k8s_secret.clone();
+        for annotation in SYNC_TIME_ANNOTATIONS {
+            existing.metadata.annotations.as_mut().unwrap().insert(
+                String::from(annotation),
+                String::from("2024-05-01T12:00:00Z"),
+            );
+        }
+        assert!(!metadata_changed(
+            &existing,
+            &k8s_secret,
+            Some("k8s-aws-secrets-sync")
+        ));
+
+        let mut relabelled = existing.clone();
+        relabelled
+            .metadata
+            .labels
+            .as_mut()
+            .unwrap()
+            .insert(String::from("team"), String::from("checkout"));
+        assert!(metadata_changed(
+            &relabelled,
+            &k8s_secret,
+            Some("k8s-aws-secrets-sync")
+        ));
+
+        // a label applied by a previous run but no longer given with `--label`
+        let mut previously_labelled = existing.clone();
+        previously_labelled.metadata.managed_fields = Some(vec![ManagedFieldsEntry {
+            manager: Some(String::from("k8s-aws-secrets-sync")),
+            operation: Some(String::from("Apply")),
+            fields_v1: Some(FieldsV1(serde_json::json!({
+                "f:metadata": { "f:labels": { "f:team": {}, "f:tier": {} } }
+            }))),
+            ..ManagedFieldsEntry::default()
+        }]);
+        assert!(metadata_changed(
+            &previously_labelled,
+            &k8s_secret,
+            Some("k8s-aws-secrets-sync")
+        ));
+        // the labels of other field managers are left alone by the patch
+        assert!(!metadata_changed(
+            &previously_labelled,
+            &k8s_secret,
+            Some("other-manager")
+        ));
+    }
+
+    #[test]
+    fn sync_event_is_a_warning_on_failure() {
+        let synced = sync_event(None);
+        assert_eq!(synced.type_, EventType::Normal);
+        assert_eq!(synced.reason, "Synced");
+        assert_eq!(
+            synced.note.as_deref(),
+            Some("Secret synced from AWS Secrets Manager")
+        );
+
+        let failed = sync_event(Some("forbidden"));
+        assert_eq!(failed.type_, EventType::Warning);
+        assert_eq!(failed.reason, "SyncFailed");
+        assert_eq!(
+            failed.note.as_deref(),
+            Some("Failed to sync secret: forbidden")
+        );
+    }
+
+    #[tokio::test]
+    async fn namespace_limiter_limits_each_namespace_separately() {
+        let limiter = NamespaceLimiter::new(2);
+        let first = limiter.acquire("prod").await;
+        let _second = limiter.acquire("prod").await;
+        assert_eq!(limiter.semaphore("prod").available_permits(), 0);
+        assert_eq!(limiter.semaphore("staging").available_permits(), 2);
+        let _staging = limiter.acquire("staging").await;
+
+        drop(first);
+        assert_eq!(limiter.semaphore("prod").available_permits(), 1);
+        assert_eq!(limiter.semaphore("staging").available_permits(), 1);
+    }
+
+    #[test]
+    fn expected_data_merges_string_data() {
+        let mut secret = build_k8s_secret(
+            "db-creds",
+            "prod",
+            SecretData::from([(String::from("password"), String::from("aHVudGVyMg=="))]),
+            &SecretTemplate::default(),
+            None,
+        );
+        secret.string_data = Some(BTreeMap::from([(
+            String::from("_aws_arn"),
+            String::from("arn:aws:secretsmanager:eu-west-1:123456789012:secret:db"),
+        )]));
+        assert_eq!(
+            expected_data(&secret),
+            BTreeMap::from([
+                (
+                    String::from("_aws_arn"),
+                    ByteString(b"arn:aws:secretsmanager:eu-west-1:123456789012:secret:db".to_vec())
+                ),
+                (String::from("password"), ByteString(b"hunter2".to_vec())),
+            ])
+        );
+    }
+
+    #[test]
+    fn mismatched_keys_compares_every_key() {
+        let template = SecretTemplate::from_args(&args(&[]), Utc::now());
+        let expected = build_k8s_secret(
+            "db-creds",
+            "prod",
+            SecretData::from([
+                (String::from("username"), String::from("YWRtaW4=")),
+                (String::from("password"), String::from("aHVudGVyMg==")),
+            ]),
+            &template,
+            None,
+        );
+        assert!(mismatched_keys(&expected, &expected).is_empty());
+
+        let mut actual = expected.clone();
+        let data = actual.data.as_mut().unwrap();
+        data.insert(String::from("password"), ByteString(b"mutated".to_vec()));
+        data.insert(String::from("injected"), ByteString(b"sidecar".to_vec()));
+        data.remove("username");
+        assert_eq!(
+            mismatched_keys(&expected, &actual),
+            ["injected", "password", "username"]
+        );
+    }
+
+    #[test]
+    fn build_config_map_holds_plain_data() {
+        let template = SecretTemplate::from_args(&args(&[]), Utc::now());
+        let data = BTreeMap::from([(String::from("log-level"), String::from("info"))]);
+        let config_map = build_config_map("myapp-config", "prod", data.clone(), &template);
+
+        assert_eq!(config_map.metadata.namespace.as_deref(), Some("prod"));
+        assert_eq!(config_map.data, Some(data));
+        assert_eq!(config_map.metadata.labels.unwrap()[MANAGED_LABEL], "true");
+    }
+
+    #[test]
+    fn is_orphan_in_scope_leaves_the_secrets_filtered_out() {
+        let synced_from = |name: &str| ObjectMeta {
+            annotations: Some(BTreeMap::from([(
+                String::from(SOURCE_ARN_ANNOTATION),
+                format!(
+                    "arn:aws:secretsmanager:eu-west-1:123456789012:secret:{}-AbCdEf",
+                    name
+                ),
+            )])),
+            ..ObjectMeta::default()
+        };
+        let args = args(&[
+            "--delete-orphans",
+            "--secret-prefix",
+            "prod/",
+            "--exclude-pattern",
+            "^prod/legacy/",
+            "--namespace-filter",
+            "prod-.*",
+        ]);
+        assert!(is_orphan_in_scope(
+            "prod-eu",
+            &synced_from("prod/db-creds"),
+            &args
+        ));
+        assert!(!is_orphan_in_scope(
+            "prod-eu",
+            &synced_from("staging/db-creds"),
+            &args
+        ));
+        assert!(!is_orphan_in_scope(
+            "prod-eu",
+            &synced_from("prod/legacy/db"),
+            &args
+        ));
+        assert!(!is_orphan_in_scope(
+            "staging",
+            &synced_from("prod/db-creds"),
+            &args
+        ));
+        assert!(!is_orphan_in_scope(
+            "prod-eu",
+            &ObjectMeta::default(),
+            &args
+        ));
+
+        let unfiltered = crate::args::tests::args(&["--delete-orphans"]);
+        assert!(is_orphan_in_scope(
+            "staging",
+            &ObjectMeta::default(),
+            &unfiltered
+        ));
+    }
+
+    #[test]
+    fn build_namespace_is_labelled_as_managed() {
+        let namespace = build_namespace("prod");
+        assert_eq!(namespace.metadata.name.as_deref(), Some("prod"));
+        assert_eq!(namespace.metadata.labels.unwrap()[MANAGED_LABEL], "true");
+    }
+
+    #[test]
+    fn patch_params_default_field_manager() {
+        let params = build_patch_params(&args(&[]));
+        assert_eq!(
+            params.field_manager.as_deref(),
+            Some("k8s-aws-secrets-sync")
+        );
+    }
+
+    #[test]
+    fn patch_params_uses_field_manager_arg() {
+        let params = build_patch_params(&args(&["--field-manager", "team-a-sync"]));
+        assert_eq!(params.field_manager.as_deref(), Some("team-a-sync"));
+    }
+
+    #[test]
+    fn patch_params_use_field_validation_arg() {
+        assert!(matches!(
+            build_patch_params(&args(&[])).field_validation,
+            Some(ValidationDirective::Strict)
+        ));
+        assert!(matches!(
+            build_patch_params(&args(&["--field-validation", "ignore"])).field_validation,
+            Some(ValidationDirective::Ignore)
+        ));
+    }
+
+    #[test]
+    fn build_k8s_secret_sets_metadata_and_data() {
+        let data = SecretData::from([(String::from("password"), String::from("aHVudGVyMg=="))]);
+        let secret = build_k8s_secret("db-creds", "prod", data, &SecretTemplate::default(), None);
+        assert_eq!(secret.metadata.name.as_deref(), Some("db-creds"));
+        assert_eq!(secret.metadata.namespace.as_deref(), Some("prod"));
+
+        let patch = serde_json::to_value(&secret).unwrap();
+        assert_eq!(patch["apiVersion"], "v1");
+        assert_eq!(patch["kind"], "Secret");
+        assert_eq!(patch["data"]["password"], "aHVudGVyMg==");
+    }
+
+    #[test]
+    fn build_k8s_secret_includes_labels() {
+        let args = args(&["--label", "managed-by=aws-sync", "--label", "env=prod"]);
+        let template = SecretTemplate::from_args(&args, Utc::now());
+        let secret = build_k8s_secret("db-creds", "prod", SecretData::default(), &template, None);
+
+        let patch = serde_json::to_value(&secret).unwrap();
+        assert_eq!(
+            patch["metadata"]["labels"],
+            serde_json::json!({
+                "managed-by": "aws-sync",
+                "env": "prod",
+                "k8s-aws-secrets-sync/managed": "true",
+                "k8s-aws-secrets-sync/owner": "k8s-aws-secrets-sync",
+            })
+        );
+    }
+
+    #[test]
+    fn build_k8s_secret_includes_annotations_sync_time_and_source_arn() {
+        let args = args(&["--annotation", "reloader.stakater.com/match=true"]);
+        let synced_at = DateTime::parse_from_rfc3339("2024-11-20T10:30:00Z").unwrap();
+        let template = SecretTemplate::from_args(&args, synced_at.with_timezone(&Utc));
+        let arn = "arn:aws:secretsmanager:eu-west-1:123456789012:secret:db-creds-AbCdEf";
+        let secret = build_k8s_secret(
+            "db-creds",
+            "prod",
+            SecretData::default(),
+            &template,
+            Some(arn),
+        );
+
+        let patch = serde_json::to_value(&secret).unwrap();
+        assert_eq!(
+            patch["metadata"]["annotations"],
+            serde_json::json!({
+                "reloader.stakater.com/match": "true",
+                "k8s-aws-secrets-sync/last-sync-time": "2024-11-20T10:30:00Z",
+                "k8s-aws-secrets-sync/synced-at": "2024-11-20T10:30:00Z",
+                "k8s-aws-secrets-sync/source-arn": arn,
+            })
+        );
+    }
+
+    #[test]
+    fn build_k8s_secret_sets_type() {
+        let args = args(&["--secret-type", "kubernetes.io/tls"]);
+        let template = SecretTemplate::from_args(&args, Utc::now());
+        let secret = build_k8s_secret("tls", "prod", SecretData::default(), &template, None);
+
+        let patch = serde_json::to_value(&secret).unwrap();
+        assert_eq!(patch["type"], "kubernetes.io/tls");
+    }
+
+    #[test]
+    fn is_unchanged_compares_the_immutable_flag() {
+        let data = SecretData::from([(String::from("password"), String::from("aHVudGVyMg=="))]);
+        let template = SecretTemplate::from_args(&args(&[]), Utc::now());
+        let k8s_secret = build_k8s_secret("db-creds", "prod", data.clone(), &template, None);
+        let field_manager = Some("k8s-aws-secrets-sync");
+        assert!(is_unchanged(&k8s_secret, &k8s_secret, field_manager));
+
+        let mut changed_data = k8s_secret.clone();
+        changed_data.data = None;
+        assert!(!is_unchanged(&changed_data, &k8s_secret, field_manager));
+
+        // a secret synced before `--immutable` was given is made immutable
+        let template = SecretTemplate::from_args(&args(&["--immutable"]), Utc::now());
+        let immutable_secret = build_k8s_secret("db-creds", "prod", data, &template, None);
+        assert!(!is_unchanged(&k8s_secret, &immutable_secret, field_manager));
+        assert!(is_unchanged(
+            &immutable_secret,
+            &immutable_secret,
+            field_manager
+        ));
+    }
+
+    #[test]
+    fn build_k8s_secret_sets_immutable() {
+        let template = SecretTemplate::from_args(&args(&["--immutable"]), Utc::now());
+        let secret = build_k8s_secret("db-creds", "prod", SecretData::default(), &template, None);
+        assert_eq!(serde_json::to_value(&secret).unwrap()["immutable"], true);
+
+        let template = SecretTemplate::from_args(&args(&[]), Utc::now());
+        let secret = build_k8s_secret("db-creds", "prod", SecretData::default(), &template, None);
+        assert!(serde_json::to_value(&secret)
+            .unwrap()
+            .get("immutable")
+            .is_none());
+    }
+
+    // writes a kubeconfig with the contexts `prod-eu` and `prod-us` to a temporary file
+    fn write_kubeconfig(name: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(name);
+        let kubeconfig = r#"
+apiVersion: v1
+kind: Config
+clusters:
+  - name: eu
+    cluster:
+      server: https://eu.example.com
+  - name: us
+    cluster:
+      server: https://us.example.com
+users:
+  - name: sync
+    user:
+      token: secret-token
+contexts:
+  - name: prod-eu
+    context:
+      cluster: eu
+      user: sync
+  - name: prod-us
+    context:
+      cluster: us
+      user: sync
+current-context: prod-eu
+"#;
+        std::fs::write(&path, kubeconfig).unwrap();
+        path
+    }
+
+    #[tokio::test]
+    async fn build_kube_clients_creates_one_client_per_context() {
+        let path = write_kubeconfig("k8s-aws-secrets-sync-contexts.kubeconfig");
+        let kubeconfig = path.to_str().unwrap();
+
+        let default = args(&["--kubeconfig", kubeconfig]);
+        assert_eq!(build_kube_clients(&default).await.unwrap().len(), 1);
+
+        let both = args(&[
+            "--kubeconfig",
+            kubeconfig,
+            "--kube-context",
+            "prod-eu",
+            "--kube-context",
+            "prod-us",
+        ]);
+        assert_eq!(build_kube_clients(&both).await.unwrap().len(), 2);
+
+        let unknown = args(&["--kubeconfig", kubeconfig, "--kube-context", "staging"]);
+        let result = build_kube_clients(&unknown).await;
+        assert!(matches!(result, Err(KubeClientError::Kubeconfig(_))));
+    }
+
+    #[test]
+    fn validate_k8s_name_accepts_dns_subdomains() {
+        assert_eq!(validate_k8s_name("db-creds"), Ok(()));
+        assert_eq!(validate_k8s_name("tls.example.com"), Ok(()));
+        assert_eq!(validate_k8s_name("0"), Ok(()));
+        assert_eq!(validate_k8s_name(&"a".repeat(MAX_NAME_LENGTH)), Ok(()));
+    }
+
+    #[test]
+    fn validate_k8s_name_rejects_invalid_names() {
+        assert_eq!(validate_k8s_name(""), Err(NameError::Empty));
+        assert!(matches!(
+            validate_k8s_name(&"a".repeat(MAX_NAME_LENGTH + 1)),
+            Err(NameError::TooLong { length: 254, .. })
+        ));
+        for name in [
+            "DB-creds",
+            "db_creds",
+            "-db-creds",
+            "db-creds-",
+            "db..creds",
+            "db/creds",
+        ] {
+            assert!(
+                matches!(
+                    validate_k8s_name(name),
+                    Err(NameError::InvalidCharacters { .. })
+                ),
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn validate_namespace_name_accepts_dns_labels_only() {
+        assert_eq!(validate_namespace_name("prod-eu"), Ok(()));
+        assert_eq!(
+            validate_namespace_name(&"a".repeat(MAX_NAMESPACE_LENGTH)),
+            Ok(())
+        );
+        assert!(matches!(
+            validate_namespace_name(&"a".repeat(MAX_NAMESPACE_LENGTH + 1)),
+            Err(NameError::TooLong { length: 64, .. })
+        ));
+        for name in ["prod.eu", "Prod", "-prod", "prod_eu"] {
+            assert!(
+                matches!(
+                    validate_namespace_name(name),
+                    Err(NameError::InvalidLabel { .. })
+                ),
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn only_rejected_immutable_fields_are_immutable_field_errors() {
+        let api_error = |code, message: &str| {
+            kube::Error::Api(kube::core::ErrorResponse {
+                status: String::from("Failure"),
+                message: String::from(message),
+                reason: String::from("Invalid"),
+                code,
+            })
+        };
+        let immutable = "Secret \"db-creds\" is invalid: type: Invalid value: \
+\"kubernetes.io/tls\": field is immutable";
+        assert!(is_immutable_field_error(&api_error(422, immutable)));
+        assert!(!is_immutable_field_error(&api_error(409, immutable)));
+        assert!(!is_immutable_field_error(&api_error(500, immutable)));
+
+        let invalid_key = "Secret \"db-creds\" is invalid: data[db creds]: Invalid value: \
+\"db creds\": a valid config key must consist of alphanumeric characters";
+        assert!(!is_immutable_field_error(&api_error(422, invalid_key)));
+    }
+}
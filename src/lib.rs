@@ -0,0 +1,1939 @@
+//! Downloads secrets from AWS Secrets Manager and uploads them to Kubernetes Secrets.
+//!
+//! The `k8s_aws_secrets_sync` binary is a thin wrapper around [`run`], intended to be run as
+//! a Kubernetes CronJob. The functions below can also be used to embed the sync elsewhere.
+
+use std::collections::{BTreeMap, HashSet};
+use std::future::Future;
+use std::process::{ExitCode, Termination};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use aws_sdk_secretsmanager::types::SecretListEntry;
+use chrono::{SecondsFormat, Utc};
+use k8s_openapi::api::core::v1::{ConfigMap, Secret};
+use kube::api::{Api, DynamicObject};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use tokio::time::MissedTickBehavior;
+use tracing::{debug, error, info, warn};
+
+use args::{
+    arg_matches, Args, Command, ErrorPolicy, LogFormat, Output, OutputMode, Source, VaultAuthMethod,
+};
+use audit::{AuditAction, AuditEvent, AuditLog};
+use aws::{
+    arn_without_region, build_list_filters, build_secretsmanager_clients, check_rotation,
+    filter_secrets_by_labels, get_parallelism_from_aws_secret, is_in_scope, is_rotating,
+    list_all_secrets, load_aws_config, log_caller_identity, preflight_check,
+    read_credentials_secret, secret_metadata, tag_last_sync, CredentialsError, PreflightError,
+    SecretVersion, TagError, TemplateError, ValidatedSecret,
+};
+use check::{check_secrets, format_table, CheckStatus};
+use config::ConfigError;
+use controller::{crd_yaml, run_controller, Context};
+use data::{
+    check_secret_size, create_datamap_from_base64_values, create_gzip_filesecret_from_aws_secret,
+    exclude_keys, handle_binary_secret, include_keys, missing_keys, remap_keys, secret_parser,
+    split_yaml_documents, ParseError,
+};
+use external_secret::{apply_external_secret, build_external_secret, external_secret_resource};
+use health::spawn_health_server;
+use helm::helm_values;
+use k8s::{
+    apply_config_map, apply_k8s_secret, build_config_map, build_kube_clients, build_patch_params,
+    delete_orphans, ensure_namespace, is_immutable_field_error, label_namespace, record_event,
+    recreate_secret, sync_event, verify_secret, Applied, KubeClientError, NamespaceLimiter,
+    SecretTemplate, ENCODING_ANNOTATION, GZIP_ENCODING,
+};
+use lock::{own_namespace, own_pod_name, Lock, LOCK_NAME};
+use metrics::Metrics;
+use pkcs12::{convert_pkcs12, Pkcs12Error, TLS_SECRET_TYPE};
+use plan::{plan_k8s_secret, PlanEntry};
+use progress::Progress;
+use report::{write_csv, ReportRow, SyncCounts, SyncReport};
+use retry::{retry_with_backoff, RetryPolicy};
+use schema::secret_schema;
+use script::{transform_with_script, ScriptError};
+use sealed_secret::{
+    apply_sealed_secret, fetch_certificate, seal_if_changed, seal_secret, SealError,
+};
+use ssm::{parameter_data, SsmClient, SsmError};
+use transform::{transform_secret, TransformError};
+use vault::{VaultClient, VaultError};
+
+pub mod args;
+pub mod audit;
+pub mod aws;
+pub mod check;
+pub mod config;
+pub mod controller;
+pub mod data;
+#[cfg(feature = "debug-dump")]
+pub mod debug_dump;
+pub mod external_secret;
+pub mod health;
+pub mod helm;
+pub mod k8s;
+pub mod lock;
+pub mod metrics;
+pub mod pkcs12;
+pub mod plan;
+pub mod progress;
+pub mod report;
+pub mod retry;
+pub mod schema;
+pub mod script;
+pub mod sealed_secret;
+pub mod ssm;
+pub mod transform;
+pub mod vault;
+
+pub use aws::{
+    get_filename_from_aws_secret, get_name_from_aws_secret, get_namespaces_from_aws_secret,
+    validate_secret_tags,
+};
+pub use data::{create_datamap_from_aws_secret, create_filesecret_from_aws_secret, SecretData};
+pub use k8s::build_k8s_secret;
+
+/// Errors raised while syncing AWS secrets to Kubernetes, for a single secret or the whole run
+#[derive(thiserror::Error, Debug)]
+pub enum SyncError {
+    /// The secret string could not be parsed
+    #[error("failed to parse secret string: {0}")]
+    Parse(#[from] ParseError),
+
+    /// A call to AWS Secrets Manager failed
+    #[error("AWS Secrets Manager error: {0}")]
+    Aws(Box<aws_sdk_secretsmanager::Error>),
+
+    /// The data of the Kubernetes secret is larger than `--max-secret-size-bytes`
+    #[error("secret data is {size} bytes, larger than the limit of {limit} bytes")]
+    TooLarge { size: usize, limit: usize },
+
+    /// The AWS secret has neither a secret string nor a secret binary
+    #[error("AWS secret has no value")]
+    EmptySecret,
+
+    /// The Kubernetes secret could not be serialised
+    #[error("failed to serialise Kubernetes secret: {0}")]
+    Json(#[from] serde_json::Error),
+
+    /// The manifest could not be serialised with `--output manifest`
+    #[error("failed to serialise manifest: {0}")]
+    Yaml(#[from] serde_yaml::Error),
+
+    /// The values could not be transformed with `--transform-script`
+    #[error(transparent)]
+    Script(#[from] ScriptError),
+
+    /// A call to SSM Parameter Store failed
+    #[error("SSM error: {0}")]
+    Ssm(#[from] SsmError),
+
+    /// The PKCS#12 bundle could not be converted with `--parse-pkcs12`
+    #[error("{0}")]
+    Pkcs12(#[from] Pkcs12Error),
+
+    /// The values could not be transformed with `--transform-command`
+    #[error("{0}")]
+    Transform(#[from] TransformError),
+
+    /// The secret could not be sealed with `--output-mode sealed-secret`
+    #[error("failed to seal secret: {0}")]
+    Seal(#[from] SealError),
+
+    /// A call to HashiCorp Vault failed
+    #[error("Vault error: {0}")]
+    Vault(#[from] VaultError),
+
+    /// A call to the Kubernetes API failed
+    #[error("Kubernetes error: {0}")]
+    Kubernetes(#[from] kube::Error),
+
+    /// The Kubernetes client could not be configured
+    #[error("failed to configure Kubernetes client: {0}")]
+    KubeClient(#[from] KubeClientError),
+
+    /// The AWS secret does not carry a tag that is required to sync it
+    #[error("AWS secret {secret} is missing required tag `{tag}`")]
+    TagMissing { secret: String, tag: String },
+
+    /// A name computed from the tags is not a valid Kubernetes name
+    #[error("invalid name: {0}")]
+    InvalidName(String),
+
+    /// The spec of an `AwsSecretSync` resource is not a valid set of arguments
+    #[error("invalid spec of AwsSecretSync {name}: {message}")]
+    Spec { name: String, message: String },
+
+    /// The arguments or the config file are invalid
+    #[error("{0}")]
+    Config(#[from] ConfigError),
+
+    /// The AWS credentials can't be read from `--aws-credentials-secret`
+    #[error("{0}")]
+    Credentials(#[from] CredentialsError),
+
+    /// The secrets can't be read with `--preflight-check`
+    #[error("preflight check failed: {0}")]
+    Preflight(#[from] PreflightError),
+
+    /// A local file, socket or signal handler failed
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+
+    /// A secret failed with `--on-aws-error abort` or `--on-k8s-error abort`
+    #[error("sync aborted: {0}")]
+    Aborted(String),
+}
+
+impl SyncError {
+    /// What `args` say to do about the error: `--on-k8s-error` for the errors of the
+    /// Kubernetes API, `--on-aws-error` for those of reading and converting the secret
+    pub fn policy(&self, args: &Args) -> ErrorPolicy {
+        match self {
+            SyncError::Kubernetes(_) | SyncError::KubeClient(_) | SyncError::Seal(_) => {
+                args.on_k8s_error
+            }
+            _ => args.on_aws_error,
+        }
+    }
+}
+
+// the retries of the calls whose errors are handled with `on_error`; only `retry` retries them
+pub(crate) fn retry_policy(args: &Args, on_error: ErrorPolicy) -> RetryPolicy {
+    RetryPolicy {
+        max_retries: match on_error {
+            ErrorPolicy::Retry => args.max_retries,
+            ErrorPolicy::Skip | ErrorPolicy::Abort => 0,
+        },
+        base_delay: Duration::from_millis(args.retry_base_ms),
+    }
+}
+
+impl From<aws_sdk_secretsmanager::Error> for SyncError {
+    fn from(e: aws_sdk_secretsmanager::Error) -> Self {
+        SyncError::Aws(Box::new(e))
+    }
+}
+
+impl From<TagError> for SyncError {
+    fn from(e: TagError) -> Self {
+        match e {
+            TagError::Missing { arn, tag } => SyncError::TagMissing { secret: arn, tag },
+            TagError::Template {
+                arn,
+                source: TemplateError::MissingTag(tag),
+            } => SyncError::TagMissing { secret: arn, tag },
+            e => SyncError::InvalidName(e.to_string()),
+        }
+    }
+}
+
+// an error ending the run is printed in full, rather than as its debug representation
+impl Termination for SyncError {
+    fn report(self) -> ExitCode {
+        eprintln!("Error: {}", self);
+        ExitCode::FAILURE
+    }
+}
+
+/// The exit code of a single sync stopped by SIGTERM
+pub const EXIT_CODE_SHUTDOWN: u8 = 130;
+
+/// The client of the secret store a listed secret was read from
+#[derive(Clone)]
+pub enum SecretSource {
+    /// AWS Secrets Manager, in the region of the client
+    Aws(aws_sdk_secretsmanager::Client),
+    /// The KV v2 secrets engine of HashiCorp Vault
+    Vault(VaultClient),
+}
+
+/// The number of Kubernetes secrets synced and left unchanged for an AWS secret, per namespace
+#[derive(Clone, Debug, Default)]
+pub struct SyncOutcome {
+    pub namespaces: BTreeMap<String, SyncCounts>,
+    /// What would change in each namespace and cluster with `--plan`
+    pub plan: Vec<PlanEntry>,
+}
+
+/// The result of syncing every AWS secret in a run
+#[derive(Debug, Default)]
+pub struct RunSummary {
+    pub report: SyncReport,
+    /// The errors of the AWS secrets that failed to sync
+    pub errors: Vec<String>,
+    /// The `(namespace, name)` of every Kubernetes secret that was part of the run
+    pub synced: HashSet<(String, String)>,
+    /// Whether SIGTERM was received before every secret was synced
+    pub shutdown_requested: bool,
+    /// The number of AWS secrets left unsynced because of the shutdown
+    pub interrupted: usize,
+    /// The result of every Kubernetes secret, and of every skipped AWS secret, for
+    /// `--report-file`
+    pub rows: Vec<ReportRow>,
+    /// Whether a secret failed with an error policy of `abort`, or too many failed in a row
+    /// for `--max-errors-before-abort`, stopping the others
+    pub aborted: bool,
+    /// What would change in Kubernetes with `--plan`
+    pub plan: Vec<PlanEntry>,
+}
+
+impl RunSummary {
+    pub fn had_errors(&self) -> bool {
+        !self.errors.is_empty() || self.report.skipped > 0
+    }
+
+    /// Fails the run if any secret failed to sync or was skipped, with 130 if the run was
+    /// stopped by SIGTERM
+    pub fn exit_code(&self) -> ExitCode {
+        if self.shutdown_requested {
+            ExitCode::from(EXIT_CODE_SHUTDOWN)
+        } else if self.had_errors() {
+            ExitCode::FAILURE
+        } else {
+            ExitCode::SUCCESS
+        }
+    }
+}
+
+/// Syncs every AWS secret selected by `args` to Kubernetes, once or repeatedly with `--watch`
+///
+/// A single sync returns a failing exit code when any secret could not be synced. In watch
+/// mode failed syncs are logged and retried on the next interval, and a successful exit code
+/// is returned once SIGINT or SIGTERM is received. A single sync stops starting new secrets
+/// on SIGTERM, finishes those in progress and exits with 130.
+pub async fn run(args: Args) -> Result<ExitCode, SyncError> {
+    let args = Arc::new(args);
+
+    if let Some(Command::GenerateHelmValues { schedule }) = &args.command {
+        let argv: Vec<String> = std::env::args_os()
+            .skip(1)
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect();
+        let values = helm_values(&argv, |name| std::env::var(name).ok(), schedule);
+        print!("{}", serde_yaml::to_string(&values)?);
+        return Ok(ExitCode::SUCCESS);
+    }
+    if args.command == Some(Command::GenerateCrd) {
+        print!("{}", crd_yaml()?);
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    let ready = Arc::new(AtomicBool::new(false));
+    if let Some(port) = args.health_check_port {
+        spawn_health_server(port, ready.clone()).await?;
+    }
+
+    let mut ssm = None;
+    let sources = match args.source {
+        Source::Aws => {
+            // set credentials for AWS
+            let credentials = match &args.aws_credentials_secret {
+                Some((namespace, name)) => {
+                    // the secret is read from the first cluster
+                    let clients = build_kube_clients(&args).await?;
+                    Some(read_credentials_secret(&clients[0], namespace, name).await?)
+                }
+                None => None,
+            };
+            let config = load_aws_config(&args, credentials).await;
+            log_caller_identity(&config).await;
+            if args.ssm_parameter_prefix.is_some() {
+                ssm = Some(SsmClient::from_config(&config, args.aws_partition)?);
+            }
+            build_secretsmanager_clients(&config, &args)
+                .into_iter()
+                .map(SecretSource::Aws)
+                .collect()
+        }
+        Source::Vault => {
+            if args.ssm_parameter_prefix.is_some() {
+                warn!("--ssm-parameter-prefix is ignored with --source vault");
+            }
+            let vault = match args.vault_auth_method {
+                VaultAuthMethod::Token => VaultClient::from_env(&args.vault_mount)?,
+                VaultAuthMethod::Kubernetes => {
+                    VaultClient::login_kubernetes(
+                        &args.vault_mount,
+                        &args.vault_auth_mount,
+                        args.vault_role.as_deref().unwrap_or_default(),
+                    )
+                    .await?
+                }
+            };
+            vec![SecretSource::Vault(vault)]
+        }
+    };
+
+    if let Some(Command::GenerateSchema {
+        secret_name,
+        output_file,
+    }) = &args.command
+    {
+        // the secret is read from the first region
+        let secret = SecretListEntry::builder().name(secret_name).build();
+        let retry_policy = retry_policy(&args, args.on_aws_error);
+        let secret_value = match read_secret_value(&secret, &args, &sources[0], &retry_policy)
+            .await?
+        {
+            SecretValue::Parsed(secret_value) | SecretValue::Binary(secret_value) => secret_value,
+        };
+        let schema = format!("{:#}\n", secret_schema(secret_name, &secret_value));
+        match output_file {
+            Some(path) => tokio::fs::write(path, schema).await?,
+            None => print!("{}", schema),
+        }
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    let (audit, audit_writer) =
+        AuditLog::open(args.audit_log.as_deref(), args.audit_syslog).await?;
+
+    if args.command == Some(Command::Controller) {
+        ready.store(true, Ordering::SeqCst);
+        let context = Context {
+            sources,
+            ssm,
+            kube_clients: build_kube_clients(&args).await?,
+            audit,
+            fallback: arg_matches()?,
+        };
+        let result = run_controller(context).await;
+        audit_writer.close().await;
+        return result;
+    }
+
+    if args.check {
+        let secrets = list_secrets(&args, &sources, &audit).await?;
+        drop(audit);
+        audit_writer.close().await;
+        let checks = check_secrets(&args, &secrets).await;
+        print!("{}", format_table(&checks));
+        let failed = checks
+            .iter()
+            .any(|check| check.status() == CheckStatus::Fail);
+        return Ok(if failed {
+            ExitCode::FAILURE
+        } else {
+            ExitCode::SUCCESS
+        });
+    }
+
+    // no Kubernetes clients are needed when only printing the patches or manifests, unless
+    // the manifests are sealed with the certificate of the first cluster
+    let kube_clients = if args.dry_run
+        || (args.output == Output::Manifest && args.output_mode != OutputMode::SealedSecret)
+    {
+        Vec::new()
+    } else {
+        build_kube_clients(&args).await?
+    };
+
+    // the lock is taken in the first cluster only
+    let lock = match kube_clients.first() {
+        Some(client) if args.leader_lock => {
+            let ttl = chrono::Duration::seconds(i64::from(args.lock_ttl_seconds));
+            match Lock::acquire(client, &own_namespace(), &own_pod_name(), ttl).await? {
+                Some(lock) => Some(lock),
+                None if args.lock_fail => return Ok(ExitCode::FAILURE),
+                None => return Ok(ExitCode::SUCCESS),
+            }
+        }
+        _ => None,
+    };
+    let result = match args.watch_interval() {
+        Some(interval) => {
+            watch(
+                &args,
+                &sources,
+                ssm.as_ref(),
+                &kube_clients,
+                interval,
+                lock.as_ref(),
+                &ready,
+                &audit,
+            )
+            .await
+        }
+        None => {
+            let shutdown = sigterm_flag()?;
+            sync_once(
+                &args,
+                &sources,
+                ssm.as_ref(),
+                &kube_clients,
+                &shutdown,
+                &audit,
+            )
+            .await
+            .map(|summary| summary.exit_code())
+        }
+    };
+    if let Some(lock) = lock {
+        if let Err(e) = lock.release().await {
+            error!("Failed to release lock: {}", e);
+        }
+    }
+    drop(audit);
+    audit_writer.close().await;
+    result
+}
+
+// syncs every `interval` until a shutdown signal arrives; a sync in progress is always finished,
+// and `ready` is set once the first one is; the `--leader-lock` is renewed before each sync
+#[allow(clippy::too_many_arguments)]
+async fn watch(
+    args: &Arc<Args>,
+    sources: &[SecretSource],
+    ssm: Option<&SsmClient>,
+    kube_clients: &[kube::Client],
+    interval: Duration,
+    lock: Option<&Lock>,
+    ready: &AtomicBool,
+    audit: &AuditLog,
+) -> Result<ExitCode, SyncError> {
+    let mut shutdown = std::pin::pin!(shutdown_signal()?);
+    // the signal is handled between syncs, so a sync is never stopped part way
+    let never = Arc::new(AtomicBool::new(false));
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            biased;
+            _ = &mut shutdown => {
+                info!("Received shutdown signal, exiting");
+                return Ok(ExitCode::SUCCESS);
+            }
+            _ = ticker.tick() => {}
+        }
+        // the lock would otherwise expire after `--lock-ttl-seconds` and let another pod sync
+        // alongside this one
+        if let Some(lock) = lock {
+            let ttl = chrono::Duration::seconds(i64::from(args.lock_ttl_seconds));
+            match lock.renew(ttl).await {
+                Ok(true) => {}
+                Ok(false) => {
+                    error!("Lock {} was taken over by another pod, exiting", LOCK_NAME);
+                    return Ok(if args.lock_fail {
+                        ExitCode::FAILURE
+                    } else {
+                        ExitCode::SUCCESS
+                    });
+                }
+                Err(e) => {
+                    error!("Failed to renew lock, skipping this sync: {}", e);
+                    continue;
+                }
+            }
+        }
+        match sync_once(args, sources, ssm, kube_clients, &never, audit).await {
+            Err(e @ SyncError::Aborted(_)) => return Err(e),
+            Err(e) => error!("Sync failed: {}", e),
+            Ok(_) => {}
+        }
+        ready.store(true, Ordering::SeqCst);
+    }
+}
+
+// resolves once the process receives SIGINT or SIGTERM
+fn shutdown_signal() -> std::io::Result<impl Future<Output = ()>> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    Ok(async move {
+        tokio::select! {
+            _ = terminate.recv() => {}
+            _ = interrupt.recv() => {}
+        }
+    })
+}
+
+// a flag set once the process receives SIGTERM
+fn sigterm_flag() -> std::io::Result<Arc<AtomicBool>> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    let flag = Arc::new(AtomicBool::new(false));
+    let set = flag.clone();
+    tokio::spawn(async move {
+        if terminate.recv().await.is_some() {
+            warn!("Received SIGTERM, finishing the secrets in progress");
+            set.store(true, Ordering::SeqCst);
+        }
+    });
+    Ok(flag)
+}
+
+// lists the AWS secrets selected by `args` and syncs each of them to Kubernetes, along with
+// the SSM parameters when `ssm` is given, until `shutdown` is set
+async fn sync_once(
+    args: &Arc<Args>,
+    sources: &[SecretSource],
+    ssm: Option<&SsmClient>,
+    kube_clients: &[kube::Client],
+    shutdown: &Arc<AtomicBool>,
+    audit: &AuditLog,
+) -> Result<RunSummary, SyncError> {
+    let secrets = list_secrets(args, sources, audit).await?;
+    if args.preflight_check {
+        match secrets.first() {
+            Some((SecretSource::Aws(client), secret)) => {
+                preflight_check(client, secret, SecretVersion::from_args(args)).await?
+            }
+            Some((SecretSource::Vault(_), _)) => {}
+            None => warn!("Preflight check found no AWS secrets to read"),
+        }
+    }
+
+    // for each secret, get the secret value and upload it to Kubernetes
+    // the secret name in Kubernetes is the value of the tag with key `/fhm/k8s/secret-name`
+    // the namespace in Kubernetes is the value of the tag with key `/fhm/k8s/namespace`
+    let metrics = Metrics::new();
+    let mut summary = sync_secrets(args, secrets, kube_clients, &metrics, shutdown, audit).await;
+    if summary.aborted {
+        // orphans can't be told apart from the secrets that weren't synced either
+        return Err(SyncError::Aborted(summary.errors.join("; ")));
+    }
+    if summary.shutdown_requested {
+        // orphans can't be told apart from the secrets that weren't synced
+        warn!(
+            "{} secret(s) not synced due to shutdown",
+            summary.interrupted
+        );
+    } else if args.plan {
+        // the plan only covers the secrets
+        summary.plan.sort_by(|a, b| {
+            (&a.k8s_namespace, &a.k8s_secret, &a.aws_secret).cmp(&(
+                &b.k8s_namespace,
+                &b.k8s_secret,
+                &b.aws_secret,
+            ))
+        });
+        println!("{}", serde_json::to_string_pretty(&summary.plan)?);
+    } else if let (Some(ssm), Some(prefix)) = (ssm, &args.ssm_parameter_prefix) {
+        if let Err(e) = sync_ssm_parameters(args, ssm, prefix, kube_clients).await {
+            summary
+                .errors
+                .push(format!("SSM parameters below {}: {}", prefix, e));
+        }
+    }
+
+    // only a run applying the secrets knows which are orphaned; the clients built to seal
+    // the printed manifests must not delete anything
+    let applied = args.output == Output::Apply && !args.dry_run && !args.plan;
+    if args.delete_orphans && applied && !summary.shutdown_requested {
+        for client in kube_clients {
+            delete_orphans(client, &summary.synced, args).await?;
+        }
+    }
+    if let Some(path) = &args.metrics_file {
+        metrics.write_to(path)?;
+    }
+    if let Some(path) = &args.report_file {
+        write_csv(path, &summary.rows)?;
+    }
+    // in JSON mode the report is a single line of its own, so it can be parsed as is, unless
+    // stdout is reserved for the manifests or the plan
+    match (args.log_format, args.output) {
+        (LogFormat::Json, Output::Apply) if !args.plan => {
+            println!("{}", serde_json::to_string(&summary.report)?)
+        }
+        _ => info!("{}", summary.report),
+    }
+    if !summary.errors.is_empty() {
+        error!("{} secret(s) failed to sync:", summary.errors.len());
+        for e in &summary.errors {
+            error!("  {}", e);
+        }
+    }
+    if summary.report.skipped > 0 {
+        warn!(
+            "{} secret(s) skipped due to missing or invalid tags",
+            summary.report.skipped
+        );
+    }
+    Ok(summary)
+}
+
+// lists the secrets selected by `args` in every source, keeping the first region's copy of
+// replicated AWS secrets
+async fn list_secrets(
+    args: &Args,
+    sources: &[SecretSource],
+    audit: &AuditLog,
+) -> Result<Vec<(SecretSource, SecretListEntry)>, SyncError> {
+    let filters = build_list_filters(args);
+    let aws_retry_policy = retry_policy(args, args.on_aws_error);
+
+    let mut secrets = Vec::new();
+    let mut seen_arns = HashSet::new();
+    for source in sources {
+        let listed: Result<_, SyncError> = match source {
+            SecretSource::Aws(client) => {
+                list_all_secrets(client, filters.clone(), &aws_retry_policy)
+                    .await
+                    .map_err(Into::into)
+            }
+            SecretSource::Vault(vault) => vault
+                .list_tagged_secrets(&args.vault_path_prefix, &args.namespace_tag)
+                .await
+                .map_err(Into::into),
+        };
+        let event = AuditEvent::new(AuditAction::List, None, Utc::now());
+        audit.record(match &listed {
+            Ok(_) => event,
+            Err(e) => event.result("error", Some(e.to_string())),
+        });
+        let listed = listed?;
+        for secret in filter_secrets_by_labels(listed, &args.required_labels) {
+            let is_new = match secret.arn.as_deref() {
+                Some(arn) => seen_arns.insert(arn_without_region(arn)),
+                None => true,
+            };
+            if is_new && is_in_scope(&secret, args) {
+                secrets.push((source.clone(), secret));
+            }
+        }
+    }
+    debug!("Number of secrets retrieved: {}", secrets.len());
+    Ok(secrets)
+}
+
+// validates the tags of every AWS secret and syncs the valid ones concurrently; once
+// `shutdown` is set, the secrets in progress are finished and the others are left unsynced
+pub async fn sync_secrets(
+    args: &Arc<Args>,
+    secrets: Vec<(SecretSource, SecretListEntry)>,
+    kube_clients: &[kube::Client],
+    metrics: &Metrics,
+    shutdown: &Arc<AtomicBool>,
+    audit: &AuditLog,
+) -> RunSummary {
+    let kube_clients: Arc<[kube::Client]> = kube_clients.into();
+    let semaphore = Arc::new(Semaphore::new(args.concurrency));
+    let limiter = Arc::new(NamespaceLimiter::new(args.k8s_concurrency));
+    let template = Arc::new(SecretTemplate::from_args(args, Utc::now()));
+    let mut summary = RunSummary::default();
+    summary.report.secrets_found = secrets.len();
+    let mut progress = Progress::new(secrets.len());
+    let mut tasks = JoinSet::new();
+    for (source, secret) in secrets {
+        if shutdown.load(Ordering::SeqCst) {
+            progress.tick(secret.name.as_deref().unwrap_or_default());
+            summary.interrupted += 1;
+            continue;
+        }
+        let mut row = ReportRow {
+            timestamp: Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+            aws_secret_name: secret.name.clone().unwrap_or_default(),
+            aws_secret_arn: secret.arn.clone().unwrap_or_default(),
+            ..ReportRow::default()
+        };
+        let validated = match validate_secret_tags(&secret, args) {
+            Ok(validated) => validated,
+            Err(e) => {
+                warn!("Skipping secret: {}", e);
+                progress.tick(&row.aws_secret_name);
+                summary
+                    .rows
+                    .push(row.with_result("", "skipped", Some(&e.to_string())));
+                audit.record(
+                    AuditEvent::new(AuditAction::Skip, secret.name.as_deref(), Utc::now())
+                        .result("skipped", Some(e.to_string())),
+                );
+                metrics.observe_error();
+                summary.report.skipped += 1;
+                continue;
+            }
+        };
+        row.k8s_secret_name = validated.secret_name.clone();
+        for namespace in &validated.namespaces {
+            summary
+                .synced
+                .insert((namespace.clone(), validated.secret_name.clone()));
+        }
+        // the secret stays in `synced`, so it isn't deleted as an orphan
+        let grace = chrono::Duration::minutes(i64::from(args.rotation_grace_minutes));
+        if args.skip_rotating_secrets && is_rotating(&secret, Utc::now(), grace) {
+            warn!(
+                "Skipping secret {} as it was rotated less than {} minute(s) ago",
+                secret.name.as_deref().unwrap_or_default(),
+                args.rotation_grace_minutes
+            );
+            progress.tick(&row.aws_secret_name);
+            audit.record(
+                AuditEvent::new(AuditAction::Skip, secret.name.as_deref(), Utc::now())
+                    .result("skipped", Some(String::from("rotated recently"))),
+            );
+            for namespace in &validated.namespaces {
+                summary
+                    .rows
+                    .push(row.with_result(namespace, "skipped", Some("rotated recently")));
+            }
+            continue;
+        }
+        let weight = args.parallelism_tag.as_deref().map_or(1, |tag| {
+            get_parallelism_from_aws_secret(&secret, tag, args.concurrency)
+        });
+        let args = args.clone();
+        let template = template.clone();
+        let semaphore = semaphore.clone();
+        let limiter = limiter.clone();
+        let kube_clients = kube_clients.clone();
+        let shutdown = shutdown.clone();
+        let audit = audit.clone();
+        tasks.spawn(async move {
+            let _permit = semaphore.acquire_many_owned(weight).await.unwrap();
+            let namespaces = validated.namespaces.clone();
+            if shutdown.load(Ordering::SeqCst) {
+                return (row, namespaces, None, Duration::ZERO);
+            }
+            let started = Instant::now();
+            let result = sync_secret(
+                secret,
+                validated,
+                &args,
+                &template,
+                &source,
+                &kube_clients,
+                &limiter,
+                &audit,
+            )
+            .await;
+            (row, namespaces, Some(result), started.elapsed())
+        });
+    }
+
+    // the secrets that failed since the last one that synced, for `--max-errors-before-abort`
+    let mut consecutive_errors = 0;
+    let mut processed = 0;
+    while let Some(result) = tasks.join_next().await {
+        processed += 1;
+        progress.tick(
+            result
+                .as_ref()
+                .map_or("", |(row, ..)| row.aws_secret_name.as_str()),
+        );
+        match result {
+            Ok((row, namespaces, None, _)) => {
+                debug!("Not syncing {} due to shutdown", row.aws_secret_name);
+                summary.interrupted += 1;
+                for namespace in &namespaces {
+                    summary
+                        .rows
+                        .push(row.with_result(namespace, "interrupted", None));
+                }
+            }
+            Ok((row, _, Some((outcome, Ok(()))), duration)) => {
+                consecutive_errors = 0;
+                metrics.observe(true, duration);
+                summary.plan.extend(outcome.plan);
+                for (namespace, counts) in outcome.namespaces {
+                    summary
+                        .rows
+                        .push(row.with_result(&namespace, counts.result(), None));
+                    summary.report.record(&namespace, counts);
+                }
+            }
+            Ok((row, namespaces, Some((outcome, Err(e))), duration)) => {
+                consecutive_errors += 1;
+                metrics.observe(false, duration);
+                summary
+                    .errors
+                    .push(format!("{}: {}", row.aws_secret_name, e));
+                summary.plan.extend(outcome.plan);
+                // the error may have happened before reaching some or all of the namespaces,
+                // which count as errors, while those synced before it keep their result
+                let errored = SyncCounts {
+                    errors: 1,
+                    ..SyncCounts::default()
+                };
+                let message = e.to_string();
+                for namespace in &namespaces {
+                    let counts = outcome
+                        .namespaces
+                        .get(namespace)
+                        .copied()
+                        .unwrap_or(errored);
+                    let message = (counts.errors > 0).then_some(message.as_str());
+                    summary
+                        .rows
+                        .push(row.with_result(namespace, counts.result(), message));
+                    summary.report.record(namespace, counts);
+                }
+                if e.policy(args) == ErrorPolicy::Abort && !summary.aborted {
+                    error!("Aborting the sync after {}: {}", row.aws_secret_name, e);
+                    summary.aborted = true;
+                    tasks.abort_all();
+                }
+            }
+            // the secrets stopped by the abort are left unsynced
+            Err(e) if e.is_cancelled() => summary.interrupted += 1,
+            Err(e) => {
+                consecutive_errors += 1;
+                metrics.observe_error();
+                summary.errors.push(format!("sync task failed: {}", e));
+                summary.report.total.errors += 1;
+            }
+        }
+        if args.max_errors_before_abort > 0
+            && consecutive_errors >= args.max_errors_before_abort
+            && !summary.aborted
+        {
+            error!(
+                "Aborting the sync after {} consecutive errors, with {} of {} secret(s) processed",
+                consecutive_errors, processed, summary.report.secrets_found
+            );
+            summary.aborted = true;
+            tasks.abort_all();
+        }
+    }
+    progress.finish();
+    summary.shutdown_requested = shutdown.load(Ordering::SeqCst);
+    summary
+}
+
+// gets the value of the AWS secret and uploads it to every namespace it is tagged with,
+// in every cluster of `kube_clients`, with at most `--k8s-concurrency` patches in flight to
+// each namespace; the outcome of the namespaces is returned along with the error of any
+// that failed
+#[allow(clippy::too_many_arguments)]
+pub async fn sync_secret(
+    secret: SecretListEntry,
+    validated: ValidatedSecret,
+    args: &Args,
+    template: &SecretTemplate,
+    source: &SecretSource,
+    kube_clients: &[kube::Client],
+    limiter: &NamespaceLimiter,
+    audit: &AuditLog,
+) -> (SyncOutcome, Result<(), SyncError>) {
+    let mut outcome = SyncOutcome::default();
+    let result = sync_secret_into(
+        secret,
+        validated,
+        args,
+        template,
+        source,
+        kube_clients,
+        limiter,
+        audit,
+        &mut outcome,
+    )
+    .await;
+    (outcome, result)
+}
+
+// syncs the AWS secret as `sync_secret` does, counting the result of each namespace in
+// `outcome` as it goes
+#[allow(clippy::too_many_arguments)]
+async fn sync_secret_into(
+    secret: SecretListEntry,
+    validated: ValidatedSecret,
+    args: &Args,
+    template: &SecretTemplate,
+    source: &SecretSource,
+    kube_clients: &[kube::Client],
+    limiter: &NamespaceLimiter,
+    audit: &AuditLog,
+    outcome: &mut SyncOutcome,
+) -> Result<(), SyncError> {
+    let aws_secret_name = secret.name.as_deref().unwrap();
+    info!(aws_secret_name, "AWS Secret Name: {}", aws_secret_name);
+    check_rotation(&secret, args, Utc::now());
+    let ValidatedSecret {
+        secret_name,
+        namespaces,
+        filenames,
+        secret_type,
+        namespace_labels,
+    } = validated;
+    // the secret type tag overrides `--secret-type` for this secret only, and the bundles
+    // converted with `--parse-pkcs12` are TLS secrets unless tagged otherwise
+    let secret_type =
+        secret_type.or_else(|| args.parse_pkcs12.then(|| String::from(TLS_SECRET_TYPE)));
+    let tagged_template;
+    let template = match secret_type {
+        Some(secret_type) => {
+            tagged_template = SecretTemplate {
+                secret_type: Some(secret_type),
+                ..template.clone()
+            };
+            &tagged_template
+        }
+        None => template,
+    };
+
+    // the files gzipped with `--compress-file-secret` are annotated for their consumers
+    let compressed_template;
+    let template = if args.compress_file_secret
+        && !filenames.is_empty()
+        && args.output_mode != OutputMode::ExternalSecret
+    {
+        let mut custom = template.clone();
+        custom.annotations.insert(
+            String::from(ENCODING_ANNOTATION),
+            String::from(GZIP_ENCODING),
+        );
+        compressed_template = custom;
+        &compressed_template
+    } else {
+        template
+    };
+
+    // the External Secrets Operator fetches the value itself
+    let data_map = match args.output_mode {
+        OutputMode::Secret | OutputMode::SealedSecret => {
+            let aws_retry_policy = retry_policy(args, args.on_aws_error);
+            let data_map =
+                fetch_data_map(&secret, filenames, args, source, &aws_retry_policy).await;
+            let event = AuditEvent::new(AuditAction::Fetch, Some(aws_secret_name), Utc::now());
+            audit.record(match &data_map {
+                Ok(_) => event,
+                Err(e) => event.result("error", Some(e.to_string())),
+            });
+            let data_map = data_map?;
+            if let Some(limit) = args.max_secret_size_bytes {
+                if !check_secret_size(&data_map, limit) {
+                    let size = data_map.byte_size();
+                    warn!(
+                        aws_secret_name,
+                        "Skipping secret of {} bytes, larger than --max-secret-size-bytes {}",
+                        size,
+                        limit
+                    );
+                    audit.record(
+                        AuditEvent::new(AuditAction::Skip, Some(aws_secret_name), Utc::now())
+                            .result("skipped", Some(format!("{} bytes is too large", size))),
+                    );
+                    return Err(SyncError::TooLarge { size, limit });
+                }
+            }
+            Some(data_map)
+        }
+        OutputMode::ExternalSecret => None,
+    };
+    let remote_key = secret.arn.as_deref().unwrap_or(aws_secret_name);
+
+    let metadata = args
+        .inject_metadata
+        .then(|| secret_metadata(&secret, &args.metadata_key_prefix));
+
+    let params = build_patch_params(args);
+    let k8s_retry_policy = retry_policy(args, args.on_k8s_error);
+    let mut result = Ok(());
+    for namespace in namespaces {
+        let manifest = match &data_map {
+            Some(data_map) => {
+                let mut k8s_secret = build_k8s_secret(
+                    &secret_name,
+                    &namespace,
+                    data_map.clone(),
+                    template,
+                    secret.arn.as_deref(),
+                );
+                k8s_secret.string_data = metadata.clone();
+                match args.output_mode {
+                    OutputMode::SealedSecret => Manifest::SealedSecret(k8s_secret),
+                    _ => Manifest::Secret(k8s_secret),
+                }
+            }
+            None => Manifest::ExternalSecret(build_external_secret(
+                &secret_name,
+                &namespace,
+                remote_key,
+                args,
+                template,
+            )),
+        };
+        let patch = build_patch(&manifest)?;
+        debug!(
+            aws_secret_name,
+            k8s_namespace = namespace,
+            k8s_secret_name = secret_name,
+            "patch: {}",
+            patch
+        );
+
+        #[cfg(feature = "debug-dump")]
+        if let (Some(dir), Some(data_map)) = (&args.debug_dump_dir, &data_map) {
+            match debug_dump::dump_secret(dir, &namespace, &secret_name, data_map) {
+                Ok(path) => warn!(aws_secret_name, "Wrote secret values to {}", path.display()),
+                Err(e) => warn!(aws_secret_name, "Failed to dump secret values: {}", e),
+            }
+        }
+
+        if args.output == Output::Manifest {
+            print!("{}", manifest.to_yaml(args, kube_clients).await?);
+            continue;
+        }
+        if args.dry_run {
+            info!(
+                aws_secret_name,
+                k8s_namespace = namespace,
+                k8s_secret_name = secret_name,
+                "[dry-run] would patch secret {}/{}",
+                namespace,
+                secret_name
+            );
+            println!("{:#}", patch);
+            continue;
+        }
+        if args.plan {
+            if let Manifest::Secret(k8s_secret) = &manifest {
+                for client in kube_clients {
+                    let secrets: Api<Secret> = Api::namespaced(client.clone(), &namespace);
+                    let (action, changed_keys) = retry_with_backoff(&k8s_retry_policy, || {
+                        plan_k8s_secret(&secrets, k8s_secret)
+                    })
+                    .await?;
+                    outcome.plan.push(PlanEntry {
+                        aws_secret: String::from(aws_secret_name),
+                        k8s_namespace: namespace.clone(),
+                        k8s_secret: secret_name.clone(),
+                        action,
+                        changed_keys,
+                    });
+                }
+            }
+            continue;
+        }
+
+        // apply the patch to every cluster
+        for client in kube_clients {
+            let _permit = limiter.acquire(&namespace).await;
+            // each cluster has its own controller, so the secret is sealed for each of them
+            let sealed_secret = match &manifest {
+                Manifest::SealedSecret(k8s_secret) => {
+                    let sealed = retry_with_backoff(&k8s_retry_policy, || {
+                        seal_if_changed(
+                            client,
+                            &args.sealed_secrets_controller,
+                            &args.kubeseal_path,
+                            k8s_secret,
+                        )
+                    })
+                    .await;
+                    match sealed {
+                        Ok(sealed) => sealed,
+                        Err(e) => {
+                            error!(
+                                aws_secret_name,
+                                k8s_namespace = namespace,
+                                k8s_secret_name = secret_name,
+                                "Error sealing secret {}/{}: {}",
+                                namespace,
+                                secret_name,
+                                e
+                            );
+                            audit.record(
+                                AuditEvent::new(
+                                    AuditAction::Patch,
+                                    Some(aws_secret_name),
+                                    Utc::now(),
+                                )
+                                .k8s(&namespace, &secret_name)
+                                .result("error", Some(e.to_string())),
+                            );
+                            outcome
+                                .namespaces
+                                .entry(namespace.clone())
+                                .or_default()
+                                .errors += 1;
+                            if args.on_k8s_error == ErrorPolicy::Abort {
+                                return Err(SyncError::Seal(e));
+                            }
+                            result = Err(SyncError::Seal(e));
+                            continue;
+                        }
+                    }
+                }
+                Manifest::Secret(_) | Manifest::ExternalSecret(_) => None,
+            };
+            let applied = retry_with_backoff(&k8s_retry_policy, || async {
+                if args.namespace_create {
+                    ensure_namespace(client, &namespace).await?;
+                }
+                if !namespace_labels.is_empty()
+                    && !label_namespace(client, &namespace, &namespace_labels).await?
+                {
+                    warn!(
+                        aws_secret_name,
+                        k8s_namespace = namespace,
+                        "Namespace {} doesn't exist, so it can't be labelled; use \
+--namespace-create to create it",
+                        namespace
+                    );
+                }
+                match &manifest {
+                    Manifest::Secret(k8s_secret) => {
+                        let secrets: Api<Secret> = Api::namespaced(client.clone(), &namespace);
+                        let applied = match apply_k8s_secret(&secrets, k8s_secret, &params).await {
+                            Err(e)
+                                if (args.force_recreate || args.immutable_recreate)
+                                    && is_immutable_field_error(&e) =>
+                            {
+                                recreate_secret(&secrets, k8s_secret, &params).await
+                            }
+                            // the data of an immutable secret can't be changed in place
+                            Err(e) if args.immutable && is_immutable_field_error(&e) => {
+                                warn!(
+                                    aws_secret_name,
+                                    k8s_namespace = namespace,
+                                    k8s_secret_name = secret_name,
+                                    "Secret {}/{} is immutable, skipping; use \
+--immutable-recreate to recreate it with the new data",
+                                    namespace,
+                                    secret_name
+                                );
+                                Ok(Applied::Unchanged)
+                            }
+                            applied => applied,
+                        }?;
+                        if args.verify && matches!(applied, Applied::Updated) {
+                            verify_secret(&secrets, k8s_secret).await?;
+                        }
+                        Ok(applied)
+                    }
+                    Manifest::ExternalSecret(external_secret) => {
+                        let resource = external_secret_resource();
+                        let external_secrets: Api<DynamicObject> =
+                            Api::namespaced_with(client.clone(), &namespace, &resource);
+                        apply_external_secret(&external_secrets, external_secret, &params).await
+                    }
+                    Manifest::SealedSecret(_) => {
+                        apply_sealed_secret(client, sealed_secret.as_ref(), &params).await
+                    }
+                }
+            })
+            .await;
+            let event = AuditEvent::new(AuditAction::Patch, Some(aws_secret_name), Utc::now())
+                .k8s(&namespace, &secret_name);
+            audit.record(match &applied {
+                Ok(Applied::Updated) => event,
+                Ok(Applied::Unchanged) => event.result("unchanged", None),
+                Err(e) => event.result("error", Some(e.to_string())),
+            });
+            // events are recorded on the Kubernetes secret, which doesn't exist with
+            // `--output-mode external-secret` or `sealed-secret` until the operator or the
+            // controller creates it
+            if matches!(manifest, Manifest::Secret(_)) {
+                let event = match &applied {
+                    Ok(Applied::Updated) => Some(sync_event(None)),
+                    Ok(Applied::Unchanged) => None,
+                    Err(e) => Some(sync_event(Some(&e.to_string()))),
+                };
+                if let Some(event) = event {
+                    if let Err(e) = record_event(client, &namespace, &secret_name, event).await {
+                        warn!(
+                            aws_secret_name,
+                            k8s_namespace = namespace,
+                            k8s_secret_name = secret_name,
+                            "Failed to record event: {}",
+                            e
+                        );
+                    }
+                }
+            }
+            match applied {
+                Ok(Applied::Updated) => {
+                    info!(
+                        aws_secret_name,
+                        k8s_namespace = namespace,
+                        k8s_secret_name = secret_name,
+                        "Secret {}/{} updated",
+                        namespace,
+                        secret_name
+                    );
+                    outcome
+                        .namespaces
+                        .entry(namespace.clone())
+                        .or_default()
+                        .synced += 1;
+                }
+                Ok(Applied::Unchanged) => {
+                    info!(
+                        aws_secret_name,
+                        k8s_namespace = namespace,
+                        k8s_secret_name = secret_name,
+                        "Secret {}/{} unchanged, skipping",
+                        namespace,
+                        secret_name
+                    );
+                    outcome
+                        .namespaces
+                        .entry(namespace.clone())
+                        .or_default()
+                        .unchanged += 1;
+                }
+                Err(e) => {
+                    error!(
+                        aws_secret_name,
+                        k8s_namespace = namespace,
+                        k8s_secret_name = secret_name,
+                        "Error updating secret {}/{}: {}",
+                        namespace,
+                        secret_name,
+                        e
+                    );
+                    outcome
+                        .namespaces
+                        .entry(namespace.clone())
+                        .or_default()
+                        .errors += 1;
+                    // the other namespaces are left alone once the run is aborted
+                    if args.on_k8s_error == ErrorPolicy::Abort {
+                        return Err(SyncError::Kubernetes(e));
+                    }
+                    result = Err(SyncError::Kubernetes(e));
+                }
+            }
+        }
+    }
+    if let (Ok(()), SecretSource::Aws(client)) = (&result, source) {
+        let applied = args.output == Output::Apply && !args.dry_run && !args.plan;
+        if args.tag_on_success && applied && !kube_clients.is_empty() {
+            let secret_id = secret.arn.as_deref().unwrap_or(aws_secret_name);
+            if let Err(e) = tag_last_sync(client, secret_id, Utc::now()).await {
+                warn!(aws_secret_name, "Failed to tag secret as synced: {}", e);
+            }
+        }
+    }
+    result
+}
+
+// syncs the SSM parameters below `prefix` into the ConfigMap given by `--ssm-namespace` and
+// `--ssm-configmap-name`, in every cluster of `kube_clients`
+async fn sync_ssm_parameters(
+    args: &Args,
+    ssm: &SsmClient,
+    prefix: &str,
+    kube_clients: &[kube::Client],
+) -> Result<(), SyncError> {
+    // both are required along with the prefix
+    let namespace = args.ssm_namespace.as_deref().unwrap_or_default();
+    let name = args.ssm_configmap_name.as_deref().unwrap_or_default();
+    let aws_retry_policy = retry_policy(args, args.on_aws_error);
+    let parameters =
+        retry_with_backoff(&aws_retry_policy, || ssm.get_parameters_by_path(prefix)).await?;
+    let template = SecretTemplate::from_args(args, Utc::now());
+    let config_map = build_config_map(
+        name,
+        namespace,
+        parameter_data(prefix, parameters),
+        &template,
+    );
+
+    if args.output == Output::Manifest {
+        print!("---\n{}", serde_yaml::to_string(&config_map)?);
+        return Ok(());
+    }
+    if args.dry_run {
+        info!("[dry-run] would patch ConfigMap {}/{}", namespace, name);
+        println!("{:#}", serde_json::to_value(&config_map)?);
+        return Ok(());
+    }
+    let params = build_patch_params(args);
+    let k8s_retry_policy = retry_policy(args, args.on_k8s_error);
+    for client in kube_clients {
+        let config_maps: Api<ConfigMap> = Api::namespaced(client.clone(), namespace);
+        let applied = retry_with_backoff(&k8s_retry_policy, || {
+            apply_config_map(&config_maps, &config_map, &params)
+        })
+        .await?;
+        match applied {
+            Applied::Updated => info!("ConfigMap {}/{} updated", namespace, name),
+            Applied::Unchanged => debug!("ConfigMap {}/{} unchanged", namespace, name),
+        }
+    }
+    Ok(())
+}
+
+// gets the value of the secret as the base64 encoded data of a Kubernetes secret
+async fn fetch_data_map(
+    secret: &SecretListEntry,
+    filenames: Vec<String>,
+    args: &Args,
+    source: &SecretSource,
+    retry_policy: &RetryPolicy,
+) -> Result<SecretData, SyncError> {
+    let aws_secret_name = secret.name.as_deref().unwrap();
+    let secret_value = match read_secret_value(secret, args, source, retry_policy).await? {
+        SecretValue::Parsed(secret_value) => secret_value,
+        // a binary bundle is read base64 encoded under `--binary-key`, like a string one
+        SecretValue::Binary(data_map) if args.parse_pkcs12 => data_map,
+        SecretValue::Binary(data_map) => return Ok(data_map),
+    };
+    let secret_value = if args.parse_pkcs12 {
+        convert_pkcs12(
+            &args.openssl_path,
+            &secret_value,
+            &args.binary_key,
+            args.pkcs12_password_key.as_deref(),
+        )
+        .await?
+    } else {
+        secret_value
+    };
+    let missing = missing_keys(&secret_value, &args.include_keys);
+    if !missing.is_empty() {
+        warn!(
+            aws_secret_name,
+            "Secret has no key {} given to --include-keys",
+            missing.join(", ")
+        );
+    }
+    let secret_value = include_keys(secret_value, &args.include_keys);
+    let secret_value = exclude_keys(secret_value, &args.exclude_keys);
+    let secret_value = remap_keys(secret_value, &args.key_map);
+    let secret_value = match &args.transform_script {
+        Some(path) => {
+            let timeout = Duration::from_millis(args.transform_timeout_ms);
+            transform_with_script(path, timeout, secret_value).await?
+        }
+        None => secret_value,
+    };
+    let secret_value = match &args.transform_command {
+        Some(command) => {
+            let timeout = Duration::from_millis(args.transform_timeout_ms);
+            transform_secret(command, timeout, secret_value).await?
+        }
+        None => secret_value,
+    };
+
+    // depending on whether the secret has filename tags, create the data of the Kubernetes
+    // secret from the secret values, with one file of all the values per filename
+    if filenames.is_empty() {
+        if args.values_already_base64 {
+            return Ok(create_datamap_from_base64_values(secret_value)?);
+        }
+        return Ok(create_datamap_from_aws_secret(secret_value));
+    }
+    let create_filesecret = if args.compress_file_secret {
+        create_gzip_filesecret_from_aws_secret
+    } else {
+        create_filesecret_from_aws_secret
+    };
+    Ok(filenames
+        .into_iter()
+        .flat_map(|filename| create_filesecret(secret_value.clone(), filename))
+        .collect())
+}
+
+// the value of a secret as read from the secret store
+enum SecretValue {
+    // the parsed values of a secret string or a Vault secret
+    Parsed(SecretData),
+    // the data of the Kubernetes secret holding a binary AWS secret under `--binary-key`
+    Binary(SecretData),
+}
+
+// reads the value of `secret` from `source` and parses it with `--parser`
+async fn read_secret_value(
+    secret: &SecretListEntry,
+    args: &Args,
+    source: &SecretSource,
+    retry_policy: &RetryPolicy,
+) -> Result<SecretValue, SyncError> {
+    let aws_secret_name = secret.name.as_deref().unwrap();
+    match source {
+        SecretSource::Aws(client) => {
+            let version = SecretVersion::from_args(args);
+            debug!(
+                aws_secret_name,
+                "Fetching {} of {}",
+                version,
+                secret.arn.as_deref().unwrap_or(aws_secret_name)
+            );
+            let secret_value = retry_with_backoff(retry_policy, || {
+                version
+                    .apply(client.get_secret_value().secret_id(aws_secret_name))
+                    .send()
+            })
+            .await
+            .map_err(aws_sdk_secretsmanager::Error::from)?;
+            match (secret_value.secret_string, secret_value.secret_binary) {
+                (Some(secret_string), _) => Ok(SecretValue::Parsed(
+                    match args
+                        .split_yaml_documents
+                        .then(|| split_yaml_documents(&secret_string))
+                        .flatten()
+                    {
+                        Some(documents) => documents,
+                        None => secret_parser(args.parser, &args.plain_text_key)
+                            .parse(&secret_string)?,
+                    },
+                )),
+                (None, Some(binary)) => Ok(SecretValue::Binary(handle_binary_secret(
+                    binary,
+                    &args.binary_key,
+                ))),
+                (None, None) => Err(SyncError::EmptySecret),
+            }
+        }
+        SecretSource::Vault(vault) => {
+            debug!(aws_secret_name, "Fetching Vault secret {}", aws_secret_name);
+            let secret_value =
+                retry_with_backoff(retry_policy, || vault.read_secret(aws_secret_name)).await?;
+            Ok(SecretValue::Parsed(secret_value))
+        }
+    }
+}
+
+// the object applied to each namespace, depending on `--output-mode`
+enum Manifest {
+    Secret(Secret),
+    ExternalSecret(DynamicObject),
+    // the secret to seal for the controller of each cluster
+    SealedSecret(Secret),
+}
+
+// the server-side apply patch of `manifest`, also printed by `--dry-run`
+fn build_patch(manifest: &Manifest) -> Result<serde_json::Value, serde_json::Error> {
+    match manifest {
+        Manifest::Secret(k8s_secret) | Manifest::SealedSecret(k8s_secret) => {
+            serde_json::to_value(k8s_secret)
+        }
+        Manifest::ExternalSecret(external_secret) => serde_json::to_value(external_secret),
+    }
+}
+
+impl Manifest {
+    // the manifest as a YAML document of a multi-document stream; a sealed secret is sealed
+    // with the certificate of the controller of the first of `kube_clients`
+    async fn to_yaml(
+        &self,
+        args: &Args,
+        kube_clients: &[kube::Client],
+    ) -> Result<String, SyncError> {
+        let yaml = match self {
+            Manifest::Secret(k8s_secret) => serde_yaml::to_string(k8s_secret)?,
+            Manifest::ExternalSecret(external_secret) => serde_yaml::to_string(external_secret)?,
+            Manifest::SealedSecret(k8s_secret) => {
+                let certificate =
+                    fetch_certificate(&kube_clients[0], &args.sealed_secrets_controller)
+                        .await
+                        .map_err(SealError::from)?;
+                let sealed = seal_secret(&args.kubeseal_path, &certificate, k8s_secret).await?;
+                serde_yaml::to_string(&sealed)?
+            }
+        };
+        Ok(format!("---\n{}", yaml))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn manifest_to_yaml_is_a_separate_document() {
+        let args = args::tests::args(&[]);
+        let template = SecretTemplate::from_args(&args, Utc::now());
+        let data = SecretData::from([(String::from("password"), String::from("aHVudGVyMg=="))]);
+        let manifest =
+            Manifest::Secret(build_k8s_secret("db-creds", "prod", data, &template, None));
+
+        let yaml = manifest.to_yaml(&args, &[]).await.unwrap();
+        assert!(yaml.starts_with("---\n"));
+        assert!(yaml.ends_with('\n'));
+        let secret: serde_yaml::Value = serde_yaml::from_str(&yaml).unwrap();
+        assert_eq!(secret["kind"], "Secret");
+        assert_eq!(secret["metadata"]["namespace"], "prod");
+        assert_eq!(secret["data"]["password"], "aHVudGVyMg==");
+    }
+
+    #[test]
+    fn build_patch_is_the_secret_as_json() {
+        let args = args::tests::args(&["--label", "team=payments", "--secret-type", "Opaque"]);
+        let synced_at = chrono::DateTime::parse_from_rfc3339("2024-05-01T12:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let template = SecretTemplate::from_args(&args, synced_at);
+        let data = SecretData::from([(String::from("password"), String::from("aHVudGVyMg=="))]);
+        let manifest = Manifest::Secret(build_k8s_secret(
+            "db-creds",
+            "prod",
+            data,
+            &template,
+            Some("arn:aws:secretsmanager:us-east-1:123456789012:secret:db-creds-AbCdEf"),
+        ));
+
+        assert_eq!(
+            build_patch(&manifest).unwrap(),
+            serde_json::json!({
+                "apiVersion": "v1",
+                "kind": "Secret",
+                "metadata": {
+                    "name": "db-creds",
+                    "namespace": "prod",
+                    "labels": {
+                        "k8s-aws-secrets-sync/managed": "true",
+                        "k8s-aws-secrets-sync/owner": "k8s-aws-secrets-sync",
+                        "team": "payments",
+                    },
+                    "annotations": {
+                        "k8s-aws-secrets-sync/last-sync-time": "2024-05-01T12:00:00Z",
+                        "k8s-aws-secrets-sync/source-arn":
+                            "arn:aws:secretsmanager:us-east-1:123456789012:secret:db-creds-AbCdEf",
+                        "k8s-aws-secrets-sync/synced-at": "2024-05-01T12:00:00Z",
+                    },
+                },
+                "data": { "password": "aHVudGVyMg==" },
+                "type": "Opaque",
+            })
+        );
+    }
+
+    #[test]
+    fn run_summary_fails_on_errors_or_skips() {
+        let ok = RunSummary::default();
+        let errored = RunSummary {
+            errors: vec![String::from("db-creds: Kubernetes error")],
+            ..RunSummary::default()
+        };
+        let skipped = RunSummary {
+            report: SyncReport {
+                skipped: 1,
+                ..SyncReport::default()
+            },
+            ..RunSummary::default()
+        };
+        assert_eq!(ok.exit_code(), ExitCode::SUCCESS);
+        assert_eq!(errored.exit_code(), ExitCode::FAILURE);
+        assert_eq!(skipped.exit_code(), ExitCode::FAILURE);
+
+        let interrupted = RunSummary {
+            shutdown_requested: true,
+            interrupted: 2,
+            ..RunSummary::default()
+        };
+        assert_eq!(interrupted.exit_code(), ExitCode::from(EXIT_CODE_SHUTDOWN));
+    }
+
+    #[test]
+    fn tag_errors_convert_to_sync_errors() {
+        let missing = SyncError::from(TagError::Missing {
+            arn: String::from("arn:db-creds"),
+            tag: String::from("ns"),
+        });
+        assert!(matches!(
+            &missing,
+            SyncError::TagMissing { secret, tag } if secret == "arn:db-creds" && tag == "ns"
+        ));
+        assert_eq!(
+            missing.to_string(),
+            "AWS secret arn:db-creds is missing required tag `ns`"
+        );
+
+        let template = SyncError::from(TagError::Template {
+            arn: String::from("arn:db-creds"),
+            source: TemplateError::MissingTag(String::from("team")),
+        });
+        assert!(matches!(template, SyncError::TagMissing { tag, .. } if tag == "team"));
+
+        let unclosed = SyncError::from(TagError::Template {
+            arn: String::from("arn:db-creds"),
+            source: TemplateError::Unclosed(String::from("{{team")),
+        });
+        assert!(matches!(unclosed, SyncError::InvalidName(_)));
+    }
+
+    #[test]
+    fn error_policies_follow_the_class_of_the_error() {
+        let args = args::tests::args(&["--on-aws-error", "abort", "--on-k8s-error", "skip"]);
+        assert_eq!(SyncError::EmptySecret.policy(&args), ErrorPolicy::Abort);
+        let not_found = kube::Error::Api(kube::core::ErrorResponse {
+            status: String::from("Failure"),
+            message: String::from("not found"),
+            reason: String::from("NotFound"),
+            code: 404,
+        });
+        assert_eq!(
+            SyncError::Kubernetes(not_found).policy(&args),
+            ErrorPolicy::Skip
+        );
+
+        assert_eq!(retry_policy(&args, ErrorPolicy::Retry).max_retries, 3);
+        assert_eq!(retry_policy(&args, ErrorPolicy::Skip).max_retries, 0);
+        assert_eq!(retry_policy(&args, ErrorPolicy::Abort).max_retries, 0);
+    }
+
+    #[tokio::test]
+    async fn sync_secrets_syncs_nothing_after_shutdown() {
+        let args = Arc::new(crate::args::tests::args(&[]));
+        let config = aws_sdk_secretsmanager::Config::builder()
+            .behavior_version(aws_config::BehaviorVersion::latest())
+            .region(aws_config::Region::new("eu-west-1"))
+            .build();
+        let client = aws_sdk_secretsmanager::Client::from_conf(config);
+        let secrets = ["db-creds", "api-key"]
+            .into_iter()
+            .map(|name| {
+                let secret = SecretListEntry::builder().name(name).build();
+                (SecretSource::Aws(client.clone()), secret)
+            })
+            .collect();
+
+        let shutdown = Arc::new(AtomicBool::new(true));
+        let summary = sync_secrets(
+            &args,
+            secrets,
+            &[],
+            &Metrics::new(),
+            &shutdown,
+            &AuditLog::default(),
+        )
+        .await;
+
+        assert_eq!(summary.interrupted, 2);
+        assert!(summary.shutdown_requested);
+        assert!(summary.synced.is_empty());
+        assert_eq!(summary.report.skipped, 0);
+    }
+}
+
+#[cfg(all(test, feature = "integration-tests"))]
+mod integration_tests {
+    use super::*;
+    use crate::args::tests::args;
+    use crate::aws::tests::tag;
+    use aws::PreflightError;
+    use aws_config::BehaviorVersion;
+    use aws_sdk_secretsmanager::config::{Credentials, Region};
+    use aws_smithy_runtime::client::http::test_util::{ReplayEvent, StaticReplayClient};
+    use aws_smithy_types::body::SdkBody;
+
+    fn list_secrets_page(body: &str) -> ReplayEvent {
+        aws_response(200, body)
+    }
+
+    fn aws_response(status: u16, body: &str) -> ReplayEvent {
+        ReplayEvent::new(
+            http::Request::builder()
+                .uri("https://secretsmanager.us-east-1.amazonaws.com/")
+                .body(SdkBody::empty())
+                .unwrap(),
+            http::Response::builder()
+                .status(status)
+                .body(SdkBody::from(body.to_owned()))
+                .unwrap(),
+        )
+    }
+
+    // a Secrets Manager client that replays `http_client` instead of calling AWS
+    fn mock_client(http_client: &StaticReplayClient) -> aws_sdk_secretsmanager::Client {
+        let config = aws_sdk_secretsmanager::Config::builder()
+            .behavior_version(BehaviorVersion::latest())
+            .region(Region::new("us-east-1"))
+            .credentials_provider(Credentials::for_tests())
+            .http_client(http_client.clone())
+            .build();
+        aws_sdk_secretsmanager::Client::from_conf(config)
+    }
+
+    #[tokio::test]
+    async fn list_all_secrets_follows_next_token() {
+        let http_client = StaticReplayClient::new(vec![
+            list_secrets_page(r#"{"SecretList":[{"Name":"a"},{"Name":"b"}],"NextToken":"page-2"}"#),
+            list_secrets_page(r#"{"SecretList":[{"Name":"c"}],"NextToken":"page-3"}"#),
+            list_secrets_page(r#"{"SecretList":[{"Name":"d"}]}"#),
+        ]);
+        let client = mock_client(&http_client);
+
+        let no_retries = RetryPolicy {
+            max_retries: 0,
+            base_delay: Duration::ZERO,
+        };
+        let secrets = list_all_secrets(&client, vec![], &no_retries)
+            .await
+            .unwrap();
+
+        let names: Vec<_> = secrets.iter().filter_map(|s| s.name.as_deref()).collect();
+        assert_eq!(names, ["a", "b", "c", "d"]);
+        let requests = http_client.actual_requests().collect::<Vec<_>>();
+        assert_eq!(requests.len(), 3);
+        let body = std::str::from_utf8(requests[2].body().bytes().unwrap()).unwrap();
+        assert!(body.contains(r#""NextToken":"page-3""#));
+    }
+
+    #[tokio::test]
+    async fn tag_last_sync_tags_the_secret() {
+        let http_client = StaticReplayClient::new(vec![aws_response(200, "{}")]);
+        let client = mock_client(&http_client);
+        let synced_at = chrono::DateTime::parse_from_rfc3339("2024-11-20T10:30:00Z")
+            .unwrap()
+            .to_utc();
+
+        tag_last_sync(&client, "db-creds", synced_at).await.unwrap();
+
+        let requests = http_client.actual_requests().collect::<Vec<_>>();
+        assert_eq!(
+            requests[0].headers().get("x-amz-target"),
+            Some("secretsmanager.TagResource")
+        );
+        let body: serde_json::Value =
+            serde_json::from_slice(requests[0].body().bytes().unwrap()).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "SecretId": "db-creds",
+                "Tags": [{"Key": "k8s-aws-secrets-sync/last-sync", "Value": "2024-11-20T10:30:00Z"}],
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_fetch_fails_the_run() {
+        let http_client = StaticReplayClient::new(vec![aws_response(
+            400,
+            r#"{"__type":"ResourceNotFoundException","message":"not found"}"#,
+        )]);
+        let client = mock_client(&http_client);
+        let args = Arc::new(args(&["--dry-run"]));
+        let secret = SecretListEntry::builder()
+            .name("db-creds")
+            .tags(tag("ns", "prod"))
+            .tags(tag("name", "db-creds"))
+            .build();
+
+        let summary = sync_secrets(
+            &args,
+            vec![(SecretSource::Aws(client), secret)],
+            &[],
+            &Metrics::new(),
+            &Arc::default(),
+            &AuditLog::default(),
+        )
+        .await;
+
+        assert_eq!(summary.errors.len(), 1);
+        assert_eq!(summary.exit_code(), ExitCode::FAILURE);
+        assert!(!summary.aborted);
+    }
+
+    #[tokio::test]
+    async fn list_all_secrets_retries_throttled_pages() {
+        let throttled = || {
+            aws_response(
+                400,
+                r#"{"__type":"ThrottlingException","message":"Rate exceeded"}"#,
+            )
+        };
+        let http_client = StaticReplayClient::new(vec![
+            list_secrets_page(r#"{"SecretList":[{"Name":"a"}],"NextToken":"page-2"}"#),
+            throttled(),
+            list_secrets_page(r#"{"SecretList":[{"Name":"b"}]}"#),
+        ]);
+        let client = mock_client(&http_client);
+        let args = args(&["--on-aws-error", "retry", "--retry-base-ms", "1"]);
+
+        let secrets = list_all_secrets(&client, vec![], &retry_policy(&args, args.on_aws_error))
+            .await
+            .unwrap();
+
+        let names: Vec<_> = secrets.iter().filter_map(|s| s.name.as_deref()).collect();
+        assert_eq!(names, ["a", "b"]);
+        assert_eq!(http_client.actual_requests().count(), 3);
+
+        // only `--on-aws-error retry` retries
+        let http_client = StaticReplayClient::new(vec![throttled()]);
+        let client = mock_client(&http_client);
+        let skip_policy = retry_policy(&args, ErrorPolicy::Skip);
+        let skipped = list_all_secrets(&client, vec![], &skip_policy).await;
+        assert!(skipped.is_err());
+    }
+
+    #[tokio::test]
+    async fn failed_fetch_aborts_the_run_with_on_aws_error_abort() {
+        let http_client = StaticReplayClient::new(vec![aws_response(
+            400,
+            r#"{"__type":"ResourceNotFoundException","message":"not found"}"#,
+        )]);
+        let client = mock_client(&http_client);
+        let args = Arc::new(args(&["--dry-run", "--on-aws-error", "abort"]));
+        let secret = SecretListEntry::builder()
+            .name("db-creds")
+            .tags(tag("ns", "prod"))
+            .tags(tag("name", "db-creds"))
+            .build();
+
+        let summary = sync_secrets(
+            &args,
+            vec![(SecretSource::Aws(client), secret)],
+            &[],
+            &Metrics::new(),
+            &Arc::default(),
+            &AuditLog::default(),
+        )
+        .await;
+
+        assert!(summary.aborted);
+        assert_eq!(summary.errors.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn consecutive_errors_abort_the_run_with_max_errors_before_abort() {
+        let not_found = || {
+            aws_response(
+                400,
+                r#"{"__type":"ResourceNotFoundException","message":"not found"}"#,
+            )
+        };
+        let http_client = StaticReplayClient::new(vec![not_found(), not_found(), not_found()]);
+        let client = mock_client(&http_client);
+        let args = Arc::new(args(&[
+            "--dry-run",
+            "--max-retries",
+            "0",
+            "--max-errors-before-abort",
+            "2",
+        ]));
+        let secrets = ["db-creds", "api-key", "tls-cert"]
+            .into_iter()
+            .map(|name| {
+                let secret = SecretListEntry::builder()
+                    .name(name)
+                    .tags(tag("ns", "prod"))
+                    .tags(tag("name", name))
+                    .build();
+                (SecretSource::Aws(client.clone()), secret)
+            })
+            .collect();
+
+        let summary = sync_secrets(
+            &args,
+            secrets,
+            &[],
+            &Metrics::new(),
+            &Arc::default(),
+            &AuditLog::default(),
+        )
+        .await;
+
+        assert!(summary.aborted);
+        // the last secret may have failed before the abort
+        assert!(summary.errors.len() >= 2);
+    }
+
+    #[tokio::test]
+    async fn preflight_check_reports_access_denied() {
+        let http_client = StaticReplayClient::new(vec![aws_response(
+            400,
+            r#"{"__type":"AccessDeniedException","message":"not authorized"}"#,
+        )]);
+        let client = mock_client(&http_client);
+        let secret = SecretListEntry::builder()
+            .arn("arn:aws:secretsmanager:us-east-1:123456789012:secret:db-creds-AbCdEf")
+            .name("db-creds")
+            .build();
+
+        let result = preflight_check(&client, &secret, SecretVersion::Stage("AWSCURRENT")).await;
+
+        assert!(
+            matches!(result, Err(PreflightError::AccessDenied { arn, .. }) if arn.ends_with("db-creds-AbCdEf"))
+        );
+    }
+}
@@ -0,0 +1,239 @@
+// A lock held in a ConfigMap with `--leader-lock`, so overlapping CronJob runs don't race to
+// patch the same secrets.
+//
+// The ConfigMap names the pod holding the lock and when the lock expires; an expired lock is
+// taken over, so a run that crashed without releasing it only blocks others until then.
+
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Duration, SecondsFormat, Utc};
+use k8s_openapi::api::core::v1::ConfigMap;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
+use kube::api::{Api, DeleteParams, PostParams};
+use tracing::{debug, info};
+
+/// The name of the lock ConfigMap
+pub const LOCK_NAME: &str = "aws-secrets-sync-lock";
+
+/// The key of the lock ConfigMap holding the name of the pod that holds the lock
+pub const HOLDER_KEY: &str = "holder";
+
+/// The key of the lock ConfigMap holding the time the lock expires
+pub const EXPIRES_AT_KEY: &str = "expires-at";
+
+/// The file holding the namespace of the pod's service account
+pub const SERVICE_ACCOUNT_NAMESPACE_PATH: &str =
+    "/var/run/secrets/kubernetes.io/serviceaccount/namespace";
+
+/// The lock of this run, released with [`Lock::release`]
+pub struct Lock {
+    config_maps: Api<ConfigMap>,
+    namespace: String,
+    holder: String,
+}
+
+impl Lock {
+    /// Takes the lock in `namespace` for `ttl`, or returns `None` when another pod holds it
+    pub async fn acquire(
+        client: &kube::Client,
+        namespace: &str,
+        holder: &str,
+        ttl: Duration,
+    ) -> Result<Option<Self>, kube::Error> {
+        let config_maps: Api<ConfigMap> = Api::namespaced(client.clone(), namespace);
+        let now = Utc::now();
+        let mut lock = build_lock(namespace, holder, now + ttl);
+
+        let taken = match config_maps.get_opt(LOCK_NAME).await? {
+            Some(existing) if is_held(&existing, now) => {
+                info!(
+                    "Lock {}/{} is held by {}",
+                    namespace,
+                    LOCK_NAME,
+                    lock_holder(&existing).unwrap_or("<unknown>")
+                );
+                return Ok(None);
+            }
+            // the resource version makes the takeover fail if another pod got there first
+            Some(expired) => {
+                debug!("Taking over the expired lock {}/{}", namespace, LOCK_NAME);
+                lock.metadata.resource_version = expired.metadata.resource_version;
+                config_maps
+                    .replace(LOCK_NAME, &PostParams::default(), &lock)
+                    .await
+            }
+            None => config_maps.create(&PostParams::default(), &lock).await,
+        };
+        match taken {
+            Ok(_) => {
+                info!("Acquired lock {}/{} as {}", namespace, LOCK_NAME, holder);
+                Ok(Some(Lock {
+                    config_maps,
+                    namespace: String::from(namespace),
+                    holder: String::from(holder),
+                }))
+            }
+            Err(kube::Error::Api(response)) if response.code == 409 => {
+                info!(
+                    "Lock {}/{} was just taken by another pod",
+                    namespace, LOCK_NAME
+                );
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Extends the lock to `ttl` from now, for `--watch`; returns `false` when it expired and
+    /// was taken over by another pod in the meantime
+    pub async fn renew(&self, ttl: Duration) -> Result<bool, kube::Error> {
+        let existing = self.config_maps.get_opt(LOCK_NAME).await?;
+        let expires_at = Utc::now() + ttl;
+        let Some(lock) = existing.and_then(|existing| {
+            renewed_lock(&existing, &self.namespace, &self.holder, expires_at)
+        }) else {
+            return Ok(false);
+        };
+        match self
+            .config_maps
+            .replace(LOCK_NAME, &PostParams::default(), &lock)
+            .await
+        {
+            Ok(_) => {
+                debug!("Renewed lock {}/{}", self.namespace, LOCK_NAME);
+                Ok(true)
+            }
+            Err(kube::Error::Api(response)) if response.code == 409 => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Deletes the lock ConfigMap, unless it was taken over by another pod in the meantime
+    pub async fn release(self) -> Result<(), kube::Error> {
+        let Some(existing) = self.config_maps.get_opt(LOCK_NAME).await? else {
+            return Ok(());
+        };
+        if lock_holder(&existing) != Some(self.holder.as_str()) {
+            return Ok(());
+        }
+        self.config_maps
+            .delete(LOCK_NAME, &DeleteParams::default())
+            .await?;
+        info!("Released lock {}", LOCK_NAME);
+        Ok(())
+    }
+}
+
+// builds the lock ConfigMap held by `holder` until `expires_at`
+pub fn build_lock(namespace: &str, holder: &str, expires_at: DateTime<Utc>) -> ConfigMap {
+    ConfigMap {
+        metadata: ObjectMeta {
+            name: Some(String::from(LOCK_NAME)),
+            namespace: Some(String::from(namespace)),
+            ..ObjectMeta::default()
+        },
+        data: Some(BTreeMap::from([
+            (String::from(HOLDER_KEY), String::from(holder)),
+            (
+                String::from(EXPIRES_AT_KEY),
+                expires_at.to_rfc3339_opts(SecondsFormat::Secs, true),
+            ),
+        ])),
+        ..ConfigMap::default()
+    }
+}
+
+// the lock `existing` held by `holder` until `expires_at` instead, or `None` when another pod
+// holds it; the resource version makes the renewal fail if another pod takes it over first
+fn renewed_lock(
+    existing: &ConfigMap,
+    namespace: &str,
+    holder: &str,
+    expires_at: DateTime<Utc>,
+) -> Option<ConfigMap> {
+    if lock_holder(existing) != Some(holder) {
+        return None;
+    }
+    let mut lock = build_lock(namespace, holder, expires_at);
+    lock.metadata.resource_version = existing.metadata.resource_version.clone();
+    Some(lock)
+}
+
+// the pod holding the lock `config_map`
+fn lock_holder(config_map: &ConfigMap) -> Option<&str> {
+    config_map
+        .data
+        .as_ref()?
+        .get(HOLDER_KEY)
+        .map(String::as_str)
+}
+
+// whether the lock `config_map` is still held at `now`; a lock without a valid expiry time
+// is treated as expired
+pub fn is_held(config_map: &ConfigMap, now: DateTime<Utc>) -> bool {
+    config_map
+        .data
+        .as_ref()
+        .and_then(|data| data.get(EXPIRES_AT_KEY))
+        .and_then(|expires_at| DateTime::parse_from_rfc3339(expires_at).ok())
+        .is_some_and(|expires_at| expires_at > now)
+}
+
+// the namespace this pod runs in, from its service account or `POD_NAMESPACE`
+pub fn own_namespace() -> String {
+    std::fs::read_to_string(SERVICE_ACCOUNT_NAMESPACE_PATH)
+        .ok()
+        .or_else(|| std::env::var("POD_NAMESPACE").ok())
+        .map(|namespace| namespace.trim().to_owned())
+        .unwrap_or_else(|| String::from("default"))
+}
+
+// the name of this pod, which Kubernetes sets as the hostname
+pub fn own_pod_name() -> String {
+    std::env::var("POD_NAME")
+        .or_else(|_| std::env::var("HOSTNAME"))
+        .unwrap_or_else(|_| String::from("k8s-aws-secrets-sync"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lock_is_held_until_it_expires() {
+        let now = DateTime::parse_from_rfc3339("2024-11-20T10:30:00Z")
+            .unwrap()
+            .to_utc();
+        let lock = build_lock("ops", "sync-28871234-abcde", now + Duration::minutes(10));
+        assert_eq!(lock_holder(&lock), Some("sync-28871234-abcde"));
+        assert_eq!(
+            lock.data.as_ref().unwrap()[EXPIRES_AT_KEY],
+            "2024-11-20T10:40:00Z"
+        );
+
+        assert!(is_held(&lock, now));
+        assert!(!is_held(&lock, now + Duration::minutes(10)));
+        assert!(!is_held(&ConfigMap::default(), now));
+    }
+
+    #[test]
+    fn only_the_holder_renews_the_lock() {
+        let now = DateTime::parse_from_rfc3339("2024-11-20T10:30:00Z")
+            .unwrap()
+            .to_utc();
+        let mut lock = build_lock("ops", "sync-28871234-abcde", now);
+        lock.metadata.resource_version = Some(String::from("42"));
+
+        let renewed = renewed_lock(
+            &lock,
+            "ops",
+            "sync-28871234-abcde",
+            now + Duration::minutes(10),
+        )
+        .unwrap();
+        assert_eq!(renewed.metadata.resource_version.as_deref(), Some("42"));
+        assert!(is_held(&renewed, now + Duration::minutes(5)));
+
+        assert!(renewed_lock(&lock, "ops", "sync-28871299-fghij", now).is_none());
+    }
+}
@@ -0,0 +1,116 @@
+// Prometheus metrics describing a single run of the sync.
+//
+// The metrics are written in the text exposition format so node_exporter's textfile
+// collector can pick them up after the CronJob has finished.
+
+use std::path::Path;
+use std::time::Duration;
+
+use prometheus::{Encoder, Histogram, HistogramOpts, IntCounterVec, Opts, Registry, TextEncoder};
+
+/// Counters and timings collected while syncing secrets
+pub struct Metrics {
+    registry: Registry,
+    secrets_synced: IntCounterVec,
+    sync_duration: Histogram,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new();
+        let secrets_synced = IntCounterVec::new(
+            Opts::new(
+                "secrets_sync_total",
+                "Number of AWS secrets synced, by result",
+            ),
+            &["result"],
+        )
+        .unwrap();
+        let sync_duration = Histogram::with_opts(HistogramOpts::new(
+            "secret_sync_duration_seconds",
+            "Time taken to sync a single AWS secret",
+        ))
+        .unwrap();
+        registry.register(Box::new(secrets_synced.clone())).unwrap();
+        registry.register(Box::new(sync_duration.clone())).unwrap();
+
+        // make sure both results are always present in the output, even when zero
+        secrets_synced.with_label_values(&["ok"]);
+        secrets_synced.with_label_values(&["error"]);
+
+        Metrics {
+            registry,
+            secrets_synced,
+            sync_duration,
+        }
+    }
+
+    /// Records the outcome of syncing a single secret
+    pub fn observe(&self, ok: bool, duration: Duration) {
+        let result = if ok { "ok" } else { "error" };
+        self.secrets_synced.with_label_values(&[result]).inc();
+        self.sync_duration.observe(duration.as_secs_f64());
+    }
+
+    /// Records a secret that failed without a measurable duration
+    pub fn observe_error(&self) {
+        self.secrets_synced.with_label_values(&["error"]).inc();
+    }
+
+    /// Writes the metrics to `path` in the Prometheus text format
+    pub fn write_to(&self, path: &Path) -> std::io::Result<()> {
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .map_err(std::io::Error::other)?;
+        std::fs::write(path, buffer)
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn written(metrics: &Metrics, name: &str) -> String {
+        let path = std::env::temp_dir().join(name);
+        metrics.write_to(&path).unwrap();
+        let text = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        text
+    }
+
+    #[test]
+    fn write_to_includes_zero_counters() {
+        let text = written(&Metrics::new(), "metrics-test-zero.prom");
+
+        assert!(text.contains("secrets_sync_total{result=\"ok\"} 0\n"));
+        assert!(text.contains("secrets_sync_total{result=\"error\"} 0\n"));
+        assert!(text.contains("secret_sync_duration_seconds_count 0\n"));
+    }
+
+    #[test]
+    fn write_to_counts_results_and_durations() {
+        let metrics = Metrics::new();
+        metrics.observe(true, Duration::from_millis(250));
+        metrics.observe(true, Duration::from_millis(750));
+        metrics.observe(false, Duration::from_secs(2));
+        metrics.observe_error();
+        let text = written(&metrics, "metrics-test-observed.prom");
+
+        assert!(text.contains("# TYPE secrets_sync_total counter\n"));
+        assert!(text.contains("secrets_sync_total{result=\"ok\"} 2\n"));
+        assert!(text.contains("secrets_sync_total{result=\"error\"} 2\n"));
+        assert!(text.contains("# TYPE secret_sync_duration_seconds histogram\n"));
+        assert!(text.contains("secret_sync_duration_seconds_bucket{le=\"0.5\"} 1\n"));
+        assert!(text.contains("secret_sync_duration_seconds_bucket{le=\"1\"} 2\n"));
+        assert!(text.contains("secret_sync_duration_seconds_bucket{le=\"+Inf\"} 3\n"));
+        assert!(text.contains("secret_sync_duration_seconds_sum 3\n"));
+        assert!(text.contains("secret_sync_duration_seconds_count 3\n"));
+    }
+}
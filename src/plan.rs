@@ -0,0 +1,131 @@
+// The changes a sync would make to the Kubernetes secrets, printed as JSON with `--plan`.
+//
+// The plan is worked out by reading each Kubernetes secret and comparing its data with the
+// data it would be given, without patching anything.
+
+use k8s_openapi::api::core::v1::Secret;
+use kube::Api;
+use serde::Serialize;
+
+use crate::k8s::{expected_data, mismatched_keys};
+
+/// What a sync would do to a Kubernetes secret
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PlanAction {
+    /// The secret doesn't exist yet
+    Create,
+    /// The secret exists with different data or a different type
+    Update,
+    /// The secret already holds the data
+    Noop,
+}
+
+/// The change a sync would make to one Kubernetes secret
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct PlanEntry {
+    pub aws_secret: String,
+    pub k8s_namespace: String,
+    pub k8s_secret: String,
+    pub action: PlanAction,
+    /// The keys that would be added, changed or removed, sorted
+    pub changed_keys: Vec<String>,
+}
+
+// compares the `existing` secret, if any, with the `k8s_secret` that would be applied
+pub fn plan_action(existing: Option<&Secret>, k8s_secret: &Secret) -> (PlanAction, Vec<String>) {
+    match existing {
+        None => (
+            PlanAction::Create,
+            expected_data(k8s_secret).into_keys().collect(),
+        ),
+        Some(existing) => {
+            let changed_keys = mismatched_keys(k8s_secret, existing);
+            if changed_keys.is_empty() && existing.type_ == k8s_secret.type_ {
+                (PlanAction::Noop, changed_keys)
+            } else {
+                (PlanAction::Update, changed_keys)
+            }
+        }
+    }
+}
+
+// reads the existing secret of `k8s_secret` and works out what applying it would change
+pub async fn plan_k8s_secret(
+    secrets: &Api<Secret>,
+    k8s_secret: &Secret,
+) -> Result<(PlanAction, Vec<String>), kube::Error> {
+    let name = k8s_secret.metadata.name.as_deref().unwrap();
+    let existing = secrets.get_opt(name).await?;
+    Ok(plan_action(existing.as_ref(), k8s_secret))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use k8s_openapi::ByteString;
+    use std::collections::BTreeMap;
+
+    fn secret(type_: &str, data: &[(&str, &str)]) -> Secret {
+        Secret {
+            type_: Some(String::from(type_)),
+            data: Some(
+                data.iter()
+                    .map(|(key, value)| (String::from(*key), ByteString(value.as_bytes().to_vec())))
+                    .collect::<BTreeMap<_, _>>(),
+            ),
+            ..Secret::default()
+        }
+    }
+
+    #[test]
+    fn plan_action_compares_with_the_existing_secret() {
+        let applied = secret("Opaque", &[("username", "admin"), ("password", "hunter2")]);
+        assert_eq!(
+            plan_action(None, &applied),
+            (
+                PlanAction::Create,
+                vec![String::from("password"), String::from("username")]
+            )
+        );
+        assert_eq!(
+            plan_action(Some(&applied.clone()), &applied),
+            (PlanAction::Noop, vec![])
+        );
+
+        let changed = secret("Opaque", &[("username", "admin"), ("token", "abc")]);
+        assert_eq!(
+            plan_action(Some(&changed), &applied),
+            (
+                PlanAction::Update,
+                vec![String::from("password"), String::from("token")]
+            )
+        );
+        let retyped = secret(
+            "kubernetes.io/basic-auth",
+            &[("username", "admin"), ("password", "hunter2")],
+        );
+        assert_eq!(
+            plan_action(Some(&retyped), &applied),
+            (PlanAction::Update, vec![])
+        );
+
+        let entry = PlanEntry {
+            aws_secret: String::from("prod/db-creds"),
+            k8s_namespace: String::from("prod"),
+            k8s_secret: String::from("db-creds"),
+            action: PlanAction::Create,
+            changed_keys: vec![String::from("password")],
+        };
+        assert_eq!(
+            serde_json::to_value(&entry).unwrap(),
+            serde_json::json!({
+                "aws_secret": "prod/db-creds",
+                "k8s_namespace": "prod",
+                "k8s_secret": "db-creds",
+                "action": "create",
+                "changed_keys": ["password"],
+            })
+        );
+    }
+}
@@ -0,0 +1,101 @@
+// A progress line showing how many of the listed secrets have been synced, when run in a
+// terminal.
+//
+// The line is redrawn in place on stderr, so it is only shown when both stdout and stderr are
+// terminals; in a CronJob the log lines are the only output.
+
+use std::io::{IsTerminal, Write};
+
+/// Counts the secrets processed out of `total`, redrawing the progress line after each one
+#[derive(Debug)]
+pub struct Progress {
+    total: usize,
+    done: usize,
+    enabled: bool,
+}
+
+impl Progress {
+    /// A progress line for `total` secrets, shown only in an interactive terminal
+    pub fn new(total: usize) -> Self {
+        let enabled = std::io::stdout().is_terminal() && std::io::stderr().is_terminal();
+        Progress::with_enabled(total, enabled)
+    }
+
+    /// A progress line for `total` secrets, shown when `enabled` whether or not this runs in
+    /// a terminal
+    pub fn with_enabled(total: usize, enabled: bool) -> Self {
+        Progress {
+            total,
+            done: 0,
+            enabled: enabled && total > 0,
+        }
+    }
+
+    /// Counts `aws_secret_name` as processed
+    pub fn tick(&mut self, aws_secret_name: &str) {
+        self.tick_to(&mut std::io::stderr().lock(), aws_secret_name);
+    }
+
+    // counts `aws_secret_name` as processed, redrawing the progress line on `out`
+    fn tick_to(&mut self, out: &mut impl Write, aws_secret_name: &str) {
+        self.done += 1;
+        if self.enabled {
+            // clears the previous line, which may have been longer
+            let _ = write!(
+                out,
+                "\r\x1b[2K{}",
+                progress_line(self.done, self.total, aws_secret_name)
+            );
+            let _ = out.flush();
+        }
+    }
+
+    /// Ends the progress line, so the log lines that follow start on a line of their own
+    pub fn finish(&self) {
+        if self.enabled && self.done > 0 {
+            eprintln!();
+        }
+    }
+}
+
+// the text of the progress line after `done` of `total` secrets
+fn progress_line(done: usize, total: usize, aws_secret_name: &str) -> String {
+    format!("Syncing secret {}/{}: {}", done, total, aws_secret_name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn progress_counts_each_secret() {
+        assert_eq!(
+            progress_line(5, 23, "prod/myapp/db-creds"),
+            "Syncing secret 5/23: prod/myapp/db-creds"
+        );
+
+        let mut progress = Progress::with_enabled(2, true);
+        assert!(progress.enabled);
+        let mut out = Vec::new();
+        progress.tick_to(&mut out, "db-creds");
+        progress.tick_to(&mut out, "api-key");
+        assert_eq!(progress.done, 2);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "\r\x1b[2KSyncing secret 1/2: db-creds\r\x1b[2KSyncing secret 2/2: api-key"
+        );
+    }
+
+    #[test]
+    fn disabled_progress_only_counts() {
+        let mut progress = Progress::with_enabled(2, false);
+        assert!(!progress.enabled);
+        let mut out = Vec::new();
+        progress.tick_to(&mut out, "db-creds");
+        assert_eq!(progress.done, 1);
+        assert!(out.is_empty());
+
+        // there is nothing to show without secrets
+        assert!(!Progress::with_enabled(0, true).enabled);
+    }
+}
@@ -0,0 +1,256 @@
+// The summary logged at the end of every run, in total and per Kubernetes namespace, and the
+// per secret CSV written with `--report-file`.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+
+use serde::Serialize;
+
+/// The number of Kubernetes secrets synced, left unchanged and failed
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub struct SyncCounts {
+    pub synced: usize,
+    pub unchanged: usize,
+    pub errors: usize,
+}
+
+impl SyncCounts {
+    /// The result of a Kubernetes secret with these counts, as written to `--report-file`
+    pub fn result(&self) -> &'static str {
+        if self.errors > 0 {
+            "error"
+        } else if self.synced > 0 {
+            "synced"
+        } else {
+            "unchanged"
+        }
+    }
+
+    fn add(&mut self, other: SyncCounts) {
+        self.synced += other.synced;
+        self.unchanged += other.unchanged;
+        self.errors += other.errors;
+    }
+}
+
+impl fmt::Display for SyncCounts {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} synced, {} unchanged, {} error(s)",
+            self.synced, self.unchanged, self.errors
+        )
+    }
+}
+
+/// The counts of a run, in total and per Kubernetes namespace
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct SyncReport {
+    /// The number of AWS secrets listed
+    pub secrets_found: usize,
+    /// The number of AWS secrets skipped because of missing or invalid tags
+    pub skipped: usize,
+    pub total: SyncCounts,
+    pub namespaces: BTreeMap<String, SyncCounts>,
+}
+
+impl SyncReport {
+    /// Adds `counts` to both the total and the counts of `namespace`
+    pub fn record(&mut self, namespace: &str, counts: SyncCounts) {
+        self.total.add(counts);
+        self.namespaces
+            .entry(String::from(namespace))
+            .or_default()
+            .add(counts);
+    }
+}
+
+impl fmt::Display for SyncReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} AWS secret(s) found, {} skipped; {}",
+            self.secrets_found, self.skipped, self.total
+        )?;
+        for (namespace, counts) in &self.namespaces {
+            write!(f, "\n  {}: {}", namespace, counts)?;
+        }
+        Ok(())
+    }
+}
+
+/// The header of the `--report-file` CSV
+pub const REPORT_HEADER: &str =
+    "timestamp,aws_secret_name,aws_secret_arn,k8s_namespace,k8s_secret_name,result,error_message";
+
+/// A line of the `--report-file` CSV, for a Kubernetes secret or an AWS secret that was skipped
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ReportRow {
+    pub timestamp: String,
+    pub aws_secret_name: String,
+    pub aws_secret_arn: String,
+    pub k8s_namespace: String,
+    pub k8s_secret_name: String,
+    /// `synced`, `unchanged`, `error`, `skipped` or `interrupted`
+    pub result: String,
+    pub error_message: String,
+}
+
+impl ReportRow {
+    /// The row for `namespace`, with `result` and the message of its error if any
+    pub fn with_result(&self, namespace: &str, result: &str, error_message: Option<&str>) -> Self {
+        ReportRow {
+            k8s_namespace: String::from(namespace),
+            result: String::from(result),
+            error_message: error_message.map(String::from).unwrap_or_default(),
+            ..self.clone()
+        }
+    }
+
+    fn to_csv(&self) -> String {
+        [
+            &self.timestamp,
+            &self.aws_secret_name,
+            &self.aws_secret_arn,
+            &self.k8s_namespace,
+            &self.k8s_secret_name,
+            &self.result,
+            &self.error_message,
+        ]
+        .map(|field| csv_field(field))
+        .join(",")
+    }
+}
+
+// quotes `field` when it holds a comma, quote or line break, doubling its quotes
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        String::from(field)
+    }
+}
+
+// formats the rows as CSV, below the header
+pub fn format_csv(rows: &[ReportRow]) -> String {
+    let mut csv = format!("{}\n", REPORT_HEADER);
+    for row in rows {
+        csv.push_str(&row.to_csv());
+        csv.push('\n');
+    }
+    csv
+}
+
+// writes the rows to `path` through a temporary file renamed into place, so the report is
+// never read half written
+pub fn write_csv(path: &Path, rows: &[ReportRow]) -> std::io::Result<()> {
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let temp_path = path.with_file_name(format!(".{}.tmp", file_name));
+    std::fs::write(&temp_path, format_csv(rows))?;
+    std::fs::rename(&temp_path, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn report_sums_counts_per_namespace() {
+        let mut report = SyncReport {
+            secrets_found: 3,
+            ..SyncReport::default()
+        };
+        let synced = SyncCounts {
+            synced: 1,
+            ..SyncCounts::default()
+        };
+        let errored = SyncCounts {
+            errors: 1,
+            ..SyncCounts::default()
+        };
+        report.record("prod", synced);
+        report.record("prod", synced);
+        report.record("staging", errored);
+
+        assert_eq!(
+            report.total,
+            SyncCounts {
+                synced: 2,
+                unchanged: 0,
+                errors: 1
+            }
+        );
+        assert_eq!(
+            report.to_string(),
+            "3 AWS secret(s) found, 0 skipped; 2 synced, 0 unchanged, 1 error(s)\n  \
+prod: 2 synced, 0 unchanged, 0 error(s)\n  \
+staging: 0 synced, 0 unchanged, 1 error(s)"
+        );
+    }
+
+    #[test]
+    fn report_serialises_to_a_single_json_line() {
+        let mut report = SyncReport::default();
+        report.record(
+            "prod",
+            SyncCounts {
+                unchanged: 1,
+                ..SyncCounts::default()
+            },
+        );
+
+        let json = serde_json::to_string(&report).unwrap();
+        assert!(!json.contains('\n'));
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&json).unwrap(),
+            serde_json::json!({
+                "secrets_found": 0,
+                "skipped": 0,
+                "total": {"synced": 0, "unchanged": 1, "errors": 0},
+                "namespaces": {"prod": {"synced": 0, "unchanged": 1, "errors": 0}},
+            })
+        );
+    }
+
+    #[test]
+    fn csv_quotes_fields_with_separators() {
+        let row = ReportRow {
+            timestamp: String::from("2024-11-20T10:30:00Z"),
+            aws_secret_name: String::from("db-creds"),
+            aws_secret_arn: String::from("arn:db-creds"),
+            k8s_secret_name: String::from("db"),
+            ..ReportRow::default()
+        };
+        let rows = [
+            row.with_result("prod", "synced", None),
+            row.with_result(
+                "staging",
+                "error",
+                Some("Kubernetes error: \"forbidden\", denied"),
+            ),
+        ];
+        assert_eq!(
+            format_csv(&rows),
+            format!(
+                "{}\n\
+                 2024-11-20T10:30:00Z,db-creds,arn:db-creds,prod,db,synced,\n\
+                 2024-11-20T10:30:00Z,db-creds,arn:db-creds,staging,db,error,\
+                 \"Kubernetes error: \"\"forbidden\"\", denied\"\n",
+                REPORT_HEADER
+            )
+        );
+    }
+
+    #[test]
+    fn write_csv_replaces_the_report() {
+        let path = std::env::temp_dir().join(format!("report-{}.csv", std::process::id()));
+        std::fs::write(&path, "stale").unwrap();
+        write_csv(&path, &[]).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            format!("{}\n", REPORT_HEADER)
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,158 @@
+// Retrying of transient AWS and Kubernetes API errors with exponential backoff.
+
+use std::fmt::Display;
+use std::future::Future;
+use std::time::Duration;
+
+use aws_sdk_secretsmanager::config::http::HttpResponse;
+use aws_sdk_secretsmanager::error::{ProvideErrorMetadata, SdkError};
+use rand::Rng;
+use tracing::warn;
+
+/// AWS error codes that indicate throttling or a transient failure on the AWS side
+const RETRYABLE_AWS_CODES: &[&str] = &[
+    "ThrottlingException",
+    "TooManyRequestsException",
+    "RequestLimitExceeded",
+    "InternalServiceError",
+];
+
+/// Errors that can tell whether retrying the failed call may succeed
+pub trait Retryable {
+    fn is_retryable(&self) -> bool;
+}
+
+impl<E: ProvideErrorMetadata> Retryable for SdkError<E, HttpResponse> {
+    fn is_retryable(&self) -> bool {
+        match self {
+            SdkError::TimeoutError(_) | SdkError::ResponseError(_) => true,
+            SdkError::DispatchFailure(failure) => failure.is_io() || failure.is_timeout(),
+            SdkError::ServiceError(e) => {
+                let status = e.raw().status().as_u16();
+                status == 429
+                    || status >= 500
+                    || e.err()
+                        .code()
+                        .is_some_and(|code| RETRYABLE_AWS_CODES.contains(&code))
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Retryable for kube::Error {
+    fn is_retryable(&self) -> bool {
+        match self {
+            kube::Error::Api(response) => response.code == 429 || response.code >= 500,
+            kube::Error::HyperError(_) | kube::Error::Service(_) => true,
+            _ => false,
+        }
+    }
+}
+
+/// How often and how quickly failed calls are retried
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub base_delay: Duration,
+}
+
+impl RetryPolicy {
+    // the delay before retry number `attempt`, doubling each time with up to 50% jitter
+    fn delay(&self, attempt: u32) -> Duration {
+        let delay = self.base_delay.saturating_mul(2u32.saturating_pow(attempt));
+        delay.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+    }
+}
+
+/// Calls `op` until it succeeds, fails with a permanent error or runs out of retries
+pub async fn retry_with_backoff<T, E, F, Fut>(policy: &RetryPolicy, mut op: F) -> Result<T, E>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+    E: Retryable + Display,
+{
+    let mut attempt = 0;
+    loop {
+        match op().await {
+            Err(e) if attempt < policy.max_retries && e.is_retryable() => {
+                let delay = policy.delay(attempt);
+                attempt += 1;
+                warn!(
+                    "Retrying in {:?} (attempt {}/{}) after error: {}",
+                    delay, attempt, policy.max_retries, e
+                );
+                tokio::time::sleep(delay).await;
+            }
+            result => return result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kube::core::ErrorResponse;
+
+    fn api_error(code: u16) -> kube::Error {
+        kube::Error::Api(ErrorResponse {
+            status: String::from("Failure"),
+            message: String::new(),
+            reason: String::new(),
+            code,
+        })
+    }
+
+    const POLICY: RetryPolicy = RetryPolicy {
+        max_retries: 3,
+        base_delay: Duration::from_millis(1),
+    };
+
+    #[test]
+    fn kube_errors_are_classified_by_status() {
+        assert!(api_error(429).is_retryable());
+        assert!(api_error(503).is_retryable());
+        assert!(!api_error(403).is_retryable());
+        assert!(!api_error(404).is_retryable());
+    }
+
+    #[tokio::test]
+    async fn retries_transient_errors_until_out_of_retries() {
+        let mut calls = 0;
+        let result: Result<(), _> = retry_with_backoff(&POLICY, || {
+            calls += 1;
+            async { Err(api_error(503)) }
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(calls, 4);
+    }
+
+    #[tokio::test]
+    async fn does_not_retry_permanent_errors() {
+        let mut calls = 0;
+        let result: Result<(), _> = retry_with_backoff(&POLICY, || {
+            calls += 1;
+            async { Err(api_error(404)) }
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(calls, 1);
+    }
+
+    #[tokio::test]
+    async fn returns_first_success() {
+        let mut calls = 0;
+        let result = retry_with_backoff(&POLICY, || {
+            calls += 1;
+            let result = if calls < 3 {
+                Err(api_error(500))
+            } else {
+                Ok(calls)
+            };
+            async move { result }
+        })
+        .await;
+        assert_eq!(result.unwrap(), 3);
+    }
+}
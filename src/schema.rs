@@ -0,0 +1,59 @@
+// JSON Schemas documenting the structure of a secret, printed by the `generate-schema`
+// subcommand.
+//
+// The values of a secret are always strings once parsed, so the schema only documents which
+// keys the secret has; it can be committed next to the code reading the secret, and the keys
+// of later versions of the secret validated against it.
+
+use std::collections::BTreeSet;
+
+use serde_json::{json, Value};
+
+use crate::data::SecretData;
+
+/// The JSON Schema dialect of the generated schemas
+pub const SCHEMA_DIALECT: &str = "https://json-schema.org/draft/2020-12/schema";
+
+// the schema of an object with exactly the keys of `secret_value`, each holding a string
+pub fn secret_schema(secret_name: &str, secret_value: &SecretData) -> Value {
+    let keys: BTreeSet<&str> = secret_value.keys().collect();
+    let properties: serde_json::Map<String, Value> = keys
+        .iter()
+        .map(|key| (String::from(*key), json!({ "type": "string" })))
+        .collect();
+    json!({
+        "$schema": SCHEMA_DIALECT,
+        "title": secret_name,
+        "type": "object",
+        "properties": properties,
+        "required": keys,
+        "additionalProperties": false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn secret_schema_requires_every_key_as_a_string() {
+        let secret_value = SecretData::from([
+            (String::from("username"), String::from("admin")),
+            (String::from("password"), String::from("hunter2")),
+        ]);
+        assert_eq!(
+            secret_schema("prod/db-creds", &secret_value),
+            json!({
+                "$schema": "https://json-schema.org/draft/2020-12/schema",
+                "title": "prod/db-creds",
+                "type": "object",
+                "properties": {
+                    "password": { "type": "string" },
+                    "username": { "type": "string" },
+                },
+                "required": ["password", "username"],
+                "additionalProperties": false,
+            })
+        );
+    }
+}
@@ -0,0 +1,167 @@
+// Transforming the values of a secret with a Rhai script, with `--transform-script`.
+//
+// The script sees the values as the map `secret` and evaluates to the map of transformed
+// values. Rhai has no access to the file system, network or environment, and the engine stops
+// the script once it has run for longer than `--transform-timeout-ms`.
+
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use rhai::{Dynamic, Engine, EvalAltResult, Map, Scope};
+
+use crate::data::SecretData;
+
+/// Errors raised while transforming the values of a secret with a script
+#[derive(thiserror::Error, Debug)]
+pub enum ScriptError {
+    /// The script could not be read
+    #[error("failed to read transform script: {0}")]
+    Io(#[from] std::io::Error),
+
+    /// The script is not valid Rhai
+    #[error("failed to compile transform script: {0}")]
+    Compile(#[from] rhai::ParseError),
+
+    /// The script ran for longer than `--transform-timeout-ms`
+    #[error("transform script timed out after {0:?}")]
+    Timeout(Duration),
+
+    /// The script raised an error or didn't evaluate to a map
+    #[error("transform script failed: {0}")]
+    Eval(Box<EvalAltResult>),
+
+    /// A value of the map returned by the script is not a string
+    #[error("transform script returned a {type_name} for key {key}, not a string")]
+    NotAString {
+        key: String,
+        type_name: &'static str,
+    },
+}
+
+// runs the script at `path` on the secret values, on a blocking thread so a slow script
+// doesn't hold up the other secrets
+pub async fn transform_with_script(
+    path: &Path,
+    timeout: Duration,
+    secret_value: SecretData,
+) -> Result<SecretData, ScriptError> {
+    let script = tokio::fs::read_to_string(path).await?;
+    tokio::task::spawn_blocking(move || run_script(&script, timeout, secret_value))
+        .await
+        .expect("transform script panicked")
+}
+
+// evaluates `script` with the secret values as `secret`, stopping it after `timeout`
+fn run_script(
+    script: &str,
+    timeout: Duration,
+    secret_value: SecretData,
+) -> Result<SecretData, ScriptError> {
+    let deadline = Instant::now() + timeout;
+    let mut engine = Engine::new();
+    // the callback runs between operations, so even a script stuck in a loop is stopped
+    engine.on_progress(move |_| (Instant::now() >= deadline).then_some(Dynamic::UNIT));
+    let ast = engine.compile(script)?;
+
+    let secret: Map = secret_value
+        .into_iter()
+        .map(|(key, value)| (key.into(), Dynamic::from(value)))
+        .collect();
+    let mut scope = Scope::new();
+    scope.push("secret", secret);
+    let transformed = match engine.eval_ast_with_scope::<Map>(&mut scope, &ast) {
+        Ok(transformed) => transformed,
+        Err(e) if matches!(*e, EvalAltResult::ErrorTerminated(..)) => {
+            return Err(ScriptError::Timeout(timeout))
+        }
+        Err(e) => return Err(ScriptError::Eval(e)),
+    };
+    transformed
+        .into_iter()
+        .map(|(key, value)| match value.into_string() {
+            Ok(value) => Ok((key.into(), value)),
+            Err(type_name) => Err(ScriptError::NotAString {
+                key: key.into(),
+                type_name,
+            }),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    fn secret_value() -> SecretData {
+        SecretData::from([
+            (String::from("host"), String::from("db.example.com")),
+            (String::from("password"), String::from("\"hunter2\"")),
+        ])
+    }
+
+    #[test]
+    fn run_script_returns_the_transformed_map() {
+        let script = r#"
+            let password = secret.password;
+            password.trim();
+            secret.password = password.sub_string(1, password.len() - 2);
+            secret.jdbc_url = `jdbc:postgresql://${secret.host}:5432/app`;
+            secret
+        "#;
+        let transformed = run_script(script, TIMEOUT, secret_value()).unwrap();
+
+        assert_eq!(
+            transformed,
+            SecretData::from([
+                (String::from("host"), String::from("db.example.com")),
+                (String::from("password"), String::from("hunter2")),
+                (
+                    String::from("jdbc_url"),
+                    String::from("jdbc:postgresql://db.example.com:5432/app")
+                ),
+            ])
+        );
+    }
+
+    #[test]
+    fn run_script_stops_a_script_after_the_timeout() {
+        let started = Instant::now();
+        let result = run_script("loop {}", Duration::from_millis(50), secret_value());
+
+        assert!(matches!(result, Err(ScriptError::Timeout(_))));
+        assert!(started.elapsed() < TIMEOUT);
+    }
+
+    #[test]
+    fn run_script_rejects_scripts_not_returning_a_map_of_strings() {
+        assert!(matches!(
+            run_script("secret.len()", TIMEOUT, secret_value()),
+            Err(ScriptError::Eval(_))
+        ));
+        assert!(matches!(
+            run_script("#{ port: 5432 }", TIMEOUT, secret_value()),
+            Err(ScriptError::NotAString { key, type_name: "i64" }) if key == "port"
+        ));
+        assert!(matches!(
+            run_script("secret.", TIMEOUT, secret_value()),
+            Err(ScriptError::Compile(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn transform_with_script_reads_the_script_file() {
+        let path = std::env::temp_dir().join("transform-script-test.rhai");
+        std::fs::write(&path, "#{ username: \"admin\" }").unwrap();
+        let transformed = transform_with_script(&path, TIMEOUT, secret_value())
+            .await
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            transformed,
+            SecretData::from([(String::from("username"), String::from("admin"))])
+        );
+    }
+}
@@ -0,0 +1,234 @@
+// Syncing to Bitnami `SealedSecret` resources with `--output-mode sealed-secret`, for GitOps
+// workflows that keep the encrypted secrets in version control.
+//
+// The data is encrypted by `kubeseal` with the certificate of the Sealed Secrets controller of
+// each cluster, fetched through the API server proxy as `kubeseal --fetch-cert` would.
+
+use std::path::PathBuf;
+use std::process::{ExitStatus, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use k8s_openapi::api::core::v1::Secret;
+use kube::api::{Api, ApiResource, DynamicObject, GroupVersionKind, Patch, PatchParams};
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+use crate::k8s::{expected_data, Applied};
+use crate::retry::Retryable;
+
+/// The API group of the Sealed Secrets resources
+pub const SEALED_SECRETS_GROUP: &str = "bitnami.com";
+
+/// The API version of the Sealed Secrets resources
+pub const SEALED_SECRETS_VERSION: &str = "v1alpha1";
+
+/// Errors raised while sealing a Kubernetes secret
+#[derive(thiserror::Error, Debug)]
+pub enum SealError {
+    /// The certificate or the existing secret could not be read from the cluster
+    #[error("failed to read from the cluster: {0}")]
+    Kubernetes(#[from] kube::Error),
+
+    /// `kubeseal` could not be started, or its pipes or certificate file failed
+    #[error("failed to run kubeseal: {0}")]
+    Io(#[from] std::io::Error),
+
+    /// `kubeseal` exited with a non-zero status
+    #[error("kubeseal failed with {status}: {stderr}")]
+    Failed { status: ExitStatus, stderr: String },
+
+    /// The secret could not be serialised, or `kubeseal` didn't write a resource
+    #[error("invalid kubeseal JSON: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+impl Retryable for SealError {
+    fn is_retryable(&self) -> bool {
+        match self {
+            SealError::Kubernetes(e) => e.is_retryable(),
+            _ => false,
+        }
+    }
+}
+
+// the `SealedSecret` resource of the Sealed Secrets controller
+pub fn sealed_secret_resource() -> ApiResource {
+    let gvk = GroupVersionKind::gvk(SEALED_SECRETS_GROUP, SEALED_SECRETS_VERSION, "SealedSecret");
+    ApiResource::from_gvk_with_plural(&gvk, "sealedsecrets")
+}
+
+// fetches the PEM certificate of the Sealed Secrets controller `namespace`/`name`
+pub async fn fetch_certificate(
+    client: &kube::Client,
+    (namespace, name): &(String, String),
+) -> Result<String, kube::Error> {
+    let uri = format!(
+        "/api/v1/namespaces/{}/services/{}:http/proxy/v1/cert.pem",
+        namespace, name
+    );
+    let request = http::Request::get(uri).body(Vec::new()).unwrap();
+    client.request_text(request).await
+}
+
+// encrypts `k8s_secret` into a `SealedSecret` with `kubeseal`, using `certificate`
+pub async fn seal_secret(
+    kubeseal: &str,
+    certificate: &str,
+    k8s_secret: &Secret,
+) -> Result<DynamicObject, SealError> {
+    let input = serde_json::to_vec(k8s_secret)?;
+    let certificate_file = CertificateFile::write(certificate).await?;
+    let mut child = Command::new(kubeseal)
+        .arg("--format")
+        .arg("json")
+        .arg("--cert")
+        .arg(&certificate_file.0)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+    let mut stdin = child.stdin.take().unwrap();
+    // the secret is written while the output is read, so neither pipe can fill up
+    let write = async move {
+        match stdin.write_all(&input).await {
+            // kubeseal may exit before reading all of a secret it rejects
+            Err(e) if e.kind() != std::io::ErrorKind::BrokenPipe => Err(e),
+            _ => Ok(()),
+        }
+    };
+    let (_, output) = tokio::try_join!(write, child.wait_with_output())?;
+    if !output.status.success() {
+        return Err(SealError::Failed {
+            status: output.status,
+            stderr: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        });
+    }
+    Ok(serde_json::from_slice(&output.stdout)?)
+}
+
+// seals `k8s_secret` with the certificate of the controller of the cluster of `client`,
+// unless the secret the controller unseals already holds its data; the sealed data differs
+// on every run, so comparing it would update every secret every time
+pub async fn seal_if_changed(
+    client: &kube::Client,
+    controller: &(String, String),
+    kubeseal: &str,
+    k8s_secret: &Secret,
+) -> Result<Option<DynamicObject>, SealError> {
+    let namespace = k8s_secret.metadata.namespace.as_deref().unwrap();
+    let name = k8s_secret.metadata.name.as_deref().unwrap();
+    let secrets: Api<Secret> = Api::namespaced(client.clone(), namespace);
+    if let Some(existing) = secrets.get_opt(name).await? {
+        if existing.data.unwrap_or_default() == expected_data(k8s_secret)
+            && existing.type_ == k8s_secret.type_
+        {
+            return Ok(None);
+        }
+    }
+    let certificate = fetch_certificate(client, controller).await?;
+    Ok(Some(seal_secret(kubeseal, &certificate, k8s_secret).await?))
+}
+
+// applies the `SealedSecret` built by `seal_if_changed`, if any
+pub async fn apply_sealed_secret(
+    client: &kube::Client,
+    sealed_secret: Option<&DynamicObject>,
+    params: &PatchParams,
+) -> Result<Applied, kube::Error> {
+    let Some(sealed_secret) = sealed_secret else {
+        return Ok(Applied::Unchanged);
+    };
+    let namespace = sealed_secret
+        .metadata
+        .namespace
+        .as_deref()
+        .unwrap_or_default();
+    let name = sealed_secret.metadata.name.as_deref().unwrap();
+    let sealed_secrets: Api<DynamicObject> =
+        Api::namespaced_with(client.clone(), namespace, &sealed_secret_resource());
+    sealed_secrets
+        .patch(name, params, &Patch::Apply(sealed_secret))
+        .await?;
+    Ok(Applied::Updated)
+}
+
+/// A temporary file holding a certificate for `kubeseal --cert`, deleted once dropped
+struct CertificateFile(PathBuf);
+
+impl CertificateFile {
+    async fn write(certificate: &str) -> std::io::Result<Self> {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "k8s-aws-secrets-sync-{}-{}.pem",
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::SeqCst)
+        ));
+        tokio::fs::write(&path, certificate).await?;
+        Ok(CertificateFile(path))
+    }
+}
+
+impl Drop for CertificateFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::args::tests::args;
+    use crate::data::SecretData;
+    use crate::k8s::{build_k8s_secret, SecretTemplate};
+    use chrono::Utc;
+    use std::os::unix::fs::PermissionsExt;
+
+    // a `kubeseal` stand-in wrapping its input and the certificate it was given in a
+    // `SealedSecret`
+    fn fake_kubeseal(dir: &std::path::Path) -> PathBuf {
+        let path = dir.join("kubeseal");
+        std::fs::write(
+            &path,
+            r#"#!/bin/sh
+[ "$1 $2 $3" = "--format json --cert" ] || exit 2
+printf '{"apiVersion":"bitnami.com/v1alpha1","kind":"SealedSecret","metadata":{"name":"db-creds","namespace":"prod"},"spec":{"cert":"%s","input":%s}}' "$(cat "$4")" "$(cat)"
+"#,
+        )
+        .unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        path
+    }
+
+    #[tokio::test]
+    async fn seal_secret_pipes_the_secret_through_kubeseal() {
+        let dir = std::env::temp_dir().join(format!("kubeseal-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let kubeseal = fake_kubeseal(&dir);
+        let template = SecretTemplate::from_args(&args(&[]), Utc::now());
+        let data = SecretData::from([(String::from("password"), String::from("aHVudGVyMg=="))]);
+        let k8s_secret = build_k8s_secret("db-creds", "prod", data, &template, None);
+
+        let sealed = seal_secret(kubeseal.to_str().unwrap(), "CERTIFICATE", &k8s_secret)
+            .await
+            .unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(sealed.types.unwrap().kind, "SealedSecret");
+        assert_eq!(sealed.data["spec"]["cert"], "CERTIFICATE");
+        assert_eq!(
+            sealed.data["spec"]["input"]["data"]["password"],
+            "aHVudGVyMg=="
+        );
+
+        let failed = seal_secret("false", "CERTIFICATE", &k8s_secret).await;
+        assert!(matches!(failed, Err(SealError::Failed { .. })));
+    }
+
+    #[test]
+    fn sealed_secret_resource_is_bitnami_v1alpha1() {
+        let resource = sealed_secret_resource();
+        assert_eq!(resource.api_version, "bitnami.com/v1alpha1");
+        assert_eq!(resource.plural, "sealedsecrets");
+    }
+}
@@ -0,0 +1,345 @@
+// Reading configuration from AWS Systems Manager Parameter Store, with `--ssm-parameter-prefix`.
+//
+// The parameters below the prefix are synced into a single ConfigMap rather than a secret, as
+// Parameter Store values are not sensitive by convention; `SecureString` parameters are the
+// exception, so they are neither decrypted nor synced. There is no SSM crate in the AWS SDK
+// dependencies, so `GetParametersByPath` is called directly, signed with the credentials of the
+// AWS configuration.
+
+use std::collections::BTreeMap;
+use std::time::SystemTime;
+
+use aws_config::SdkConfig;
+use aws_credential_types::provider::error::CredentialsError;
+use aws_credential_types::provider::{ProvideCredentials, SharedCredentialsProvider};
+use aws_sigv4::http_request::{sign, SignableBody, SignableRequest, SigningSettings};
+use aws_sigv4::sign::v4;
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full};
+use hyper::header::{CONTENT_TYPE, HOST};
+use hyper::{Method, Request, StatusCode};
+use hyper_rustls::HttpsConnector;
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::TokioExecutor;
+use tracing::{debug, warn};
+
+use crate::args::AwsPartition;
+use crate::aws::service_endpoint;
+use crate::retry::Retryable;
+
+/// Errors raised while reading parameters from SSM Parameter Store
+#[derive(thiserror::Error, Debug)]
+pub enum SsmError {
+    /// The AWS configuration has no region or no credentials
+    #[error("the AWS configuration has no {0}")]
+    MissingConfig(&'static str),
+
+    /// The credentials could not be loaded
+    #[error("failed to load AWS credentials: {0}")]
+    Credentials(#[from] CredentialsError),
+
+    /// The native TLS root certificates could not be loaded
+    #[error("failed to load TLS root certificates: {0}")]
+    Tls(std::io::Error),
+
+    /// The request could not be signed
+    #[error("failed to sign SSM request: {0}")]
+    Signing(String),
+
+    /// The request could not be built
+    #[error("invalid SSM request: {0}")]
+    Request(#[from] hyper::http::Error),
+
+    /// The request could not be sent to SSM
+    #[error("SSM request failed: {0}")]
+    Http(#[from] hyper_util::client::legacy::Error),
+
+    /// The response body could not be read
+    #[error("failed to read SSM response: {0}")]
+    Body(#[from] hyper::Error),
+
+    /// SSM responded with an error status
+    #[error("SSM returned {status}: {body}")]
+    Status { status: StatusCode, body: String },
+
+    /// The response is not valid JSON
+    #[error("failed to parse SSM response: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+impl Retryable for SsmError {
+    fn is_retryable(&self) -> bool {
+        match self {
+            SsmError::Http(_) => true,
+            SsmError::Status { status, body } => {
+                *status == StatusCode::TOO_MANY_REQUESTS
+                    || status.is_server_error()
+                    || body.contains("ThrottlingException")
+            }
+            _ => false,
+        }
+    }
+}
+
+/// A client calling `GetParametersByPath` in the region of an AWS configuration
+#[derive(Clone)]
+pub struct SsmClient {
+    region: String,
+    endpoint: String,
+    credentials: SharedCredentialsProvider,
+    http: Client<HttpsConnector<HttpConnector>, Full<Bytes>>,
+}
+
+impl SsmClient {
+    /// Creates a client for the region and credentials of `config`, in `partition` when given
+    pub fn from_config(
+        config: &SdkConfig,
+        partition: Option<AwsPartition>,
+    ) -> Result<Self, SsmError> {
+        let region = config
+            .region()
+            .ok_or(SsmError::MissingConfig("region"))?
+            .to_string();
+        let credentials = config
+            .credentials_provider()
+            .ok_or(SsmError::MissingConfig("credentials"))?;
+        let partition = partition.unwrap_or(if region.starts_with("cn-") {
+            AwsPartition::AwsCn
+        } else {
+            AwsPartition::Aws
+        });
+        let connector = hyper_rustls::HttpsConnectorBuilder::new()
+            .with_native_roots()
+            .map_err(SsmError::Tls)?
+            .https_or_http()
+            .enable_http1()
+            .build();
+        Ok(SsmClient {
+            endpoint: service_endpoint("ssm", &region, partition),
+            region,
+            credentials,
+            http: Client::builder(TokioExecutor::new()).build(connector),
+        })
+    }
+
+    // builds the request for the SSM `action` with the JSON `body`, signed with SigV4
+    async fn signed_request(
+        &self,
+        action: &str,
+        body: &serde_json::Value,
+    ) -> Result<Request<Full<Bytes>>, SsmError> {
+        let body = serde_json::to_vec(body)?;
+        let host = self.endpoint.trim_start_matches("https://");
+        let mut request = Request::builder()
+            .method(Method::POST)
+            .uri(format!("{}/", self.endpoint))
+            .header(HOST, host)
+            .header(CONTENT_TYPE, "application/x-amz-json-1.1")
+            .header("X-Amz-Target", format!("AmazonSSM.{}", action))
+            .body(Full::new(Bytes::from(body.clone())))?;
+
+        let identity = self.credentials.provide_credentials().await?.into();
+        let params = v4::SigningParams::builder()
+            .identity(&identity)
+            .region(&self.region)
+            .name("ssm")
+            .time(SystemTime::now())
+            .settings(SigningSettings::default())
+            .build()
+            .map_err(|e| SsmError::Signing(e.to_string()))?
+            .into();
+        let headers = request
+            .headers()
+            .iter()
+            .filter_map(|(name, value)| Some((name.as_str(), value.to_str().ok()?)));
+        let signable = SignableRequest::new(
+            "POST",
+            request.uri().to_string(),
+            headers,
+            SignableBody::Bytes(&body),
+        )
+        .map_err(|e| SsmError::Signing(e.to_string()))?;
+        let (instructions, _) = sign(signable, &params)
+            .map_err(|e| SsmError::Signing(e.to_string()))?
+            .into_parts();
+        instructions.apply_to_request_http1x(&mut request);
+        Ok(request)
+    }
+
+    // sends a signed request for the SSM `action` with the JSON `body`
+    async fn request(
+        &self,
+        action: &str,
+        body: &serde_json::Value,
+    ) -> Result<serde_json::Value, SsmError> {
+        let request = self.signed_request(action, body).await?;
+        let response = self.http.request(request).await?;
+        let status = response.status();
+        let body = response.into_body().collect().await?.to_bytes();
+        if !status.is_success() {
+            return Err(SsmError::Status {
+                status,
+                body: String::from_utf8_lossy(&body).into_owned(),
+            });
+        }
+        Ok(serde_json::from_slice(&body)?)
+    }
+
+    /// Gets the name and value of every parameter below `path`, recursively, leaving out the
+    /// `SecureString` parameters
+    pub async fn get_parameters_by_path(
+        &self,
+        path: &str,
+    ) -> Result<Vec<(String, String)>, SsmError> {
+        let mut parameters = Vec::new();
+        let mut next_token: Option<String> = None;
+        loop {
+            let mut body = serde_json::json!({
+                "Path": path,
+                "Recursive": true,
+                "WithDecryption": false,
+            });
+            if let Some(token) = next_token {
+                body["NextToken"] = serde_json::Value::String(token);
+            }
+            let response = self.request("GetParametersByPath", &body).await?;
+            parameters.extend(parameters_of(&response));
+            next_token = response["NextToken"].as_str().map(String::from);
+            if next_token.is_none() {
+                break;
+            }
+        }
+        debug!("Found {} SSM parameter(s) below {}", parameters.len(), path);
+        Ok(parameters)
+    }
+}
+
+// the name and value of the parameters of a `GetParametersByPath` response, skipping the
+// `SecureString` parameters with a warning so their values never reach a ConfigMap
+pub fn parameters_of(response: &serde_json::Value) -> Vec<(String, String)> {
+    response["Parameters"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|parameter| {
+            if parameter["Type"] == "SecureString" {
+                warn!(
+                    "Skipping SecureString SSM parameter {}, which can't be synced into a ConfigMap",
+                    parameter["Name"].as_str().unwrap_or_default()
+                );
+                return None;
+            }
+            Some((
+                String::from(parameter["Name"].as_str()?),
+                String::from(parameter["Value"].as_str()?),
+            ))
+        })
+        .collect()
+}
+
+// uses the name of each parameter relative to `prefix` as its ConfigMap key, replacing the
+// `/` of nested parameters with `.` as ConfigMap keys can't contain slashes
+pub fn parameter_data(prefix: &str, parameters: Vec<(String, String)>) -> BTreeMap<String, String> {
+    let prefix = prefix.trim_end_matches('/');
+    parameters
+        .into_iter()
+        .map(|(name, value)| {
+            let relative = name.strip_prefix(prefix).unwrap_or(&name);
+            (relative.trim_start_matches('/').replace('/', "."), value)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn signed_request_targets_the_partition_endpoint() {
+        let config = SdkConfig::builder()
+            .region(aws_config::Region::new("cn-north-1"))
+            .credentials_provider(SharedCredentialsProvider::new(
+                aws_credential_types::Credentials::new("AKID", "SECRET", None, None, "test"),
+            ))
+            .build();
+        let ssm = SsmClient::from_config(&config, None).unwrap();
+        let request = ssm
+            .signed_request(
+                "GetParametersByPath",
+                &serde_json::json!({"Path": "/myapp"}),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(request.uri(), "https://ssm.cn-north-1.amazonaws.com.cn/");
+        assert_eq!(
+            request.headers()["X-Amz-Target"],
+            "AmazonSSM.GetParametersByPath"
+        );
+        let authorization = request.headers()["Authorization"].to_str().unwrap();
+        assert!(authorization.starts_with("AWS4-HMAC-SHA256 Credential=AKID/"));
+        assert!(authorization.contains("/cn-north-1/ssm/aws4_request"));
+    }
+
+    #[test]
+    fn parameters_of_reads_names_and_values() {
+        let response = serde_json::json!({
+            "Parameters": [
+                {"Name": "/myapp/prod/log-level", "Value": "info", "Type": "String"},
+                {"Name": "/myapp/prod/db/host", "Value": "db.internal", "Type": "String"},
+            ],
+            "NextToken": "abc",
+        });
+        assert_eq!(
+            parameters_of(&response),
+            [
+                (String::from("/myapp/prod/log-level"), String::from("info")),
+                (
+                    String::from("/myapp/prod/db/host"),
+                    String::from("db.internal")
+                ),
+            ]
+        );
+        assert!(parameters_of(&serde_json::json!({})).is_empty());
+    }
+
+    #[test]
+    fn parameters_of_skips_secure_strings() {
+        let response = serde_json::json!({
+            "Parameters": [
+                {"Name": "/myapp/prod/log-level", "Value": "info", "Type": "String"},
+                {
+                    "Name": "/myapp/prod/db/password",
+                    "Value": "AQICAHh...encrypted",
+                    "Type": "SecureString",
+                },
+                {"Name": "/myapp/prod/hosts", "Value": "a,b", "Type": "StringList"},
+            ],
+        });
+        assert_eq!(
+            parameters_of(&response),
+            [
+                (String::from("/myapp/prod/log-level"), String::from("info")),
+                (String::from("/myapp/prod/hosts"), String::from("a,b")),
+            ]
+        );
+    }
+
+    #[test]
+    fn parameter_data_uses_names_relative_to_the_prefix() {
+        let parameters = vec![
+            (String::from("/myapp/prod/log-level"), String::from("info")),
+            (
+                String::from("/myapp/prod/db/host"),
+                String::from("db.internal"),
+            ),
+        ];
+        let expected = BTreeMap::from([
+            (String::from("db.host"), String::from("db.internal")),
+            (String::from("log-level"), String::from("info")),
+        ]);
+        assert_eq!(parameter_data("/myapp/prod", parameters.clone()), expected);
+        assert_eq!(parameter_data("/myapp/prod/", parameters), expected);
+    }
+}
@@ -0,0 +1,107 @@
+// Transforming the values of a secret with an external command, with `--transform-command`.
+//
+// The command is run by `sh -c`, so it can be a pipeline. It reads the values as a JSON object
+// of strings on stdin, and writes the transformed object the same way on stdout.
+
+use std::process::{ExitStatus, Stdio};
+use std::time::Duration;
+
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+use crate::data::SecretData;
+
+/// Errors raised while transforming the values of a secret
+#[derive(thiserror::Error, Debug)]
+pub enum TransformError {
+    /// The command could not be started or its pipes failed
+    #[error("failed to run transform command: {0}")]
+    Io(#[from] std::io::Error),
+
+    /// The command ran for longer than `--transform-timeout-ms`
+    #[error("transform command timed out after {0:?}")]
+    Timeout(Duration),
+
+    /// The command exited with a non-zero status
+    #[error("transform command failed with {status}: {stderr}")]
+    Failed { status: ExitStatus, stderr: String },
+
+    /// The command didn't write a JSON object of strings
+    #[error("transform command wrote invalid JSON: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+// pipes the secret values through `command` as JSON, killing it after `timeout`
+pub async fn transform_secret(
+    command: &str,
+    timeout: Duration,
+    secret_value: SecretData,
+) -> Result<SecretData, TransformError> {
+    let input = serde_json::to_vec(&secret_value.0)?;
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+    let mut stdin = child.stdin.take().unwrap();
+    // the values are written while the output is read, so neither pipe can fill up
+    let write = async move {
+        match stdin.write_all(&input).await {
+            // a command may not need all of its input
+            Err(e) if e.kind() != std::io::ErrorKind::BrokenPipe => Err(e),
+            _ => Ok(()),
+        }
+    };
+    let run = async { tokio::try_join!(write, child.wait_with_output()) };
+    let (_, output) = tokio::time::timeout(timeout, run)
+        .await
+        .map_err(|_| TransformError::Timeout(timeout))??;
+    if !output.status.success() {
+        return Err(TransformError::Failed {
+            status: output.status,
+            stderr: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        });
+    }
+    Ok(SecretData(serde_json::from_slice(&output.stdout)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    fn secret_value() -> SecretData {
+        SecretData::from([(String::from("username"), String::from("admin"))])
+    }
+
+    #[tokio::test]
+    async fn transform_secret_reads_the_output_of_the_command() {
+        let transformed = transform_secret("sed s/admin/root/", TIMEOUT, secret_value())
+            .await
+            .unwrap();
+        assert_eq!(
+            transformed,
+            SecretData::from([(String::from("username"), String::from("root"))])
+        );
+    }
+
+    #[tokio::test]
+    async fn transform_secret_fails_with_the_command() {
+        let failed = transform_secret("echo broken >&2; exit 3", TIMEOUT, secret_value()).await;
+        assert!(matches!(
+            failed,
+            Err(TransformError::Failed { status, stderr }) if status.code() == Some(3) && stderr == "broken"
+        ));
+
+        let invalid = transform_secret("echo '[1]'", TIMEOUT, secret_value()).await;
+        assert!(matches!(invalid, Err(TransformError::Json(_))));
+
+        let timeout = Duration::from_millis(50);
+        let slow = transform_secret("sleep 5", timeout, secret_value()).await;
+        assert!(matches!(slow, Err(TransformError::Timeout(_))));
+    }
+}
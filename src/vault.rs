@@ -0,0 +1,544 @@
+// Reading secrets from the KV v2 secrets engine of HashiCorp Vault, with `--source vault`.
+//
+// Vault secrets are listed below `--vault-path-prefix` and their custom metadata is used in
+// place of the tags of an AWS secret, so the namespace, secret name and filename tags work
+// the same for both sources. `VAULT_CACERT` names the CA certificate of a server with a
+// private certificate.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use aws_sdk_secretsmanager::types::{SecretListEntry, Tag};
+use tracing::debug;
+use vaultrs::client::{VaultClientSettingsBuilder, VaultClientSettingsBuilderError};
+use vaultrs::error::ClientError;
+use vaultrs::{auth, kv2};
+
+use crate::data::{ParseError, SecretData};
+use crate::retry::Retryable;
+
+/// The environment variable holding the address of the Vault server
+pub const VAULT_ADDR_ENV: &str = "VAULT_ADDR";
+
+/// The environment variable holding the Vault token
+pub const VAULT_TOKEN_ENV: &str = "VAULT_TOKEN";
+
+/// The service account token of the pod, used to log in with `--vault-auth-method kubernetes`
+pub const SERVICE_ACCOUNT_TOKEN_PATH: &str = "/var/run/secrets/kubernetes.io/serviceaccount/token";
+
+/// Errors raised while reading secrets from Vault
+#[derive(thiserror::Error, Debug)]
+pub enum VaultError {
+    /// A required environment variable is not set
+    #[error("environment variable {var} must be set to read secrets from Vault")]
+    MissingEnv { var: &'static str },
+
+    /// The service account token of the pod could not be read
+    #[error("failed to read service account token {path}: {source}")]
+    ServiceAccountToken {
+        path: &'static str,
+        source: std::io::Error,
+    },
+
+    /// `VAULT_ADDR` is not a valid URL
+    #[error("invalid Vault address {address}: {source}")]
+    Address {
+        address: String,
+        source: url::ParseError,
+    },
+
+    /// The client could not be configured, e.g. because `VAULT_ADDR` is not an HTTP URL
+    #[error("invalid Vault client settings: {0}")]
+    Settings(#[from] VaultClientSettingsBuilderError),
+
+    /// The HTTP client could not be created, e.g. because `VAULT_CACERT` can't be read
+    #[error("failed to create Vault client: {0}")]
+    Client(#[source] ClientError),
+
+    /// A request to Vault failed or returned an error
+    #[error("Vault request for {path} failed: {source}")]
+    Request { path: String, source: ClientError },
+
+    /// The secret data holds a value that is not a string
+    #[error("failed to parse Vault secret: {0}")]
+    Parse(#[from] ParseError),
+}
+
+impl Retryable for VaultError {
+    fn is_retryable(&self) -> bool {
+        match self {
+            VaultError::Request {
+                source: ClientError::APIError { code, .. },
+                ..
+            } => *code == 429 || *code >= 500,
+            // transport errors, and error responses without a Vault error body, e.g. from a proxy
+            VaultError::Request {
+                source: ClientError::RestClientError { .. },
+                ..
+            } => true,
+            _ => false,
+        }
+    }
+}
+
+/// A client for a KV v2 secrets engine mounted at `mount`
+#[derive(Clone)]
+pub struct VaultClient {
+    client: Arc<vaultrs::client::VaultClient>,
+    mount: String,
+}
+
+impl VaultClient {
+    /// Creates a client for the server at `address`, authenticating with `token`
+    pub fn new(address: &str, token: &str, mount: &str) -> Result<Self, VaultError> {
+        // the settings builder panics on an invalid address, so it is parsed first
+        let address = url::Url::parse(address).map_err(|source| VaultError::Address {
+            address: String::from(address),
+            source,
+        })?;
+        let settings = VaultClientSettingsBuilder::default()
+            .address(address)
+            .token(token)
+            .build()?;
+        let client = vaultrs::client::VaultClient::new(settings).map_err(VaultError::Client)?;
+        Ok(VaultClient {
+            client: Arc::new(client),
+            mount: mount.trim_matches('/').to_owned(),
+        })
+    }
+
+    /// Creates a client for the server in `VAULT_ADDR`, authenticating with `VAULT_TOKEN`
+    pub fn from_env(mount: &str) -> Result<Self, VaultError> {
+        VaultClient::from_vars(mount, env_var)
+    }
+
+    // creates a client from the variables found by `lookup`, which stands in for the environment
+    fn from_vars(mount: &str, lookup: impl Fn(&str) -> Option<String>) -> Result<Self, VaultError> {
+        let address = var(&lookup, VAULT_ADDR_ENV)?;
+        let token = var(&lookup, VAULT_TOKEN_ENV)?;
+        VaultClient::new(&address, &token, mount)
+    }
+
+    /// Creates a client for the server in `VAULT_ADDR`, logging in as `role` of the Kubernetes
+    /// auth method mounted at `auth_mount` with the service account token of the pod
+    pub async fn login_kubernetes(
+        mount: &str,
+        auth_mount: &str,
+        role: &str,
+    ) -> Result<Self, VaultError> {
+        let jwt = std::fs::read_to_string(SERVICE_ACCOUNT_TOKEN_PATH).map_err(|source| {
+            VaultError::ServiceAccountToken {
+                path: SERVICE_ACCOUNT_TOKEN_PATH,
+                source,
+            }
+        })?;
+        let client = VaultClient::new(&var(env_var, VAULT_ADDR_ENV)?, "", mount)?;
+        client.login(auth_mount, role, jwt.trim()).await
+    }
+
+    // logs the client in as `role` of the Kubernetes auth method with the token `jwt`
+    async fn login(mut self, auth_mount: &str, role: &str, jwt: &str) -> Result<Self, VaultError> {
+        let auth_mount = auth_mount.trim_matches('/');
+        let auth = auth::kubernetes::login(&*self.client, auth_mount, role, jwt)
+            .await
+            .map_err(|source| VaultError::Request {
+                path: format!("auth/{}/login", auth_mount),
+                source,
+            })?;
+        // the client was only just created, so nothing else holds it yet
+        vaultrs::client::Client::set_token(
+            Arc::get_mut(&mut self.client).unwrap(),
+            &auth.client_token,
+        );
+        debug!("Logged in to Vault as role {}", role);
+        Ok(self)
+    }
+
+    /// Lists the paths of every secret below `prefix`, descending into sub folders
+    pub async fn list_secrets(&self, prefix: &str) -> Result<Vec<String>, VaultError> {
+        let mut paths = Vec::new();
+        let mut folders = vec![prefix.trim_matches('/').to_owned()];
+        while let Some(folder) = folders.pop() {
+            let keys = match kv2::list(&*self.client, &self.mount, &folder).await {
+                Ok(keys) => keys,
+                // a folder without secrets doesn't exist
+                Err(ClientError::APIError { code: 404, .. }) => continue,
+                Err(source) => return Err(self.request_error("metadata", &folder, source)),
+            };
+            for key in keys {
+                let path = if folder.is_empty() {
+                    key
+                } else {
+                    format!("{}/{}", folder, key)
+                };
+                match path.strip_suffix('/') {
+                    Some(folder) => folders.push(String::from(folder)),
+                    None => paths.push(path),
+                }
+            }
+        }
+        paths.sort();
+        debug!("Found {} Vault secret(s) below {}", paths.len(), prefix);
+        Ok(paths)
+    }
+
+    /// Reads the custom metadata of the secret at `path`, as the tags of an AWS secret
+    pub async fn read_secret_entry(&self, path: &str) -> Result<SecretListEntry, VaultError> {
+        let metadata = kv2::read_metadata(&*self.client, &self.mount, path)
+            .await
+            .map_err(|source| self.request_error("metadata", path, source))?;
+        Ok(secret_entry(
+            path,
+            &metadata.custom_metadata.unwrap_or_default(),
+        ))
+    }
+
+    /// Lists the secrets below `prefix` that have one of the custom metadata keys
+    /// `namespace_tags`, like the tag key filter does for AWS secrets
+    pub async fn list_tagged_secrets(
+        &self,
+        prefix: &str,
+        namespace_tags: &[String],
+    ) -> Result<Vec<SecretListEntry>, VaultError> {
+        let mut secrets = Vec::new();
+        for path in self.list_secrets(prefix).await? {
+            let secret = self.read_secret_entry(&path).await?;
+            if secret.tags().iter().any(|tag| {
+                tag.key()
+                    .is_some_and(|key| namespace_tags.iter().any(|t| t == key))
+            }) {
+                secrets.push(secret);
+            }
+        }
+        Ok(secrets)
+    }
+
+    /// Reads the current version of the secret at `path`
+    pub async fn read_secret(&self, path: &str) -> Result<SecretData, VaultError> {
+        let data: serde_json::Value = kv2::read(&*self.client, &self.mount, path)
+            .await
+            .map_err(|source| self.request_error("data", path, source))?;
+        Ok(secret_data(&data)?)
+    }
+
+    // the error of a request for the `kind` (`data` or `metadata`) of the secret at `path`
+    fn request_error(&self, kind: &str, path: &str, source: ClientError) -> VaultError {
+        VaultError::Request {
+            path: format!("{}/{}/{}", self.mount, kind, path),
+            source,
+        }
+    }
+}
+
+// reads the environment variable `name`
+fn env_var(name: &str) -> Option<String> {
+    std::env::var(name).ok()
+}
+
+// reads the variable `name` with `lookup`, which must be set
+fn var(lookup: impl Fn(&str) -> Option<String>, name: &'static str) -> Result<String, VaultError> {
+    lookup(name).ok_or(VaultError::MissingEnv { var: name })
+}
+
+// builds the listing entry of the Vault secret `path`, with its custom metadata as tags
+pub fn secret_entry(path: &str, custom_metadata: &HashMap<String, String>) -> SecretListEntry {
+    let tags = custom_metadata
+        .iter()
+        .map(|(key, value)| Tag::builder().key(key).value(value).build());
+    tags.fold(SecretListEntry::builder().name(path), |entry, tag| {
+        entry.tags(tag)
+    })
+    .build()
+}
+
+// converts the `data.data` object of a KV v2 response into the values of a secret
+pub fn secret_data(data: &serde_json::Value) -> Result<SecretData, ParseError> {
+    let object = data.as_object().ok_or(ParseError::NotAMapping)?;
+    object
+        .iter()
+        .map(|(key, value)| match value {
+            serde_json::Value::String(value) => Ok((key.clone(), value.clone())),
+            _ => Err(ParseError::NonStringValue { key: key.clone() }),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use bytes::Bytes;
+    use http_body_util::Full;
+    use hyper::server::conn::http1;
+    use hyper::service::service_fn;
+    use hyper::{Method, Request, Response, StatusCode};
+    use hyper_util::rt::TokioIo;
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    #[test]
+    fn secret_entry_uses_custom_metadata_as_tags() {
+        let metadata = HashMap::from([
+            (String::from("/fhm/k8s/namespace"), String::from("prod")),
+            (
+                String::from("/fhm/k8s/secret-name"),
+                String::from("db-creds"),
+            ),
+        ]);
+        let entry = secret_entry("apps/db-creds", &metadata);
+
+        assert_eq!(entry.name(), Some("apps/db-creds"));
+        let mut tags: Vec<_> = entry
+            .tags()
+            .iter()
+            .map(|tag| (tag.key().unwrap(), tag.value().unwrap()))
+            .collect();
+        tags.sort();
+        assert_eq!(
+            tags,
+            [
+                ("/fhm/k8s/namespace", "prod"),
+                ("/fhm/k8s/secret-name", "db-creds")
+            ]
+        );
+    }
+
+    #[test]
+    fn secret_entry_without_custom_metadata_has_no_tags() {
+        let entry = secret_entry("apps/db-creds", &HashMap::new());
+        assert!(entry.tags().is_empty());
+    }
+
+    #[test]
+    fn secret_data_requires_string_values() {
+        let data = serde_json::json!({"username": "admin", "password": "hunter2"});
+        assert_eq!(
+            secret_data(&data).unwrap(),
+            SecretData::from([
+                (String::from("username"), String::from("admin")),
+                (String::from("password"), String::from("hunter2")),
+            ])
+        );
+
+        let data = serde_json::json!({"port": 5432});
+        assert!(matches!(
+            secret_data(&data),
+            Err(ParseError::NonStringValue { key }) if key == "port"
+        ));
+    }
+
+    #[test]
+    fn from_vars_requires_vault_addr_and_token() {
+        assert!(matches!(
+            VaultClient::from_vars("secret", |_| None),
+            Err(VaultError::MissingEnv {
+                var: VAULT_ADDR_ENV
+            })
+        ));
+        let address_only =
+            |var: &str| (var == VAULT_ADDR_ENV).then(|| String::from("http://vault:8200"));
+        assert!(matches!(
+            VaultClient::from_vars("secret", address_only),
+            Err(VaultError::MissingEnv {
+                var: VAULT_TOKEN_ENV
+            })
+        ));
+        assert!(matches!(
+            VaultClient::new("vault:8200", "token", "secret"),
+            Err(VaultError::Settings(_))
+        ));
+        assert!(matches!(
+            VaultClient::new("not a url", "token", "secret"),
+            Err(VaultError::Address { .. })
+        ));
+    }
+
+    const TOKEN: &str = "hvs.test";
+
+    // the response of the fake Vault server to a request, with the body of a successful response
+    // wrapped the way Vault does
+    fn respond(
+        method: &Method,
+        path: &str,
+        token: Option<&str>,
+    ) -> (StatusCode, serde_json::Value) {
+        if (method.as_str(), path) == ("POST", "/v1/auth/kubernetes/login") {
+            return (
+                StatusCode::OK,
+                serde_json::json!({
+                    "request_id": "5f9e0c2b",
+                    "lease_id": "",
+                    "lease_duration": 0,
+                    "renewable": false,
+                    "auth": {
+                        "client_token": TOKEN,
+                        "accessor": "8609694a",
+                        "policies": ["default"],
+                        "token_policies": ["default"],
+                        "metadata": {"role": "k8s-aws-secrets-sync"},
+                        "lease_duration": 3600,
+                        "renewable": true,
+                        "entity_id": "7d2e3179",
+                        "token_type": "service",
+                        "orphan": true,
+                    },
+                }),
+            );
+        }
+        if token != Some(TOKEN) {
+            return (
+                StatusCode::FORBIDDEN,
+                serde_json::json!({"errors": ["permission denied"]}),
+            );
+        }
+        let metadata = |custom_metadata: serde_json::Value| {
+            serde_json::json!({
+                "cas_required": false,
+                "created_time": "2024-11-28T10:00:00Z",
+                "current_version": 1,
+                "delete_version_after": "0s",
+                "max_versions": 0,
+                "oldest_version": 0,
+                "updated_time": "2024-11-28T10:00:00Z",
+                "custom_metadata": custom_metadata,
+                "versions": {},
+            })
+        };
+        let data = match (method.as_str(), path) {
+            ("LIST", "/v1/secret/metadata/apps") => {
+                serde_json::json!({"keys": ["db-creds", "team/"]})
+            }
+            ("LIST", "/v1/secret/metadata/apps/team") => serde_json::json!({"keys": ["api-key"]}),
+            ("GET", "/v1/secret/metadata/apps/db-creds") => {
+                metadata(serde_json::json!({"/fhm/k8s/namespace": "prod"}))
+            }
+            ("GET", "/v1/secret/metadata/apps/team/api-key") => metadata(serde_json::Value::Null),
+            ("GET", "/v1/secret/data/apps/db-creds") => serde_json::json!({
+                "data": {"username": "admin", "password": "hunter2"},
+                "metadata": {
+                    "created_time": "2024-11-28T10:00:00Z",
+                    "deletion_time": "",
+                    "custom_metadata": null,
+                    "destroyed": false,
+                    "version": 1,
+                },
+            }),
+            ("GET", "/v1/secret/data/apps/sealed") => {
+                return (
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    serde_json::json!({"errors": ["Vault is sealed"]}),
+                )
+            }
+            _ => return (StatusCode::NOT_FOUND, serde_json::json!({"errors": []})),
+        };
+        (
+            StatusCode::OK,
+            serde_json::json!({
+                "request_id": "d7b2a3c1",
+                "lease_id": "",
+                "lease_duration": 0,
+                "renewable": false,
+                "data": data,
+            }),
+        )
+    }
+
+    // serves `respond` on a local port, returning a client of the fake Vault server
+    async fn fake_vault(token: &str) -> VaultClient {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let service = service_fn(|request: Request<hyper::body::Incoming>| {
+                        let token = request
+                            .headers()
+                            .get("X-Vault-Token")
+                            .and_then(|token| token.to_str().ok());
+                        let (status, body) = respond(request.method(), request.uri().path(), token);
+                        let mut response = Response::new(Full::new(Bytes::from(body.to_string())));
+                        *response.status_mut() = status;
+                        async move { Ok::<_, Infallible>(response) }
+                    });
+                    let _ = http1::Builder::new()
+                        .serve_connection(TokioIo::new(stream), service)
+                        .await;
+                });
+            }
+        });
+        VaultClient::new(&address, token, "/secret/").unwrap()
+    }
+
+    #[tokio::test]
+    async fn list_secrets_descends_into_folders() {
+        let vault = fake_vault(TOKEN).await;
+
+        assert_eq!(
+            vault.list_secrets("/apps/").await.unwrap(),
+            ["apps/db-creds", "apps/team/api-key"]
+        );
+        assert!(vault.list_secrets("missing").await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn list_tagged_secrets_keeps_secrets_with_a_namespace_tag() {
+        let vault = fake_vault(TOKEN).await;
+        let secrets = vault
+            .list_tagged_secrets("apps", &[String::from("/fhm/k8s/namespace")])
+            .await
+            .unwrap();
+
+        assert_eq!(secrets.len(), 1);
+        assert_eq!(secrets[0].name(), Some("apps/db-creds"));
+        assert_eq!(secrets[0].tags()[0].value(), Some("prod"));
+    }
+
+    #[tokio::test]
+    async fn read_secret_reads_the_current_version() {
+        let vault = fake_vault(TOKEN).await;
+
+        assert_eq!(
+            vault.read_secret("apps/db-creds").await.unwrap(),
+            SecretData::from([
+                (String::from("username"), String::from("admin")),
+                (String::from("password"), String::from("hunter2")),
+            ])
+        );
+        let sealed = vault.read_secret("apps/sealed").await.unwrap_err();
+        assert!(sealed.is_retryable(), "{sealed}");
+        let missing = vault.read_secret("apps/missing").await.unwrap_err();
+        assert!(!missing.is_retryable(), "{missing}");
+    }
+
+    #[tokio::test]
+    async fn requests_are_sent_with_the_token() {
+        let vault = fake_vault("hvs.expired").await;
+        let err = vault.read_secret("apps/db-creds").await.unwrap_err();
+
+        assert!(matches!(
+            err,
+            VaultError::Request {
+                ref path,
+                source: ClientError::APIError { code: 403, .. },
+            } if path == "secret/data/apps/db-creds"
+        ));
+        assert!(!err.is_retryable());
+    }
+
+    #[tokio::test]
+    async fn login_uses_the_token_of_the_kubernetes_auth_method() {
+        let vault = fake_vault("")
+            .await
+            .login(
+                "/kubernetes/",
+                "k8s-aws-secrets-sync",
+                "eyJhbGciOiJSUzI1NiJ9",
+            )
+            .await
+            .unwrap();
+
+        assert!(vault.read_secret("apps/db-creds").await.is_ok());
+    }
+}
@@ -0,0 +1,341 @@
+// End to end tests of the sync, from the AWS SDK through to the Kubernetes client.
+//
+// The Kubernetes API is a fake server keeping the applied objects in memory. The AWS side is
+// either replayed HTTP responses, or LocalStack for the tests marked `#[ignore]`:
+//
+//     docker run --rm -p 4566:4566 localstack/localstack
+//     cargo test --features integration-tests -- --ignored
+
+#![cfg(feature = "integration-tests")]
+
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use aws_config::BehaviorVersion;
+use aws_sdk_secretsmanager::config::{Credentials, Region};
+use aws_sdk_secretsmanager::types::{SecretListEntry, Tag};
+use aws_smithy_runtime::client::http::test_util::{ReplayEvent, StaticReplayClient};
+use aws_smithy_types::body::SdkBody;
+use bytes::Bytes;
+use clap::Parser;
+use http_body_util::{BodyExt, Full};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use tokio::net::TcpListener;
+
+use k8s_aws_secrets_sync::args::Args;
+use k8s_aws_secrets_sync::audit::AuditLog;
+use k8s_aws_secrets_sync::aws::{build_list_filters, list_all_secrets};
+use k8s_aws_secrets_sync::metrics::Metrics;
+use k8s_aws_secrets_sync::plan::PlanAction;
+use k8s_aws_secrets_sync::retry::RetryPolicy;
+use k8s_aws_secrets_sync::{sync_secrets, RunSummary, SecretSource};
+
+/// The environment variable holding the LocalStack endpoint, `http://localhost:4566` if unset
+const LOCALSTACK_ENDPOINT_ENV: &str = "LOCALSTACK_ENDPOINT";
+
+/// The objects applied to the fake Kubernetes API, by path
+type Objects = Arc<Mutex<HashMap<String, serde_json::Value>>>;
+
+// serves a fake Kubernetes API on a free local port, returning a client for it and the
+// objects applied so far
+async fn fake_kubernetes() -> (kube::Client, Objects) {
+    let listener = TcpListener::bind(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)))
+        .await
+        .unwrap();
+    let address = listener.local_addr().unwrap();
+    let objects = Objects::default();
+    let served = objects.clone();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let objects = served.clone();
+            tokio::spawn(async move {
+                let service = service_fn(move |request| {
+                    let objects = objects.clone();
+                    async move { Ok::<_, Infallible>(respond(request, &objects).await) }
+                });
+                let _ = http1::Builder::new()
+                    .serve_connection(TokioIo::new(stream), service)
+                    .await;
+            });
+        }
+    });
+    let config = kube::Config::new(format!("http://{}", address).parse().unwrap());
+    (kube::Client::try_from(config).unwrap(), objects)
+}
+
+// gets, applies or creates the object at the path of `request`; patches in the `forbidden`
+// namespace are rejected
+async fn respond(
+    request: Request<hyper::body::Incoming>,
+    objects: &Objects,
+) -> Response<Full<Bytes>> {
+    let method = request.method().clone();
+    let path = String::from(request.uri().path());
+    let body = request.into_body().collect().await.unwrap().to_bytes();
+    let (status, object) = match method {
+        Method::GET => match objects.lock().unwrap().get(&path) {
+            Some(object) => (StatusCode::OK, object.clone()),
+            None => (
+                StatusCode::NOT_FOUND,
+                serde_json::json!({
+                    "kind": "Status",
+                    "apiVersion": "v1",
+                    "metadata": {},
+                    "status": "Failure",
+                    "reason": "NotFound",
+                    "code": 404,
+                }),
+            ),
+        },
+        Method::PATCH if path.starts_with("/api/v1/namespaces/forbidden/") => (
+            StatusCode::FORBIDDEN,
+            serde_json::json!({
+                "kind": "Status",
+                "apiVersion": "v1",
+                "metadata": {},
+                "status": "Failure",
+                "message": "secrets is forbidden",
+                "reason": "Forbidden",
+                "code": 403,
+            }),
+        ),
+        // server-side apply, which doubles as a create
+        Method::PATCH => {
+            let object: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            objects.lock().unwrap().insert(path, object.clone());
+            (StatusCode::OK, object)
+        }
+        // the events recorded on the secrets
+        _ => (StatusCode::CREATED, serde_json::from_slice(&body).unwrap()),
+    };
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(Full::from(object.to_string()))
+        .unwrap()
+}
+
+fn args() -> Arc<Args> {
+    args_with(&[])
+}
+
+fn args_with(extra: &[&str]) -> Arc<Args> {
+    let required = [
+        "k8s_aws_secrets_sync",
+        "-n",
+        "ns-tag",
+        "-s",
+        "name-tag",
+        "-f",
+        "file-tag",
+    ];
+    Arc::new(Args::parse_from(required.iter().chain(extra)))
+}
+
+fn tag(key: &str, value: &str) -> Tag {
+    Tag::builder().key(key).value(value).build()
+}
+
+async fn sync(
+    args: &Arc<Args>,
+    secrets: Vec<(SecretSource, SecretListEntry)>,
+    kube_client: &kube::Client,
+) -> RunSummary {
+    sync_secrets(
+        args,
+        secrets,
+        std::slice::from_ref(kube_client),
+        &Metrics::new(),
+        &Arc::default(),
+        &AuditLog::default(),
+    )
+    .await
+}
+
+// the data of the Kubernetes secret `namespace`/`name` applied to the fake API
+fn applied_data(objects: &Objects, namespace: &str, name: &str) -> serde_json::Value {
+    let path = format!("/api/v1/namespaces/{}/secrets/{}", namespace, name);
+    objects.lock().unwrap()[&path]["data"].clone()
+}
+
+// a Secrets Manager client replaying `count` responses of the secret `db-creds`
+fn replayed_client(count: usize) -> aws_sdk_secretsmanager::Client {
+    let get_secret_value = || {
+        ReplayEvent::new(
+            http::Request::builder()
+                .uri("https://secretsmanager.us-east-1.amazonaws.com/")
+                .body(SdkBody::empty())
+                .unwrap(),
+            http::Response::builder()
+                .status(200)
+                .body(SdkBody::from(
+                    r#"{"Name":"db-creds","SecretString":"{\"username\":\"admin\"}"}"#,
+                ))
+                .unwrap(),
+        )
+    };
+    let http_client = StaticReplayClient::new((0..count).map(|_| get_secret_value()).collect());
+    let config = aws_sdk_secretsmanager::Config::builder()
+        .behavior_version(BehaviorVersion::latest())
+        .region(Region::new("us-east-1"))
+        .credentials_provider(Credentials::for_tests())
+        .http_client(http_client)
+        .build();
+    aws_sdk_secretsmanager::Client::from_conf(config)
+}
+
+fn db_creds() -> SecretListEntry {
+    SecretListEntry::builder()
+        .name("db-creds")
+        .tags(tag("ns-tag", "prod"))
+        .tags(tag("name-tag", "db-creds"))
+        .build()
+}
+
+#[tokio::test]
+async fn syncs_a_replayed_secret_and_leaves_it_unchanged_on_the_next_run() {
+    let client = replayed_client(2);
+    let secret = db_creds();
+    let (kube_client, objects) = fake_kubernetes().await;
+    let args = args();
+
+    let first = sync(
+        &args,
+        vec![(SecretSource::Aws(client.clone()), secret.clone())],
+        &kube_client,
+    )
+    .await;
+    assert_eq!(first.errors, Vec::<String>::new());
+    assert_eq!(first.report.total.synced, 1);
+    assert_eq!(
+        applied_data(&objects, "prod", "db-creds"),
+        serde_json::json!({"username": "YWRtaW4="})
+    );
+
+    let second = sync(
+        &args,
+        vec![(SecretSource::Aws(client), secret)],
+        &kube_client,
+    )
+    .await;
+    assert_eq!(second.errors, Vec::<String>::new());
+    assert_eq!(second.report.total.unchanged, 1);
+}
+
+#[tokio::test]
+async fn a_failed_namespace_keeps_the_result_of_the_others() {
+    let client = replayed_client(1);
+    let secret = SecretListEntry::builder()
+        .name("db-creds")
+        .tags(tag("ns-tag", "forbidden, prod"))
+        .tags(tag("name-tag", "db-creds"))
+        .build();
+    let (kube_client, _) = fake_kubernetes().await;
+    let args = args_with(&["--on-k8s-error", "skip"]);
+
+    let summary = sync(
+        &args,
+        vec![(SecretSource::Aws(client), secret)],
+        &kube_client,
+    )
+    .await;
+
+    assert_eq!(summary.errors.len(), 1);
+    assert_eq!(summary.report.namespaces["forbidden"].errors, 1);
+    assert_eq!(summary.report.namespaces["prod"].synced, 1);
+    assert_eq!(summary.report.namespaces["prod"].errors, 0);
+    let results: Vec<_> = summary
+        .rows
+        .iter()
+        .map(|row| (row.k8s_namespace.as_str(), row.result.as_str()))
+        .collect();
+    assert_eq!(results, [("forbidden", "error"), ("prod", "synced")]);
+}
+
+#[tokio::test]
+async fn plan_compares_with_the_applied_secrets_without_changing_them() {
+    let client = replayed_client(3);
+    let (kube_client, objects) = fake_kubernetes().await;
+    let plan = args_with(&["--plan"]);
+    let source = || vec![(SecretSource::Aws(client.clone()), db_creds())];
+
+    let before = sync(&plan, source(), &kube_client).await;
+    assert_eq!(before.errors, Vec::<String>::new());
+    assert_eq!(
+        serde_json::to_value(&before.plan).unwrap(),
+        serde_json::json!([{
+            "aws_secret": "db-creds",
+            "k8s_namespace": "prod",
+            "k8s_secret": "db-creds",
+            "action": "create",
+            "changed_keys": ["username"],
+        }])
+    );
+    assert!(objects.lock().unwrap().is_empty());
+
+    sync(&args(), source(), &kube_client).await;
+    let after = sync(&plan, source(), &kube_client).await;
+    assert_eq!(after.plan[0].action, PlanAction::Noop);
+    assert_eq!(after.plan[0].changed_keys, Vec::<String>::new());
+}
+
+#[tokio::test]
+#[ignore = "needs LocalStack, see the top of this file"]
+async fn syncs_a_localstack_secret_to_kubernetes() {
+    let endpoint = std::env::var(LOCALSTACK_ENDPOINT_ENV)
+        .unwrap_or_else(|_| String::from("http://localhost:4566"));
+    let config = aws_sdk_secretsmanager::Config::builder()
+        .behavior_version(BehaviorVersion::latest())
+        .region(Region::new("us-east-1"))
+        .credentials_provider(Credentials::new("test", "test", None, None, "localstack"))
+        .endpoint_url(endpoint)
+        .build();
+    let client = aws_sdk_secretsmanager::Client::from_conf(config);
+    let name = format!("integration-test-{}", std::process::id());
+    client
+        .create_secret()
+        .name(&name)
+        .secret_string(r#"{"username": "admin", "password": "hunter2"}"#)
+        .tags(tag("ns-tag", "prod, staging"))
+        .tags(tag("name-tag", "db-creds"))
+        .send()
+        .await
+        .unwrap();
+
+    let args = args();
+    let retry_policy = RetryPolicy {
+        max_retries: args.max_retries,
+        base_delay: Duration::from_millis(args.retry_base_ms),
+    };
+    let secrets = list_all_secrets(&client, build_list_filters(&args), &retry_policy)
+        .await
+        .unwrap()
+        .into_iter()
+        .filter(|secret| secret.name.as_deref() == Some(name.as_str()))
+        .map(|secret| (SecretSource::Aws(client.clone()), secret))
+        .collect();
+    let (kube_client, objects) = fake_kubernetes().await;
+    let summary = sync(&args, secrets, &kube_client).await;
+
+    client
+        .delete_secret()
+        .secret_id(&name)
+        .force_delete_without_recovery(true)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(summary.errors, Vec::<String>::new());
+    assert_eq!(summary.report.total.synced, 2);
+    for namespace in ["prod", "staging"] {
+        assert_eq!(
+            applied_data(&objects, namespace, "db-creds"),
+            serde_json::json!({"username": "YWRtaW4=", "password": "aHVudGVyMg=="})
+        );
+    }
+}